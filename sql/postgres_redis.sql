@@ -0,0 +1,25 @@
+CREATE SCHEMA IF NOT EXISTS postgres_redis;
+
+-- Changes captured by prepared transactions, released to redis by COMMIT PREPARED.
+CREATE TABLE postgres_redis.prepared_changes (
+    gid text NOT NULL,
+    position int NOT NULL,
+    operation text NOT NULL,
+    key text NOT NULL,
+    value text,
+    from_select bool NOT NULL DEFAULT false,
+    version bigint NOT NULL DEFAULT 0,
+    relid oid NOT NULL DEFAULT 0,
+    synchronous bool NOT NULL DEFAULT false,
+    PRIMARY KEY (gid, position)
+);
+
+-- Tables tracked with postgres_redis.track_table.
+CREATE TABLE postgres_redis.mappings (
+    relid regclass PRIMARY KEY,
+    key_columns text[] NOT NULL,
+    value_columns text[] NOT NULL,
+    key_prefix text NOT NULL DEFAULT '',
+    sync_mode text NOT NULL DEFAULT 'async' CHECK (sync_mode IN ('async', 'synchronous')),
+    write_policy text NOT NULL DEFAULT 'set' CHECK (write_policy IN ('set', 'invalidate'))
+);
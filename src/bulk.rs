@@ -0,0 +1,191 @@
+use std::ffi::CString;
+
+use pgrx::prelude::*;
+
+use crate::gucs;
+use crate::prshmem::{self, Info};
+use crate::utils::{quote_identifier, quote_literal};
+use crate::xact;
+
+/// The function COPY calls for every row it loads into the tracked table.
+const CAPTURE_FUNCTION: &str = "postgres_redis.capture_copied_row(text, text, oid, text)";
+
+/// True while a `COPY ... FROM` into the tracked table runs with `capture_copied_row` in its
+/// WHERE clause.
+static mut COPY_RUNNING: bool = false;
+
+/// Queue the row loaded by a `COPY ... FROM` into the tracked table `relid`, its `key` and
+/// `value` columns as text, and let COPY insert it. COPY doesn't run through the executor
+/// hooks, so `add_capture` adds a call of this function to its WHERE clause, with the table
+/// and the `key_prefix` of its keys resolved once as constants. A BEFORE trigger that
+/// changes the row runs after the WHERE clause, tables with such triggers are better tracked
+/// with `track_table`.
+///
+/// The function is declared STABLE, although it queues a change, so COPY still inserts the
+/// rows in batches. The WHERE clause of COPY is evaluated exactly once per row either way.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."capture_copied_row"(
+    "key" text, "value" text, "relid" oid, "key_prefix" text
+) RETURNS bool
+    STABLE
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'capture_copied_row_wrapper';
+"#)]
+fn capture_copied_row(
+    key: Option<&str>,
+    value: Option<&str>,
+    relid: pg_sys::Oid,
+    key_prefix: &str,
+) -> bool {
+    if !unsafe { COPY_RUNNING } {
+        error!("postgres_redis: capture_copied_row is only called by COPY");
+    }
+    let Some(key) = key else {
+        return true;
+    };
+    let key = format!("{key_prefix}{key}");
+    let info = match value {
+        Some(value) => Info::write(&key, value),
+        None => Info::delete(&key),
+    };
+    match info {
+        Some(info) => xact::push_table_change(
+            info.with_relation(relid)
+                .with_policy(gucs::PGD_WRITE_POLICY.get()),
+        ),
+        None => prshmem::warn_key_too_long(&key),
+    }
+    true
+}
+
+/// Returns a copy of `pstmt`, a `COPY ... FROM` into the tracked table `relid`, that calls
+/// `capture_copied_row` for every row it loads, see `end_copy`. Returns None if the rows
+/// can't be captured: the extension isn't installed in the database, or the key or value
+/// column is a generated column, which the WHERE clause of COPY can't read. Raises an error
+/// if the user can't call the function, rather than dropping every key of the table.
+///
+/// # Safety
+///
+/// `pstmt` must be a valid utility statement holding a `CopyStmt`.
+pub unsafe fn add_capture(
+    pstmt: *mut pg_sys::PlannedStmt,
+    relid: pg_sys::Oid,
+    key_column: &str,
+    value_column: &str,
+) -> Option<*mut pg_sys::PlannedStmt> {
+    let callable = Spi::get_one_with_args::<bool>(
+        "SELECT CASE WHEN p IS NOT NULL THEN has_schema_privilege('postgres_redis', 'USAGE')
+             AND has_function_privilege(p, 'EXECUTE') END
+         FROM to_regprocedure($1) p",
+        vec![(PgBuiltInOids::TEXTOID.oid(), CAPTURE_FUNCTION.into_datum())],
+    );
+    match callable {
+        Ok(Some(true)) => {}
+        // The extension isn't installed in this database.
+        Ok(None) | Err(_) => return None,
+        Ok(Some(false)) => error!(
+            "postgres_redis: COPY into the tracked table needs the EXECUTE privilege on {}",
+            CAPTURE_FUNCTION
+        ),
+    }
+    for column in [key_column, value_column] {
+        let name = CString::new(column).expect("column name contains a null byte");
+        let attnum = pg_sys::get_attnum(relid, name.as_ptr());
+        if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber
+            || pg_sys::get_attgenerated(relid, attnum) != 0
+        {
+            return None;
+        }
+    }
+    let call = format!(
+        "SELECT postgres_redis.capture_copied_row({}::text, {}::text, {}::oid, {})",
+        quote_identifier(key_column),
+        quote_identifier(value_column),
+        relid.as_u32(),
+        quote_literal(&gucs::key_prefix()),
+    );
+    let call = CString::new(call).expect("column name contains a null byte");
+    let parsed = pg_sys::raw_parser(call.as_ptr(), pg_sys::RawParseMode_RAW_PARSE_DEFAULT);
+    let stmt = (*pgrx::list::List::<*mut core::ffi::c_void>::downcast_ptr(parsed)?.get(0)?)
+        .cast::<pg_sys::RawStmt>();
+    let select = (*stmt).stmt.cast::<pg_sys::SelectStmt>();
+    let target =
+        *pgrx::list::List::<*mut core::ffi::c_void>::downcast_ptr((*select).targetList)?.get(0)?;
+    let condition = (*target.cast::<pg_sys::ResTarget>()).val;
+
+    // The statement may belong to a cached plan, which must not change.
+    let pstmt = pg_sys::copyObjectImpl(pstmt.cast()).cast::<pg_sys::PlannedStmt>();
+    let copy = (*pstmt).utilityStmt.cast::<pg_sys::CopyStmt>();
+    // The rows the WHERE clause of the statement skips are not captured.
+    (*copy).whereClause = match (*copy).whereClause {
+        where_clause if where_clause.is_null() => condition,
+        where_clause => {
+            let args = pg_sys::lappend(std::ptr::null_mut(), where_clause.cast());
+            let args = pg_sys::lappend(args, condition.cast());
+            pg_sys::makeBoolExpr(pg_sys::BoolExprType_AND_EXPR, args, -1).cast()
+        }
+    };
+    COPY_RUNNING = true;
+    Some(pstmt)
+}
+
+/// Stop capturing the rows of the COPY started with `add_capture`. A COPY that fails leaves
+/// the capture on until the transaction ends.
+pub fn end_copy() {
+    unsafe { COPY_RUNNING = false };
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::prshmem::Operation;
+    use crate::xact;
+
+    #[pg_test]
+    fn test_copy_captures_every_row() {
+        // More rows than a shard of the shared memory buffer holds.
+        let path = format!("/tmp/postgres_redis_copy_{}.csv", unsafe {
+            pg_sys::MyProcPid
+        });
+        Spi::run(&format!(
+            "COPY (SELECT i, 'name ' || i FROM generate_series(1000, 1499) i) TO '{path}'"
+        ))
+        .unwrap();
+        Spi::run(&format!(
+            "COPY users (id, first_name) FROM '{path}' WHERE id <> 1499"
+        ))
+        .unwrap();
+        let changes = xact::pending_infos();
+        assert_eq!(499, changes.len());
+        assert!(changes
+            .iter()
+            .all(|change| change.operation == Operation::Set));
+        assert_eq!("users:1000", changes[0].key_string());
+        assert_eq!("name 1000", changes[0].value_string());
+        assert_eq!("users:1498", changes[498].key_string());
+    }
+
+    #[pg_test(
+        error = "postgres_redis: COPY into the tracked table needs the EXECUTE privilege on postgres_redis.capture_copied_row(text, text, oid, text)"
+    )]
+    fn test_copy_without_the_capture_privilege() {
+        Spi::run(
+            "CREATE ROLE postgres_redis_loader;\
+             GRANT USAGE ON SCHEMA postgres_redis TO postgres_redis_loader;\
+             GRANT INSERT ON users TO postgres_redis_loader;\
+             GRANT pg_read_server_files TO postgres_redis_loader;\
+             REVOKE EXECUTE ON FUNCTION postgres_redis.capture_copied_row(text, text, oid, text)\
+                 FROM PUBLIC;\
+             SET ROLE postgres_redis_loader",
+        )
+        .unwrap();
+        Spi::run("COPY users (id, first_name) FROM '/dev/null'").unwrap();
+    }
+
+    #[pg_test(error = "postgres_redis: capture_copied_row is only called by COPY")]
+    fn test_capture_copied_row_outside_copy() {
+        Spi::run("SELECT postgres_redis.capture_copied_row('1', 'Ada', 0, 'users:')").unwrap();
+    }
+}
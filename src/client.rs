@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use crate::gucs;
+
+/// How long a backend waits for redis before giving up on a command. Backends talk to redis
+/// while running a query, so an unreachable server must not hold the query for long.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The redis connection of the current backend. It is opened on first use and dropped after
+/// a connection error or a timeout, the next command then connects again.
+static mut CONNECTION: Option<redis::Connection> = None;
+
+/// How long a backend leaves redis alone after failing to reach it, see `is_available`.
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// When the backend may try to reach redis again after a connection error or a timeout.
+static mut RETRY_AT: Option<Instant> = None;
+
+// A backend is single threaded, so there is never more than one user of the connection.
+fn connection() -> &'static mut Option<redis::Connection> {
+    unsafe { &mut *std::ptr::addr_of_mut!(CONNECTION) }
+}
+
+fn connect() -> redis::RedisResult<redis::Connection> {
+    let url = match gucs::PGD_REDIS_URL.get() {
+        Some(url) => url.to_string_lossy().into_owned(),
+        None => {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "postgres_redis.redis_url is not set",
+            )))
+        }
+    };
+    let connection = redis::Client::open(url)?.get_connection_with_timeout(COMMAND_TIMEOUT)?;
+    connection.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    connection.set_write_timeout(Some(COMMAND_TIMEOUT))?;
+    Ok(connection)
+}
+
+/// False for `RETRY_BACKOFF` after the backend failed to reach redis. Callers that can do
+/// without redis, like the read-through lookups, skip it meanwhile instead of waiting for
+/// `COMMAND_TIMEOUT` on every query while the server is down.
+pub fn is_available() -> bool {
+    match unsafe { RETRY_AT } {
+        Some(retry_at) => Instant::now() >= retry_at,
+        None => true,
+    }
+}
+
+/// Run `command` on the redis connection of the current backend.
+pub fn with_connection<T>(
+    command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+) -> redis::RedisResult<T> {
+    let connection = connection();
+    let result = match connection {
+        Some(connection) => command(connection),
+        None => connect().and_then(|new| command(connection.insert(new))),
+    };
+    match &result {
+        Err(err) if err.is_io_error() => {
+            *connection = None;
+            unsafe { RETRY_AT = Some(Instant::now() + RETRY_BACKOFF) };
+        }
+        Ok(_) => unsafe { RETRY_AT = None },
+        Err(_) => {}
+    }
+    result
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_unreachable_redis_is_skipped() {
+        // Nothing listens on port 1.
+        Spi::run("SET postgres_redis.redis_url = 'redis://127.0.0.1:1'").unwrap();
+        assert!(super::is_available());
+        let result =
+            super::with_connection(|connection| redis::cmd("PING").query::<String>(connection));
+        assert!(result.is_err());
+        assert!(!super::is_available());
+    }
+}
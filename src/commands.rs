@@ -0,0 +1,177 @@
+use pgrx::prelude::*;
+
+use crate::client;
+use crate::prshmem::{Info, Operation, MAX_INFO_LENGTH};
+use crate::xact;
+
+/// Run `command` on the redis connection of the backend, raising an error if redis fails.
+fn run<T: redis::FromRedisValue>(command: &redis::Cmd) -> T {
+    match client::with_connection(|connection| command.query(connection)) {
+        Ok(result) => result,
+        Err(err) => error!("postgres_redis: redis command failed: {err}"),
+    }
+}
+
+/// Returns the value of `key`, or NULL if redis doesn't have it.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_get"("key" text) RETURNS text
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_get_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_get"(text) FROM PUBLIC;
+"#)]
+fn redis_get(key: &str) -> Option<String> {
+    run(redis::cmd("GET").arg(key))
+}
+
+/// Returns the values of `keys`, with NULL for every key redis doesn't have.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_mget"("keys" text[]) RETURNS text[]
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_mget_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_mget"(text[]) FROM PUBLIC;
+"#)]
+fn redis_mget(keys: Vec<String>) -> Vec<Option<String>> {
+    if keys.is_empty() {
+        return vec![];
+    }
+    run(redis::cmd("MGET").arg(keys))
+}
+
+/// Set `key` to `value`, expiring after `ttl` seconds if given. The value is written as is,
+/// without the version check of the background worker.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_set"(
+    "key" text,
+    "value" text,
+    "ttl" bigint DEFAULT NULL
+) RETURNS bool
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_set_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_set"(text, text, bigint) FROM PUBLIC;
+"#)]
+fn redis_set(key: Option<&str>, value: Option<&str>, ttl: Option<i64>) -> bool {
+    let (key, value) = match (key, value) {
+        (Some(key), Some(value)) => (key, value),
+        _ => error!("postgres_redis: redis_set needs a key and a value"),
+    };
+    let mut command = redis::cmd("SET");
+    command.arg(key).arg(value);
+    if let Some(ttl) = ttl {
+        if ttl <= 0 {
+            error!("postgres_redis: ttl must be a positive number of seconds");
+        }
+        command.arg("EX").arg(ttl);
+    }
+    run::<redis::Value>(&command);
+    true
+}
+
+/// Delete `keys`. Returns the number of keys that existed.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_del"("keys" text[]) RETURNS bigint
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_del_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_del"(text[]) FROM PUBLIC;
+"#)]
+fn redis_del(keys: Vec<String>) -> i64 {
+    if keys.is_empty() {
+        return 0;
+    }
+    run(redis::cmd("DEL").arg(keys))
+}
+
+/// Returns the fields and values of the hash `key`.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_hgetall"("key" text)
+    RETURNS TABLE ("field" text, "value" text)
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_hgetall_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_hgetall"(text) FROM PUBLIC;
+"#)]
+fn redis_hgetall(
+    key: &str,
+) -> TableIterator<'static, (name!(field, String), name!(value, String))> {
+    let fields: Vec<(String, String)> = run(redis::cmd("HGETALL").arg(key));
+    TableIterator::new(fields)
+}
+
+/// Returns true if redis has `key`.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_exists"("key" text) RETURNS bool
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_exists_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_exists"(text) FROM PUBLIC;
+"#)]
+fn redis_exists(key: &str) -> bool {
+    run(redis::cmd("EXISTS").arg(key))
+}
+
+/// Queue `op` on `key` for the current transaction, like a change captured from a tracked
+/// table. It is sent to redis by the background worker once the transaction commits and is
+/// dropped if the transaction, or the savepoint it was queued in, rolls back. `op` is `set`,
+/// `delete`, or `truncate` to delete every key starting with `key`.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."redis_enqueue"(
+    "key" text,
+    "value" text DEFAULT NULL,
+    "op" text DEFAULT 'set'
+) RETURNS void
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_enqueue_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."redis_enqueue"(text, text, text) FROM PUBLIC;
+"#)]
+fn redis_enqueue(key: Option<&str>, value: Option<&str>, op: Option<&str>) {
+    let key = match key {
+        Some(key) => key,
+        None => error!("postgres_redis: redis_enqueue needs a key"),
+    };
+    let op = op.unwrap_or("set");
+    let operation = match Operation::from_name(op) {
+        Some(operation) => operation,
+        None => error!("postgres_redis: unknown operation {op}, use set, delete or truncate"),
+    };
+    let info = match (operation, value) {
+        (Operation::Set, Some(value)) => Info::new(key, value),
+        (Operation::Set, None) => error!("postgres_redis: set needs a value"),
+        (Operation::Delete, _) => Info::delete(key),
+        (Operation::Truncate, _) => Info::truncate(key),
+    };
+    match info {
+        Some(info) => xact::push(info),
+        None => {
+            error!("postgres_redis: keys and values are limited to {MAX_INFO_LENGTH} characters")
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test(error = "postgres_redis: ttl must be a positive number of seconds")]
+    fn test_redis_set_rejects_ttl() {
+        Spi::run("SELECT postgres_redis.redis_set('key', 'value', 0)").unwrap();
+    }
+
+    #[pg_test(error = "permission denied for function redis_enqueue")]
+    fn test_redis_enqueue_needs_a_grant() {
+        Spi::run(
+            "CREATE ROLE postgres_redis_app;\
+             GRANT USAGE ON SCHEMA postgres_redis TO postgres_redis_app;\
+             SET ROLE postgres_redis_app",
+        )
+        .unwrap();
+        Spi::run("SELECT postgres_redis.redis_enqueue('users:1', 'Ada')").unwrap();
+    }
+
+    #[pg_test(error = "postgres_redis: unknown operation incr, use set, delete or truncate")]
+    fn test_redis_enqueue_rejects_operation() {
+        Spi::run("SELECT postgres_redis.redis_enqueue('key', '1', 'incr')").unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+use std::ffi::CStr;
+
+use pgrx::{error, list, pg_guard, pg_sys, varlena, PgTupleDesc};
+
+use crate::gucs::{self, WritePolicy};
+use crate::prshmem::{self, Info, Operation};
+use crate::utils;
+
+/// The output formats of the `postgres_redis` output plugin, chosen with the `format` option
+/// of the slot consumer, e.g. `pg_recvlogical -o format=resp`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The line format read back by the background worker, see `Record`.
+    Internal,
+    /// The redis commands for every change, encoded in the redis protocol. The output can be
+    /// piped to `redis-cli --pipe`.
+    Resp,
+    /// A JSON object for every change and commit.
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "internal" => Some(Format::Internal),
+            "resp" => Some(Format::Resp),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A change or commit decoded by the `postgres_redis` output plugin. Every change on the
+/// tracked table becomes a `Change` and every decoded transaction ends with a `Commit`, so
+/// that a consumer always knows up to where the slot can be advanced.
+///
+/// In the internal format the lines look like `C <version>`,
+/// `S <version> <key length> <key><value>`, `D <version> <key length> <key>` and
+/// `T <version> <prefix length> <key prefix>` for a truncate. The key length is in bytes,
+/// which keeps keys with spaces intact.
+pub enum Record {
+    Change(Box<Info>),
+    Commit(u64),
+}
+
+impl Record {
+    pub fn render(&self) -> String {
+        match self {
+            Record::Commit(version) => format!("C {version}"),
+            Record::Change(info) => {
+                let key = info.key_string();
+                match info.operation {
+                    Operation::Set => format!(
+                        "S {} {} {key}{}",
+                        info.version,
+                        key.len(),
+                        info.value_string()
+                    ),
+                    Operation::Delete => format!("D {} {} {key}", info.version, key.len()),
+                    Operation::Truncate => format!("T {} {} {key}", info.version, key.len()),
+                }
+            }
+        }
+    }
+
+    /// Render the record as the same SET or DEL command the background worker applies.
+    /// A commit has no command, and neither has a truncate since its keys have to be found
+    /// with SCAN first.
+    pub fn render_resp(&self) -> Option<String> {
+        let Record::Change(info) = self else {
+            return None;
+        };
+        let key = info.key_string();
+        let args = match info.operation {
+            Operation::Set => vec![String::from("SET"), key, info.value_string()],
+            Operation::Delete => vec![String::from("DEL"), key],
+            Operation::Truncate => return None,
+        };
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+        }
+        Some(command)
+    }
+
+    pub fn render_json(&self) -> String {
+        match self {
+            Record::Commit(version) => format!(r#"{{"op":"commit","version":{version}}}"#),
+            Record::Change(info) => {
+                let key = utils::json_string(&info.key_string());
+                match info.operation {
+                    Operation::Set => format!(
+                        r#"{{"op":"set","key":{key},"value":{},"version":{}}}"#,
+                        utils::json_string(&info.value_string()),
+                        info.version
+                    ),
+                    Operation::Delete => format!(
+                        r#"{{"op":"delete","key":{key},"version":{}}}"#,
+                        info.version
+                    ),
+                    Operation::Truncate => format!(
+                        r#"{{"op":"truncate","key_prefix":{key},"version":{}}}"#,
+                        info.version
+                    ),
+                }
+            }
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Record> {
+        let (kind, rest) = line.split_once(' ')?;
+        if kind == "C" {
+            return rest.parse().ok().map(Record::Commit);
+        }
+        let (version, rest) = rest.split_once(' ')?;
+        let (key_length, rest) = rest.split_once(' ')?;
+        let version: u64 = version.parse().ok()?;
+        let key_length: usize = key_length.parse().ok()?;
+        let key = rest.get(..key_length)?;
+        let mut info = match kind {
+            "S" => Info::write(key, &rest[key_length..]),
+            "D" => Info::delete(key),
+            "T" => Info::truncate(key),
+            _ => return None,
+        }?;
+        info.version = version;
+        Some(Record::Change(Box::new(info)))
+    }
+}
+
+/// The entry point Postgres looks up when a replication slot uses `postgres_redis` as its
+/// output plugin.
+///
+/// # Safety
+///
+/// Only Postgres calls this, with a valid callbacks struct to fill in.
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C" fn _PG_output_plugin_init(cb: *mut pg_sys::OutputPluginCallbacks) {
+    (*cb).startup_cb = Some(decoder_startup);
+    (*cb).begin_cb = Some(decoder_begin);
+    (*cb).change_cb = Some(decoder_change);
+    (*cb).truncate_cb = Some(decoder_truncate);
+    (*cb).commit_cb = Some(decoder_commit);
+    (*cb).shutdown_cb = Some(decoder_shutdown);
+}
+
+/// Reads the `format` option of the consumer. The chosen format lives in the private data of
+/// the decoding context until the decoding shuts down.
+#[pg_guard]
+unsafe extern "C" fn decoder_startup(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    options: *mut pg_sys::OutputPluginOptions,
+    _is_init: bool,
+) {
+    let mut format = Format::Internal;
+    if let Some(plugin_options) =
+        list::List::<*mut core::ffi::c_void>::downcast_ptr((*ctx).output_plugin_options)
+    {
+        for option in plugin_options.iter() {
+            let option = (*option).cast::<pg_sys::DefElem>();
+            let name = CStr::from_ptr((*option).defname).to_string_lossy();
+            if name != "format" {
+                error!("option \"{name}\" is not supported by postgres_redis");
+            }
+            let value = CStr::from_ptr(pg_sys::defGetString(option)).to_string_lossy();
+            format = match Format::from_name(&value) {
+                Some(format) => format,
+                None => error!(
+                    "unknown postgres_redis format \"{value}\", expected internal, resp or json"
+                ),
+            };
+        }
+    }
+    (*ctx).output_plugin_private = Box::into_raw(Box::new(format)).cast();
+    (*options).output_type = pg_sys::OutputPluginOutputType_OUTPUT_PLUGIN_TEXTUAL_OUTPUT;
+}
+
+#[pg_guard]
+unsafe extern "C" fn decoder_shutdown(ctx: *mut pg_sys::LogicalDecodingContext) {
+    let format = (*ctx).output_plugin_private.cast::<Format>();
+    if !format.is_null() {
+        drop(Box::from_raw(format));
+        (*ctx).output_plugin_private = std::ptr::null_mut();
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn decoder_begin(
+    _ctx: *mut pg_sys::LogicalDecodingContext,
+    _txn: *mut pg_sys::ReorderBufferTXN,
+) {
+}
+
+/// Every change of a transaction is versioned with the end of its commit record, the same
+/// version the executor hooks give to the changes of a committed transaction.
+#[pg_guard]
+unsafe extern "C" fn decoder_change(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    txn: *mut pg_sys::ReorderBufferTXN,
+    relation: pg_sys::Relation,
+    change: *mut pg_sys::ReorderBufferChange,
+) {
+    let (Some(table), Some(key_column), Some(value_column)) = (
+        gucs::PGD_REDIS_TABLE.get(),
+        gucs::PGD_KEY_COLUMN.get(),
+        gucs::PGD_VALUE_COLUMN.get(),
+    ) else {
+        return;
+    };
+    let table = table.to_str().expect("table name extraction failed");
+    if !utils::is_mapped_relation((*relation).rd_id, table) {
+        return;
+    }
+
+    // A DELETE only logs the replica identity of the old row, the key column must be part
+    // of it for deletes to be seen.
+    let tuples = (*change).data.tp;
+    let (operation, tuple) = match (*change).action {
+        pg_sys::ReorderBufferChangeType_REORDER_BUFFER_CHANGE_INSERT
+        | pg_sys::ReorderBufferChangeType_REORDER_BUFFER_CHANGE_UPDATE => {
+            (Operation::Set, tuples.newtuple)
+        }
+        pg_sys::ReorderBufferChangeType_REORDER_BUFFER_CHANGE_DELETE => {
+            (Operation::Delete, tuples.oldtuple)
+        }
+        _ => return,
+    };
+    if tuple.is_null() {
+        return;
+    }
+
+    let tuple_desc = PgTupleDesc::from_pg_unchecked((*relation).rd_att);
+    let tuple = &mut (*tuple).tuple;
+    let key = match tuple_column(tuple, &tuple_desc, key_column.to_str().unwrap_or_default()) {
+        Some(Column::Value(key)) => format!("{}{key}", gucs::key_prefix()),
+        _ => return,
+    };
+    let info = match operation {
+        Operation::Delete => Info::delete(&key),
+        Operation::Set if gucs::PGD_WRITE_POLICY.get() == WritePolicy::Invalidate => {
+            Info::delete(&key)
+        }
+        Operation::Set => {
+            let value_column = value_column.to_str().unwrap_or_default();
+            let mut value = tuple_column(tuple, &tuple_desc, value_column);
+            // An UPDATE that leaves a TOASTed value alone doesn't log it. The old row holds it
+            // with `REPLICA IDENTITY FULL`, otherwise the value in redis is still current.
+            if matches!(value, Some(Column::Unchanged)) && !tuples.oldtuple.is_null() {
+                let old_tuple = &mut (*tuples.oldtuple).tuple;
+                value = tuple_column(old_tuple, &tuple_desc, value_column);
+            }
+            match value {
+                Some(Column::Value(value)) => Info::write(&key, &value),
+                Some(Column::Null) => Info::delete(&key),
+                Some(Column::Unchanged) | None => return,
+            }
+        }
+        Operation::Truncate => return,
+    };
+    // The change is left out of the stream rather than failing the decoding, which would
+    // stop the slot from ever moving past it.
+    let Some(mut info) = info else {
+        prshmem::warn_key_too_long(&key);
+        return;
+    };
+    info.version = (*txn).end_lsn;
+    write_record(ctx, &Record::Change(Box::new(info)));
+}
+
+/// A truncate of the tracked table or any of its partitions removes every key of the table.
+#[pg_guard]
+unsafe extern "C" fn decoder_truncate(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    txn: *mut pg_sys::ReorderBufferTXN,
+    nrelations: i32,
+    relations: *mut pg_sys::Relation,
+    _change: *mut pg_sys::ReorderBufferChange,
+) {
+    let Some(table) = gucs::PGD_REDIS_TABLE.get() else {
+        return;
+    };
+    let table = table.to_str().expect("table name extraction failed");
+    let relations = std::slice::from_raw_parts(relations, nrelations as usize);
+    if relations
+        .iter()
+        .any(|relation| utils::is_mapped_relation((**relation).rd_id, table))
+    {
+        let Some(mut info) = Info::truncate(&gucs::key_prefix()) else {
+            prshmem::warn_key_too_long(&gucs::key_prefix());
+            return;
+        };
+        info.version = (*txn).end_lsn;
+        write_record(ctx, &Record::Change(Box::new(info)));
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn decoder_commit(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    txn: *mut pg_sys::ReorderBufferTXN,
+    _commit_lsn: pg_sys::XLogRecPtr,
+) {
+    write_record(ctx, &Record::Commit((*txn).end_lsn));
+}
+
+unsafe fn write_record(ctx: *mut pg_sys::LogicalDecodingContext, record: &Record) {
+    let format = (*ctx).output_plugin_private.cast::<Format>();
+    let format = if format.is_null() {
+        Format::Internal
+    } else {
+        *format
+    };
+    let line = match format {
+        Format::Internal => record.render(),
+        Format::Json => record.render_json(),
+        Format::Resp => match record.render_resp() {
+            Some(command) => command,
+            None => return,
+        },
+    };
+    pg_sys::OutputPluginPrepareWrite(ctx, true);
+    pg_sys::appendBinaryStringInfo((*ctx).out, line.as_ptr().cast(), line.len() as i32);
+    pg_sys::OutputPluginWrite(ctx, true);
+}
+
+/// The `column` attribute of a decoded tuple.
+enum Column {
+    Value(String),
+    Null,
+    /// An unchanged TOASTed value of an UPDATE, which isn't part of the WAL record.
+    Unchanged,
+}
+
+/// Returns the `column` attribute of a decoded tuple, None if the column doesn't exist.
+unsafe fn tuple_column(
+    tuple: *mut pg_sys::HeapTupleData,
+    tuple_desc: &PgTupleDesc,
+    column: &str,
+) -> Option<Column> {
+    let Some((datum, attr)) = utils::heap_tuple_attr(tuple, tuple_desc, column) else {
+        let exists = tuple_desc
+            .iter()
+            .any(|attr| !attr.attisdropped && attr.name() == column);
+        return exists.then_some(Column::Null);
+    };
+    if attr.attlen == -1 {
+        let value = datum.cast_mut_ptr::<pg_sys::varlena>();
+        if varlena::varatt_is_1b_e(value)
+            && varlena::vartag_external(value) == pg_sys::vartag_external_VARTAG_ONDISK as u8
+        {
+            return Some(Column::Unchanged);
+        }
+    }
+    Some(Column::Value(utils::output_datum(datum, attr.atttypid)))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_decoder_record_round_trip() {
+        use super::Record;
+        use crate::prshmem::{Info, Operation};
+
+        let mut info = Info::new("first name", "last name").unwrap();
+        info.version = 42;
+        let line = Record::Change(Box::new(info)).render();
+        assert_eq!("S 42 10 first namelast name", line);
+        match Record::parse(&line) {
+            Some(Record::Change(parsed)) => {
+                assert_eq!("first name", parsed.key_string());
+                assert_eq!("last name", parsed.value_string());
+                assert_eq!(42, parsed.version);
+            }
+            _ => panic!("failed to parse {line}"),
+        }
+        assert!(matches!(Record::parse("C 7"), Some(Record::Commit(7))));
+
+        let mut truncate = Info::truncate("user:").unwrap();
+        truncate.version = 9;
+        let line = Record::Change(Box::new(truncate)).render();
+        assert_eq!("T 9 5 user:", line);
+        match Record::parse(&line) {
+            Some(Record::Change(parsed)) => {
+                assert_eq!(Operation::Truncate, parsed.operation);
+                assert_eq!("user:", parsed.key_string());
+            }
+            _ => panic!("failed to parse {line}"),
+        }
+    }
+
+    #[pg_test]
+    fn test_decoder_record_formats() {
+        use super::Record;
+        use crate::prshmem::Info;
+
+        let mut info = Info::new("key", "a \"b\"").unwrap();
+        info.version = 3;
+        let record = Record::Change(Box::new(info));
+        assert_eq!(
+            Some(String::from(
+                "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\na \"b\"\r\n"
+            )),
+            record.render_resp()
+        );
+        assert_eq!(
+            r#"{"op":"set","key":"key","value":"a \"b\"","version":3}"#,
+            record.render_json()
+        );
+        assert_eq!(None, Record::Commit(3).render_resp());
+    }
+
+    #[pg_test]
+    fn test_decoder_null_value_deletes() {
+        use super::Record;
+        use crate::prshmem::Operation;
+
+        Spi::run(
+            "SELECT pg_create_logical_replication_slot('postgres_redis_test', 'postgres_redis')",
+        )
+        .unwrap();
+        crate::tests::commit_in_worker(
+            "INSERT INTO users (id, first_name) VALUES (2101, 'Ada');
+             UPDATE users SET first_name = NULL WHERE id = 2101;
+             DELETE FROM users WHERE id = 2101",
+        );
+        let changes = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT data FROM pg_logical_slot_peek_changes('postgres_redis_test', NULL, NULL)",
+                    None,
+                    None,
+                )?
+                .map(|row| row.get::<String>(1))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap();
+        Spi::run("SELECT pg_drop_replication_slot('postgres_redis_test')").unwrap();
+        let changes: Vec<(Operation, String)> = changes
+            .iter()
+            .flatten()
+            .filter_map(|line| match Record::parse(line) {
+                Some(Record::Change(info)) => Some((info.operation, info.value_string())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (Operation::Set, String::from("Ada")),
+                (Operation::Delete, String::new()),
+                (Operation::Delete, String::new()),
+            ],
+            changes
+        );
+    }
+}
@@ -0,0 +1,731 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::os::raw::c_int;
+
+use pgrx::{
+    extension_sql, is_a, list,
+    pg_sys::{self, Node, NodeTag},
+    prelude::*,
+    PgMemoryContexts,
+};
+
+use crate::client;
+use crate::utils;
+use crate::writer::VERSION_KEY_SUFFIX;
+
+/// The number of keys asked for by every SCAN of a foreign table.
+const SCAN_COUNT: usize = 1000;
+
+/// The column compared by the pushed down clauses.
+const KEY_COLUMN: &CStr = c"key";
+
+/// Rows planned for a foreign table without a key lookup.
+const SCAN_ROWS: f64 = 1000.0;
+
+extension_sql!(
+    r#"
+CREATE FUNCTION postgres_redis."redis_fdw_handler"() RETURNS fdw_handler
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_fdw_handler';
+
+CREATE FUNCTION postgres_redis."redis_fdw_validator"(text[], oid) RETURNS void
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'redis_fdw_validator';
+
+CREATE FOREIGN DATA WRAPPER postgres_redis_fdw
+    HANDLER postgres_redis.redis_fdw_handler
+    VALIDATOR postgres_redis.redis_fdw_validator;
+"#,
+    name = "redis_fdw"
+);
+
+/// The kind of redis value a foreign table reads. Each row holds the `key` and, depending on
+/// the kind, a `field`, a `value` and an `id` column. Columns with other names are NULL.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TableType {
+    /// A row per string key, with its `value`.
+    String,
+    /// A row per hash field, with its `field` and `value`.
+    Hash,
+    /// A row per set member, the member is the `value`.
+    Set,
+    /// A row per stream entry field, with the entry `id`, the `field` and its `value`.
+    Stream,
+}
+
+impl TableType {
+    fn from_name(name: &str) -> Option<TableType> {
+        match name {
+            "string" => Some(TableType::String),
+            "hash" => Some(TableType::Hash),
+            "set" => Some(TableType::Set),
+            "stream" => Some(TableType::Stream),
+            _ => None,
+        }
+    }
+
+    /// The name of the type in the `TYPE` option of SCAN.
+    fn redis_name(&self) -> &'static str {
+        match self {
+            TableType::String => "string",
+            TableType::Hash => "hash",
+            TableType::Set => "set",
+            TableType::Stream => "stream",
+        }
+    }
+}
+
+/// How the keys of a scan are found.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+enum Pushdown {
+    /// SCAN every key of the table type.
+    None,
+    /// Read a single key, from `key = <expr>`.
+    Key,
+    /// SCAN MATCH the keys matching `key LIKE <expr>`.
+    Like,
+}
+
+impl Pushdown {
+    fn from_i32(value: i32) -> Pushdown {
+        match value {
+            1 => Pushdown::Key,
+            2 => Pushdown::Like,
+            _ => Pushdown::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Column {
+    Key,
+    Field,
+    Value,
+    Id,
+    Other,
+}
+
+#[derive(Default)]
+struct Row {
+    key: String,
+    field: Option<String>,
+    value: Option<String>,
+    id: Option<String>,
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_redis_fdw_handler() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// The handler of the `postgres_redis_fdw` foreign data wrapper.
+///
+/// # Safety
+///
+/// Called by Postgres through the `redis_fdw_handler` SQL function.
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn redis_fdw_handler(_fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    let mut routine = PgBox::<pg_sys::FdwRoutine>::alloc_node(NodeTag::T_FdwRoutine);
+    routine.GetForeignRelSize = Some(get_foreign_rel_size);
+    routine.GetForeignPaths = Some(get_foreign_paths);
+    routine.GetForeignPlan = Some(get_foreign_plan);
+    routine.ExplainForeignScan = Some(explain_foreign_scan);
+    routine.BeginForeignScan = Some(begin_foreign_scan);
+    routine.IterateForeignScan = Some(iterate_foreign_scan);
+    routine.ReScanForeignScan = Some(rescan_foreign_scan);
+    routine.EndForeignScan = Some(end_foreign_scan);
+    pg_sys::Datum::from(routine.into_pg())
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_redis_fdw_validator() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// The validator of the `postgres_redis_fdw` foreign data wrapper. Foreign tables take the
+/// `table_type` option, the redis URL comes from `postgres_redis.redis_url`.
+///
+/// # Safety
+///
+/// Called by Postgres through the `redis_fdw_validator` SQL function.
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn redis_fdw_validator(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    let options = pg_sys::untransformRelOptions(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0));
+    let catalog = pgrx::fcinfo::pg_getarg::<pg_sys::Oid>(fcinfo, 1).unwrap_or_default();
+    for (name, value) in def_elems(options) {
+        match name.as_str() {
+            "table_type" if catalog == pg_sys::ForeignTableRelationId => {
+                if TableType::from_name(&value).is_none() {
+                    error!("postgres_redis_fdw: unknown table_type {value}, use string, hash, set or stream");
+                }
+            }
+            _ => error!("postgres_redis_fdw: unknown option {name}"),
+        }
+    }
+    pg_sys::Datum::from(0)
+}
+
+/// Returns the names and values of a list of `DefElem` options.
+unsafe fn def_elems(options: *mut pg_sys::List) -> Vec<(String, String)> {
+    list::List::<*mut core::ffi::c_void>::downcast_ptr(options)
+        .map(|options| {
+            options
+                .iter()
+                .map(|option| {
+                    let option = (*option).cast::<pg_sys::DefElem>();
+                    (
+                        CStr::from_ptr((*option).defname)
+                            .to_string_lossy()
+                            .into_owned(),
+                        CStr::from_ptr(pg_sys::defGetString(option))
+                            .to_string_lossy()
+                            .into_owned(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+unsafe fn table_type(relid: pg_sys::Oid) -> TableType {
+    let table = pg_sys::GetForeignTable(relid);
+    def_elems((*table).options)
+        .into_iter()
+        .find(|(name, _)| name == "table_type")
+        .and_then(|(_, value)| TableType::from_name(&value))
+        .unwrap_or(TableType::String)
+}
+
+/// True for the string types a key column or a lookup value may have: text and the types
+/// binary coercible to it.
+fn is_string_type(typoid: pg_sys::Oid) -> bool {
+    [pg_sys::TEXTOID, pg_sys::VARCHAROID, pg_sys::NAMEOID].contains(&typoid)
+}
+
+/// Returns `node` without the relabelings the parser adds to compare a varchar with a text.
+unsafe fn strip_relabel(mut node: *mut Node) -> *mut Node {
+    while is_a(node, NodeTag::T_RelabelType) {
+        node = (*node.cast::<pg_sys::RelabelType>()).arg.cast();
+    }
+    node
+}
+
+/// Returns the pushdown of `clause` on the `key_attno` column of the relation `relid`: the
+/// kind of lookup and the expression compared with the key.
+unsafe fn clause_pushdown(
+    clause: *mut Node,
+    relid: u32,
+    key_attno: i16,
+) -> Option<(Pushdown, *mut Node)> {
+    if !is_a(clause, NodeTag::T_OpExpr) {
+        return None;
+    }
+    let op_expr = clause.cast::<pg_sys::OpExpr>();
+    let args = list::List::<*mut core::ffi::c_void>::downcast_ptr((*op_expr).args)?;
+    if args.len() != 2 {
+        return None;
+    }
+    let var = strip_relabel((*args.get(0)?).cast());
+    let value = strip_relabel((*args.get(1)?).cast());
+    if !is_a(var, NodeTag::T_Var)
+        || i64::from((*var.cast::<pg_sys::Var>()).varno) != i64::from(relid)
+        || (*var.cast::<pg_sys::Var>()).varattno != key_attno
+        || !is_string_type((*var.cast::<pg_sys::Var>()).vartype)
+    {
+        return None;
+    }
+    let is_lookup_value = is_a(value, NodeTag::T_Const)
+        || (is_a(value, NodeTag::T_Param)
+            && (*value.cast::<pg_sys::Param>()).paramkind == pg_sys::ParamKind_PARAM_EXTERN);
+    if !is_lookup_value || !is_string_type(pg_sys::exprType(value)) {
+        return None;
+    }
+    let op_name = CStr::from_ptr(pg_sys::get_opname((*op_expr).opno));
+    match op_name.to_bytes() {
+        b"=" => Some((Pushdown::Key, value)),
+        b"~~" => Some((Pushdown::Like, value)),
+        _ => None,
+    }
+}
+
+/// Returns the best pushdown among the restrictions of `baserel`, a key lookup before a
+/// pattern.
+unsafe fn rel_pushdown(
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+) -> Option<(Pushdown, *mut Node)> {
+    let key_attno = pg_sys::get_attnum(foreigntableid, KEY_COLUMN.as_ptr());
+    if key_attno == pg_sys::InvalidAttrNumber as i16 {
+        return None;
+    }
+    let restrictions =
+        list::List::<*mut core::ffi::c_void>::downcast_ptr((*baserel).baserestrictinfo)?;
+    let pushdowns: Vec<(Pushdown, *mut Node)> = restrictions
+        .iter()
+        .filter_map(|restriction| {
+            let clause = (*(*restriction).cast::<pg_sys::RestrictInfo>()).clause;
+            clause_pushdown(clause.cast(), (*baserel).relid, key_attno)
+        })
+        .collect();
+    pushdowns
+        .iter()
+        .find(|(pushdown, _)| *pushdown == Pushdown::Key)
+        .or_else(|| pushdowns.first())
+        .copied()
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_rel_size(
+    _root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+) {
+    (*baserel).rows = match rel_pushdown(baserel, foreigntableid) {
+        Some((Pushdown::Key, _)) => 1.0,
+        _ => SCAN_ROWS,
+    };
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_paths(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    _foreigntableid: pg_sys::Oid,
+) {
+    // Every row is a round trip to redis, a key lookup is a single one.
+    let rows = (*baserel).rows;
+    let path = pg_sys::create_foreignscan_path(
+        root,
+        baserel,
+        std::ptr::null_mut(),
+        rows,
+        10.0,
+        10.0 + rows,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
+    pg_sys::add_path(baserel, path.cast());
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_plan(
+    _root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+    _best_path: *mut pg_sys::ForeignPath,
+    tlist: *mut pg_sys::List,
+    scan_clauses: *mut pg_sys::List,
+    outer_plan: *mut pg_sys::Plan,
+) -> *mut pg_sys::ForeignScan {
+    // The pushdown only narrows down the keys that are read, every clause is still checked
+    // on the rows.
+    let (pushdown, fdw_exprs) = match rel_pushdown(baserel, foreigntableid) {
+        Some((pushdown, expr)) => (
+            pushdown,
+            pg_sys::lappend(std::ptr::null_mut(), pg_sys::copyObjectImpl(expr.cast())),
+        ),
+        None => (Pushdown::None, std::ptr::null_mut()),
+    };
+    let fdw_private = pg_sys::lappend(
+        std::ptr::null_mut(),
+        pg_sys::makeConst(
+            pg_sys::INT4OID,
+            -1,
+            pg_sys::InvalidOid,
+            4,
+            pg_sys::Datum::from(pushdown as i32),
+            false,
+            true,
+        )
+        .cast(),
+    );
+    pg_sys::make_foreignscan(
+        tlist,
+        pg_sys::extract_actual_clauses(scan_clauses, false),
+        (*baserel).relid,
+        fdw_exprs,
+        fdw_private,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        outer_plan,
+    )
+}
+
+unsafe fn plan_pushdown(plan: *mut pg_sys::ForeignScan) -> Pushdown {
+    list::List::<*mut core::ffi::c_void>::downcast_ptr((*plan).fdw_private)
+        .and_then(|private| private.get(0).copied())
+        .map(|pushdown| {
+            Pushdown::from_i32((*pushdown.cast::<pg_sys::Const>()).constvalue.value() as i32)
+        })
+        .unwrap_or(Pushdown::None)
+}
+
+const EXPLAIN_LOOKUP: &CStr = c"Redis Lookup";
+
+/// The lookup shown by EXPLAIN for each pushdown.
+fn explain_lookup(pushdown: Pushdown) -> &'static CStr {
+    match pushdown {
+        Pushdown::None => c"SCAN",
+        Pushdown::Key => c"key",
+        Pushdown::Like => c"SCAN MATCH",
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn explain_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+    es: *mut pg_sys::ExplainState,
+) {
+    let lookup = explain_lookup(plan_pushdown((*node).ss.ps.plan.cast()));
+    pg_sys::ExplainPropertyText(EXPLAIN_LOOKUP.as_ptr(), lookup.as_ptr(), es);
+}
+
+/// The rows of a foreign table scan, read from redis a batch of keys at a time.
+struct ScanState {
+    table_type: TableType,
+    pushdown: Pushdown,
+    expr: *mut pg_sys::ExprState,
+    columns: Vec<Column>,
+    /// The SCAN cursor, or the key of a key lookup, None once every key was read.
+    lookup: Option<Lookup>,
+    started: bool,
+    rows: VecDeque<Row>,
+}
+
+enum Lookup {
+    Scan { cursor: u64, pattern: String },
+    Key(String),
+}
+
+impl ScanState {
+    /// Evaluate the pushed down expression to start reading keys.
+    unsafe fn start(&mut self, econtext: *mut pg_sys::ExprContext) {
+        self.started = true;
+        self.rows.clear();
+        let value = if self.expr.is_null() {
+            None
+        } else {
+            let mut is_null = false;
+            let expr = self.expr;
+            let datum = PgMemoryContexts::For((*econtext).ecxt_per_tuple_memory)
+                .switch_to(|_| (*expr).evalfunc.unwrap()(expr, econtext, &mut is_null));
+            if is_null {
+                // Neither `key = NULL` nor `key LIKE NULL` matches a row.
+                self.lookup = None;
+                return;
+            }
+            Some(utils::output_datum(
+                datum,
+                pg_sys::exprType((*expr).expr.cast()),
+            ))
+        };
+        self.lookup = match (self.pushdown, value) {
+            (Pushdown::Key, Some(key)) => Some(Lookup::Key(key)),
+            (Pushdown::Like, Some(pattern)) => Some(Lookup::Scan {
+                cursor: 0,
+                pattern: like_to_glob(&pattern),
+            }),
+            _ => Some(Lookup::Scan {
+                cursor: 0,
+                pattern: String::from("*"),
+            }),
+        };
+    }
+
+    /// Returns the next row, reading the next batch of keys from redis when needed.
+    fn next_row(&mut self) -> redis::RedisResult<Option<Row>> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Ok(Some(row));
+            }
+            let keys = match self.lookup.take() {
+                None => return Ok(None),
+                Some(Lookup::Key(key)) => {
+                    let key_type: String = client::with_connection(|connection| {
+                        redis::cmd("TYPE").arg(&key).query(connection)
+                    })?;
+                    if key_type != self.table_type.redis_name() {
+                        return Ok(None);
+                    }
+                    vec![key]
+                }
+                Some(Lookup::Scan { cursor, pattern }) => {
+                    let (next_cursor, keys): (u64, Vec<String>) =
+                        client::with_connection(|connection| {
+                            redis::cmd("SCAN")
+                                .arg(cursor)
+                                .arg("MATCH")
+                                .arg(&pattern)
+                                .arg("COUNT")
+                                .arg(SCAN_COUNT)
+                                .arg("TYPE")
+                                .arg(self.table_type.redis_name())
+                                .query(connection)
+                        })?;
+                    if next_cursor != 0 {
+                        self.lookup = Some(Lookup::Scan {
+                            cursor: next_cursor,
+                            pattern,
+                        });
+                    }
+                    // The version keys written next to the values are no table content.
+                    keys.into_iter()
+                        .filter(|key| !key.ends_with(VERSION_KEY_SUFFIX))
+                        .collect()
+                }
+            };
+            if !keys.is_empty() {
+                self.rows.extend(self.read_keys(keys)?);
+            }
+        }
+    }
+
+    /// Read the rows of `keys`.
+    fn read_keys(&self, keys: Vec<String>) -> redis::RedisResult<Vec<Row>> {
+        let command = match self.table_type {
+            TableType::String => {
+                let values: Vec<Option<String>> = client::with_connection(|connection| {
+                    redis::cmd("MGET").arg(&keys).query(connection)
+                })?;
+                return Ok(keys
+                    .into_iter()
+                    .zip(values)
+                    .filter_map(|(key, value)| {
+                        value.map(|value| Row {
+                            key,
+                            value: Some(value),
+                            ..Default::default()
+                        })
+                    })
+                    .collect());
+            }
+            TableType::Hash => "HGETALL",
+            TableType::Set => "SMEMBERS",
+            TableType::Stream => "XRANGE",
+        };
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd(command).arg(key);
+            if self.table_type == TableType::Stream {
+                pipe.arg("-").arg("+");
+            }
+        }
+        let mut rows = vec![];
+        match self.table_type {
+            TableType::Hash => {
+                let hashes: Vec<Vec<(String, String)>> =
+                    client::with_connection(|connection| pipe.query(connection))?;
+                for (key, fields) in keys.into_iter().zip(hashes) {
+                    rows.extend(fields.into_iter().map(|(field, value)| Row {
+                        key: key.clone(),
+                        field: Some(field),
+                        value: Some(value),
+                        ..Default::default()
+                    }));
+                }
+            }
+            TableType::Set => {
+                let sets: Vec<Vec<String>> =
+                    client::with_connection(|connection| pipe.query(connection))?;
+                for (key, members) in keys.into_iter().zip(sets) {
+                    rows.extend(members.into_iter().map(|member| Row {
+                        key: key.clone(),
+                        value: Some(member),
+                        ..Default::default()
+                    }));
+                }
+            }
+            _ => {
+                let streams: Vec<Vec<(String, Vec<String>)>> =
+                    client::with_connection(|connection| pipe.query(connection))?;
+                for (key, entries) in keys.into_iter().zip(streams) {
+                    for (id, fields) in entries {
+                        rows.extend(fields.chunks_exact(2).map(|field| Row {
+                            key: key.clone(),
+                            field: Some(field[0].clone()),
+                            value: Some(field[1].clone()),
+                            id: Some(id.clone()),
+                        }));
+                    }
+                }
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// Translate a LIKE pattern into a redis glob pattern, escaping the glob characters.
+fn like_to_glob(pattern: &str) -> String {
+    let mut glob = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => glob.push('*'),
+            '_' => glob.push('?'),
+            '\\' => match chars.next() {
+                Some(escaped) => push_glob_literal(&mut glob, escaped),
+                None => push_glob_literal(&mut glob, '\\'),
+            },
+            c => push_glob_literal(&mut glob, c),
+        }
+    }
+    glob
+}
+
+fn push_glob_literal(glob: &mut String, c: char) {
+    if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+        glob.push('\\');
+    }
+    glob.push(c);
+}
+
+#[pg_guard]
+unsafe extern "C" fn begin_foreign_scan(node: *mut pg_sys::ForeignScanState, eflags: c_int) {
+    if eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as c_int != 0 {
+        return;
+    }
+    let plan = (*node).ss.ps.plan.cast::<pg_sys::ForeignScan>();
+    let relation = (*node).ss.ss_currentRelation;
+    let expr = list::List::<*mut core::ffi::c_void>::downcast_ptr((*plan).fdw_exprs)
+        .and_then(|exprs| exprs.get(0).copied())
+        .map(|expr| pg_sys::ExecInitExpr(expr.cast(), &mut (*node).ss.ps))
+        .unwrap_or(std::ptr::null_mut());
+    let tuple_desc = &*(*relation).rd_att;
+    let columns = tuple_desc
+        .attrs
+        .as_slice(tuple_desc.natts as usize)
+        .iter()
+        .map(|attr| match attr.name() {
+            _ if attr.attisdropped => Column::Other,
+            "key" => Column::Key,
+            "field" => Column::Field,
+            "value" => Column::Value,
+            "id" => Column::Id,
+            _ => Column::Other,
+        })
+        .collect();
+    let state = ScanState {
+        table_type: table_type((*relation).rd_id),
+        pushdown: plan_pushdown(plan),
+        expr,
+        columns,
+        lookup: None,
+        started: false,
+        rows: VecDeque::new(),
+    };
+    // The state is dropped with the memory context of the query, even if the query fails.
+    (*node).fdw_state = PgMemoryContexts::CurrentMemoryContext
+        .leak_and_drop_on_delete(state)
+        .cast();
+}
+
+#[pg_guard]
+unsafe extern "C" fn iterate_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::TupleTableSlot {
+    let state = &mut *(*node).fdw_state.cast::<ScanState>();
+    let slot = (*node).ss.ss_ScanTupleSlot;
+    (*(*slot).tts_ops).clear.unwrap()(slot);
+    if !state.started {
+        state.start((*node).ss.ps.ps_ExprContext);
+    }
+    let row = match state.next_row() {
+        Ok(Some(row)) => row,
+        Ok(None) => return slot,
+        Err(err) => error!("postgres_redis_fdw: redis command failed: {err}"),
+    };
+    let tuple_desc = &*(*slot).tts_tupleDescriptor;
+    let attrs = tuple_desc.attrs.as_slice(tuple_desc.natts as usize);
+    for (i, (attr, column)) in attrs.iter().zip(state.columns.iter()).enumerate() {
+        let text = match column {
+            Column::Key => Some(row.key.as_str()),
+            Column::Field => row.field.as_deref(),
+            Column::Value => row.value.as_deref(),
+            Column::Id => row.id.as_deref(),
+            Column::Other => None,
+        };
+        let datum = text.and_then(|text| utils::input_datum(text, attr.atttypid, attr.atttypmod));
+        *(*slot).tts_values.add(i) = datum.unwrap_or(pg_sys::Datum::from(0));
+        *(*slot).tts_isnull.add(i) = datum.is_none();
+    }
+    pg_sys::ExecStoreVirtualTuple(slot)
+}
+
+#[pg_guard]
+unsafe extern "C" fn rescan_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    let state = &mut *(*node).fdw_state.cast::<ScanState>();
+    state.started = false;
+    state.lookup = None;
+    state.rows.clear();
+}
+
+#[pg_guard]
+unsafe extern "C" fn end_foreign_scan(_node: *mut pg_sys::ForeignScanState) {}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test(
+        error = "postgres_redis_fdw: unknown table_type list, use string, hash, set or stream"
+    )]
+    fn test_redis_fdw_rejects_table_type() {
+        Spi::run("CREATE SERVER redis FOREIGN DATA WRAPPER postgres_redis_fdw").unwrap();
+        Spi::run(
+            "CREATE FOREIGN TABLE redis_lists (key text, value text) SERVER redis OPTIONS (table_type 'list')",
+        )
+        .unwrap();
+    }
+
+    /// The `Redis Lookup` line of the plan of `query`.
+    fn explain_lookup(query: &str) -> String {
+        Spi::connect(|client| {
+            client
+                .select(&format!("EXPLAIN {query}"), None, None)
+                .unwrap()
+                .filter_map(|row| row.get::<String>(1).unwrap())
+                .find(|line| line.contains("Redis Lookup"))
+                .unwrap()
+        })
+    }
+
+    #[pg_test]
+    fn test_redis_fdw_explains_pushdown() {
+        Spi::run(
+            "CREATE SERVER redis FOREIGN DATA WRAPPER postgres_redis_fdw;\
+             CREATE FOREIGN TABLE redis_text (key text, value text) SERVER redis;\
+             CREATE FOREIGN TABLE redis_varchar (key varchar, value text) SERVER redis;\
+             CREATE FOREIGN TABLE redis_name (key name, value text) SERVER redis",
+        )
+        .unwrap();
+        for table in ["redis_text", "redis_varchar", "redis_name"] {
+            assert!(
+                explain_lookup(&format!("SELECT * FROM {table} WHERE key = 'users:1'"))
+                    .ends_with("Redis Lookup: key"),
+                "{table}"
+            );
+            assert!(
+                explain_lookup(&format!("SELECT * FROM {table} WHERE key LIKE 'users:%'"))
+                    .ends_with("Redis Lookup: SCAN MATCH"),
+                "{table}"
+            );
+            assert!(
+                explain_lookup(&format!("SELECT * FROM {table} WHERE value = 'Ada'"))
+                    .ends_with("Redis Lookup: SCAN"),
+                "{table}"
+            );
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use pgrx::*;
 use std::ffi::CStr;
+use std::time::Duration;
+
+use crate::prshmem;
 
 pub static PGD_REDIS_URL: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(None);
@@ -15,6 +18,211 @@ pub static PGD_VALUE_COLUMN: GucSetting<Option<&'static CStr>> =
 
 pub static PGD_BG_DELAY: GucSetting<i32> = GucSetting::<i32>::new(10);
 
+/// The time between the rounds of the background worker in milliseconds, -1 to use
+/// `postgres_redis.bg_delay`.
+pub static PGD_FLUSH_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(-1);
+
+/// Returns the time between the rounds of the background worker.
+pub fn flush_interval() -> Duration {
+    match PGD_FLUSH_INTERVAL.get() {
+        -1 => Duration::from_secs(PGD_BG_DELAY.get() as u64),
+        interval => Duration::from_millis(interval as u64),
+    }
+}
+
+/// The number of background workers writing to redis, each with its own connection and shard
+/// of the buffer.
+pub static PGD_WORKERS: GucSetting<i32> = GucSetting::<i32>::new(1);
+
+/// The committed changes the background worker lets pile up before it writes them to redis,
+/// and the most changes it sends in a pipeline.
+pub static PGD_MAX_BATCH_ITEMS: GucSetting<i32> = GucSetting::<i32>::new(100);
+
+/// The length of the keys and values the background worker lets pile up, and sends in a
+/// pipeline, in bytes.
+pub static PGD_MAX_BATCH_BYTES: GucSetting<i32> = GucSetting::<i32>::new(65536);
+
+/// How long a committed change may wait for the background worker, in milliseconds.
+pub static PGD_MAX_BATCH_DELAY: GucSetting<i32> = GucSetting::<i32>::new(10);
+
+/// How changes to the tracked table are captured.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaptureMode {
+    /// The executor hooks of every backend capture the changes of their own queries.
+    Hooks,
+    /// The background worker decodes the changes from a logical replication slot.
+    Logical,
+}
+
+pub static PGD_CAPTURE_MODE: GucSetting<CaptureMode> =
+    GucSetting::<CaptureMode>::new(CaptureMode::Hooks);
+
+/// What the background worker deletes when a tracked table is truncated.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TruncateStrategy {
+    /// SCAN for the version keys under the key prefix and delete the keys they belong to.
+    /// Only keys written by the extension are removed.
+    Scan,
+    /// SCAN for every key under the key prefix and delete it.
+    Prefix,
+    /// Leave redis untouched.
+    Ignore,
+}
+
+pub static PGD_TRUNCATE_STRATEGY: GucSetting<TruncateStrategy> =
+    GucSetting::<TruncateStrategy>::new(TruncateStrategy::Scan);
+
+/// What the background worker logs about the changes it writes, each level includes the ones
+/// above it.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    /// Nothing.
+    Off,
+    /// The rounds that failed to write to redis.
+    Warning,
+    /// A line per round with the number of changes written.
+    Info,
+    /// A line per change of a sampled key.
+    Debug,
+}
+
+pub static PGD_LOG_LEVEL: GucSetting<LogLevel> = GucSetting::<LogLevel>::new(LogLevel::Warning);
+
+/// Log the values of the changes instead of their length.
+pub static PGD_LOG_VALUES: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// The fraction of the keys whose changes are logged at the debug level.
+pub static PGD_LOG_SAMPLE_RATE: GucSetting<f64> = GucSetting::<f64>::new(0.01);
+
+/// What the changes of a mapped table do to its redis keys.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WritePolicy {
+    /// An insert or update sets the key to the new value.
+    #[default]
+    Set,
+    /// Every change deletes the key, and the values read by SELECT queries populate it again.
+    Invalidate,
+}
+
+impl WritePolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WritePolicy::Set => "set",
+            WritePolicy::Invalidate => "invalidate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WritePolicy> {
+        match name {
+            "set" => Some(WritePolicy::Set),
+            "invalidate" => Some(WritePolicy::Invalidate),
+            _ => None,
+        }
+    }
+}
+
+pub static PGD_WRITE_POLICY: GucSetting<WritePolicy> =
+    GucSetting::<WritePolicy>::new(WritePolicy::Set);
+
+/// Whether the commit of a transaction writing to a mapped table waits for redis.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SyncMode {
+    /// The background worker writes the changes after the commit.
+    #[default]
+    Async,
+    /// The commit waits for the background worker to write the changes, see
+    /// `postgres_redis.sync_timeout` and `postgres_redis.sync_failure`.
+    Synchronous,
+}
+
+impl SyncMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncMode::Async => "async",
+            SyncMode::Synchronous => "synchronous",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SyncMode> {
+        match name {
+            "async" => Some(SyncMode::Async),
+            "synchronous" => Some(SyncMode::Synchronous),
+            _ => None,
+        }
+    }
+}
+
+pub static PGD_SYNC_MODE: GucSetting<SyncMode> = GucSetting::<SyncMode>::new(SyncMode::Async);
+
+/// What a transaction writing to a synchronous mapping does when redis can't be updated.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncFailure {
+    /// Commit, and warn the client once the changes couldn't be written in time.
+    Warn,
+    /// Fail the commit if the background worker can't reach redis, warn like `Warn` if the
+    /// changes then fail or time out.
+    Error,
+    /// Commit without a word, the changes are written like the ones of an async mapping.
+    Async,
+}
+
+pub static PGD_SYNC_FAILURE: GucSetting<SyncFailure> =
+    GucSetting::<SyncFailure>::new(SyncFailure::Warn);
+
+/// How long a commit waits for the changes of synchronous mappings, in milliseconds.
+pub static PGD_SYNC_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(1000);
+
+pub static PGD_KEY_PREFIX: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Returns the prefix of the redis keys of the `postgres_redis.table` table.
+pub fn key_prefix() -> String {
+    PGD_KEY_PREFIX
+        .get()
+        .map(|key_prefix| key_prefix.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the oid of the `postgres_redis.table` table, InvalidOid if it isn't set or doesn't
+/// exist.
+pub fn table_relid() -> pg_sys::Oid {
+    match PGD_REDIS_TABLE.get() {
+        Some(table) => unsafe { pg_sys::RelnameGetRelid(table.as_ptr()) },
+        None => pg_sys::InvalidOid,
+    }
+}
+
+/// The local port of the Prometheus metrics endpoint, 0 to not serve metrics.
+pub static PGD_METRICS_PORT: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Count the keys of the captured changes, see the `hotkeys` module.
+pub static PGD_TRACK_HOT_KEYS: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Answer point lookups on the tracked table from redis, see the `readthrough` module.
+pub static PGD_READ_THROUGH: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+pub static PGD_DATABASE: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+pub static PGD_SLOT_NAME: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+/// Returns the database the background worker connects to in the logical capture mode.
+pub fn database() -> String {
+    PGD_DATABASE
+        .get()
+        .map(|database| database.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("postgres"))
+}
+
+/// Returns the name of the logical replication slot used in the logical capture mode.
+pub fn slot_name() -> String {
+    PGD_SLOT_NAME
+        .get()
+        .map(|slot_name| slot_name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("postgres_redis"))
+}
+
 // Initialize all the relevant postgresql conf parameters type and value
 pub fn init() {
     GucRegistry::define_string_guc(
@@ -60,4 +268,183 @@ pub fn init() {
         GucContext::Userset,
         GucFlags::default(),
     );
+    GucRegistry::define_int_guc(
+        "postgres_redis.workers",
+        "Background workers",
+        "The number of background workers writing to redis. The changes are split between them by the hash of their key.",
+        &PGD_WORKERS,
+        1,
+        prshmem::MAX_WORKERS as i32,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "postgres_redis.flush_interval",
+        "Delay between rounds",
+        "The time between the rounds of the background worker on an idle server, -1 to use postgres_redis.bg_delay.",
+        &PGD_FLUSH_INTERVAL,
+        -1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "postgres_redis.max_batch_items",
+        "Changes per batch",
+        "The number of committed changes that wakes the background worker up to write them to redis, and the most changes sent in a pipeline.",
+        &PGD_MAX_BATCH_ITEMS,
+        1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "postgres_redis.max_batch_bytes",
+        "Bytes per batch",
+        "The length of the keys and values of the committed changes that wakes the background worker up to write them to redis, and that is sent in a pipeline.",
+        &PGD_MAX_BATCH_BYTES,
+        1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_BYTE,
+    );
+    GucRegistry::define_int_guc(
+        "postgres_redis.max_batch_delay",
+        "Batch delay",
+        "How long a committed change may wait for more changes before the background worker writes it to redis.",
+        &PGD_MAX_BATCH_DELAY,
+        0,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_enum_guc(
+        "postgres_redis.write_policy",
+        "Write policy",
+        "What the changes of the tracked table do to its redis keys: set the new value (set) or delete the key (invalidate).",
+        &PGD_WRITE_POLICY,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "postgres_redis.sync_mode",
+        "Synchronous mode",
+        "Whether a commit writing to the tracked table waits for its changes to reach redis: async or synchronous.",
+        &PGD_SYNC_MODE,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "postgres_redis.sync_failure",
+        "Synchronous mapping failure",
+        "What a commit writing to a synchronous mapping does when redis can't be updated: warn, error before committing, or fall back to async.",
+        &PGD_SYNC_FAILURE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "postgres_redis.sync_timeout",
+        "Synchronous mapping timeout",
+        "How long a commit waits for the background worker to write the changes of synchronous mappings to redis.",
+        &PGD_SYNC_TIMEOUT,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_string_guc(
+        "postgres_redis.key_prefix",
+        "Redis key prefix",
+        "The prefix added to the redis keys of the tracked table.",
+        &PGD_KEY_PREFIX,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "postgres_redis.truncate_strategy",
+        "Truncate strategy",
+        "What is deleted from redis when a tracked table is truncated: the keys written by the extension (scan), every key under the key prefix (prefix) or nothing (ignore).",
+        &PGD_TRUNCATE_STRATEGY,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "postgres_redis.read_through",
+        "Read-through cache",
+        "Answer point lookups on the key column of the tracked table from redis, falling back to the table on a miss.",
+        &PGD_READ_THROUGH,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "postgres_redis.track_hot_keys",
+        "Track hot keys",
+        "Count the keys of the captured changes to report the hottest ones with postgres_redis.hot_keys().",
+        &PGD_TRACK_HOT_KEYS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "postgres_redis.metrics_port",
+        "Metrics port",
+        "The local port a background worker serves Prometheus metrics on, 0 to not serve metrics.",
+        &PGD_METRICS_PORT,
+        0,
+        65535,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "postgres_redis.log_level",
+        "Log level",
+        "What the background worker logs: nothing (off), failed rounds (warning), every round (info) or the changes of sampled keys (debug).",
+        &PGD_LOG_LEVEL,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "postgres_redis.log_values",
+        "Log values",
+        "Log the values of the changes at the debug log level instead of their length. Values may hold personal data.",
+        &PGD_LOG_VALUES,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "postgres_redis.log_sample_rate",
+        "Log sample rate",
+        "The fraction of the keys whose changes are logged at the debug log level.",
+        &PGD_LOG_SAMPLE_RATE,
+        0.0,
+        1.0,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "postgres_redis.capture_mode",
+        "Change capture mode",
+        "Capture changes with the executor hooks (hooks) or from a logical replication slot (logical).",
+        &PGD_CAPTURE_MODE,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "postgres_redis.database",
+        "Database name",
+        "The database of the tracked table, used by the background worker in the logical capture mode.",
+        &PGD_DATABASE,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "postgres_redis.slot_name",
+        "Replication slot name",
+        "The logical replication slot consumed by the background worker in the logical capture mode.",
+        &PGD_SLOT_NAME,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
 }
@@ -0,0 +1,153 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use pgrx::{pg_shmem_init, prelude::*, shmem::*, PGRXSharedMemory, PgLwLock};
+
+use crate::gucs;
+use crate::prshmem::{Info, MAX_INFO_LENGTH};
+
+/// The rows and columns of the count-min sketch. With 2048 counters per row, the count of a
+/// key is overestimated by at most 0.1% of all counted keys in 98% of the cases.
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 2048;
+
+/// The number of hottest keys kept.
+const TOP_KEYS: usize = 32;
+
+#[derive(Copy, Clone)]
+struct HotKey {
+    key: [u8; MAX_INFO_LENGTH],
+    key_length: usize,
+    count: u32,
+}
+
+impl HotKey {
+    fn key(&self) -> &[u8] {
+        &self.key[..self.key_length]
+    }
+}
+
+/// The keys of the captured changes, counted in a count-min sketch. The sketch only holds
+/// counts, the keys with the highest estimate are kept next to it.
+#[derive(Copy, Clone)]
+pub struct HotKeys {
+    sketch: [[u32; SKETCH_WIDTH]; SKETCH_DEPTH],
+    top: [HotKey; TOP_KEYS],
+    top_length: usize,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys {
+            sketch: [[0; SKETCH_WIDTH]; SKETCH_DEPTH],
+            top: [HotKey {
+                key: [0; MAX_INFO_LENGTH],
+                key_length: 0,
+                count: 0,
+            }; TOP_KEYS],
+            top_length: 0,
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for HotKeys {}
+
+pub static HOT_KEYS: PgLwLock<HotKeys> = PgLwLock::new();
+
+pub fn init() {
+    pg_shmem_init!(HOT_KEYS);
+}
+
+/// The column of `key` in the row `row` of the sketch. Every process runs the same binary, so
+/// they all hash a key the same way.
+fn bucket(row: usize, key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % SKETCH_WIDTH as u64) as usize
+}
+
+impl HotKeys {
+    fn count(&mut self, key: &[u8]) {
+        let mut estimate = u32::MAX;
+        for (row, counters) in self.sketch.iter_mut().enumerate() {
+            let counter = &mut counters[bucket(row, key)];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        let top = &mut self.top[..self.top_length];
+        if let Some(hot_key) = top.iter_mut().find(|hot_key| hot_key.key() == key) {
+            hot_key.count = estimate;
+            return;
+        }
+        let slot = if self.top_length < TOP_KEYS {
+            self.top_length += 1;
+            self.top_length - 1
+        } else {
+            match top
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, hot_key)| hot_key.count)
+            {
+                Some((i, coldest)) if coldest.count < estimate => i,
+                _ => return,
+            }
+        };
+        let hot_key = &mut self.top[slot];
+        hot_key.key[..key.len()].copy_from_slice(key);
+        hot_key.key_length = key.len();
+        hot_key.count = estimate;
+    }
+}
+
+/// Count the keys of `items` if `postgres_redis.track_hot_keys` is on.
+pub fn record(items: &[Info]) {
+    if !gucs::PGD_TRACK_HOT_KEYS.get() || items.is_empty() {
+        return;
+    }
+    let mut hot_keys = HOT_KEYS.exclusive();
+    for item in items {
+        let key = item.key_string();
+        // A key is at most MAX_INFO_LENGTH characters, cut it on a character boundary.
+        let mut length = key.len().min(MAX_INFO_LENGTH);
+        while !key.is_char_boundary(length) {
+            length -= 1;
+        }
+        hot_keys.count(&key.as_bytes()[..length]);
+    }
+}
+
+/// Forget every counted key.
+pub fn reset() {
+    *HOT_KEYS.exclusive() = HotKeys::default();
+}
+
+/// Returns the `count` keys with the most captured changes and an estimate of their number of
+/// changes, hottest first. The estimate can be too high, never too low. Keys are only counted
+/// while `postgres_redis.track_hot_keys` is on. The keys come from every table and database,
+/// so only superusers can call the function unless it is granted.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."hot_keys"("count" int DEFAULT 10)
+    RETURNS TABLE ("key" text, "estimated_changes" bigint)
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'hot_keys_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."hot_keys"(int) FROM PUBLIC;
+"#)]
+fn hot_keys(
+    count: i32,
+) -> TableIterator<'static, (name!(key, String), name!(estimated_changes, i64))> {
+    let hot_keys = *HOT_KEYS.share();
+    let mut top: Vec<(String, i64)> = hot_keys.top[..hot_keys.top_length]
+        .iter()
+        .map(|hot_key| {
+            (
+                String::from_utf8_lossy(hot_key.key()).into_owned(),
+                hot_key.count as i64,
+            )
+        })
+        .collect();
+    top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    top.truncate(count.max(0) as usize);
+    TableIterator::new(top)
+}
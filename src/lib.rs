@@ -1,22 +1,38 @@
-use std::time::Duration;
+use std::time::Instant;
 
 use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
-use pgrx::pg_sys::{CmdType_CMD_SELECT, CmdType_CMD_UPDATE, DestReceiver};
+use pgrx::pg_sys::{
+    CmdType_CMD_DELETE, CmdType_CMD_INSERT, CmdType_CMD_SELECT, CmdType_CMD_UPDATE, DestReceiver,
+};
 use pgrx::{prelude::*, register_hook, HookResult, PgHooks};
-use prshmem::{add_item, init_redis_buffer, move_redis_data, Info};
+use prshmem::{init_redis_buffer, move_redis_data, Info};
 use select::{create_custom_dest_receiver, CustomDestReceiver};
-use update::UpdateDestReceiver;
+pub mod bulk;
+pub mod client;
+pub mod commands;
+pub mod decoder;
+pub mod fdw;
 pub mod gucs;
+pub mod hotkeys;
+pub mod logging;
+pub mod logical;
+pub mod metrics;
 pub mod prshmem;
+pub mod readthrough;
 pub mod select;
+pub mod stats;
+pub mod trigger;
+pub mod twophase;
 pub mod update;
 pub mod utils;
+pub mod verify;
+pub mod warm;
+pub mod writer;
+pub mod xact;
 
 pgrx::pg_module_magic!();
 
 struct PRHook {
-    custom_receiver: Option<CustomDestReceiver>,
-    update_receiver: Option<UpdateDestReceiver>,
     where_clause_receiver: Option<(String, String)>,
     table: Option<String>,
     key_column: Option<String>,
@@ -27,7 +43,7 @@ struct PRHook {
 impl PgHooks for PRHook {
     fn planner(
         &mut self,
-        parse: PgBox<pg_sys::Query>,
+        mut parse: PgBox<pg_sys::Query>,
         query_string: *const std::os::raw::c_char,
         cursor_options: i32,
         bound_params: PgBox<pg_sys::ParamListInfoData>,
@@ -38,14 +54,51 @@ impl PgHooks for PRHook {
             bound_params: PgBox<pg_sys::ParamListInfoData>,
         ) -> HookResult<*mut pg_sys::PlannedStmt>,
     ) -> HookResult<*mut pg_sys::PlannedStmt> {
+        if self.table.is_none() {
+            self.keep_running = false;
+            return prev_hook(parse, query_string, cursor_options, bound_params);
+        }
+
         // Set a flag to true if the query involves the tracked table. This flag will be used
         // for a quick check throughout the rest of the process execution to determine if the
         // rest of the plugin should run.
+        if gucs::PGD_CAPTURE_MODE.get() == gucs::CaptureMode::Hooks {
+            unsafe {
+                update::add_cte_returning(
+                    &mut parse,
+                    self.table.as_ref().unwrap(),
+                    self.key_column.as_ref().unwrap(),
+                    self.value_column.as_ref().unwrap(),
+                )
+            };
+        }
         self.keep_running = utils::is_contain_table(parse.rtable, self.table.as_ref().unwrap());
         if !self.keep_running {
             return prev_hook(parse, query_string, cursor_options, bound_params);
         }
 
+        // The keys of the written rows are read from the rows themselves, see
+        // `update::add_returning`.
+        if [CmdType_CMD_INSERT, CmdType_CMD_UPDATE, CmdType_CMD_DELETE].contains(&parse.commandType)
+            || update::is_merge_query(&parse)
+        {
+            self.where_clause_receiver = None;
+            let returning = gucs::PGD_CAPTURE_MODE.get() == gucs::CaptureMode::Hooks
+                && unsafe {
+                    update::add_returning(
+                        &mut parse,
+                        self.table.as_ref().unwrap(),
+                        self.key_column.as_ref().unwrap(),
+                        self.value_column.as_ref().unwrap(),
+                    )
+                };
+            let result = prev_hook(parse, query_string, cursor_options, bound_params);
+            if returning && !result.inner.is_null() {
+                unsafe { (*result.inner).hasReturning = false };
+            }
+            return result;
+        }
+
         unsafe {
             self.where_clause_receiver = utils::get_where_object(
                 parse.jointree,
@@ -60,8 +113,43 @@ impl PgHooks for PRHook {
         if self.where_clause_receiver.is_none() {
             self.keep_running = false;
         }
-        prev_hook(parse, query_string, cursor_options, bound_params)
+        let result = prev_hook(parse, query_string, cursor_options, bound_params);
+        // The key of a point lookup may be a parameter, which the where clause object can't
+        // hold, so the plan itself is checked for a lookup redis can answer.
+        unsafe {
+            readthrough::plan(
+                result.inner,
+                self.table.as_ref().unwrap(),
+                self.key_column.as_ref().unwrap(),
+                self.value_column.as_ref().unwrap(),
+                cursor_options,
+            );
+        }
+        result
     }
+    fn executor_start(
+        &mut self,
+        query_desc: PgBox<pg_sys::QueryDesc>,
+        eflags: i32,
+        prev_hook: fn(query_desc: PgBox<pg_sys::QueryDesc>, eflags: i32) -> HookResult<()>,
+    ) -> HookResult<()> {
+        let query_desc_ptr = query_desc.as_ptr();
+        prev_hook(query_desc, eflags);
+        let query_desc = unsafe { PgBox::from_pg(query_desc_ptr) };
+        // In the logical capture mode the background worker reads the writes from the WAL.
+        if let (Some(table), Some(key), Some(value), gucs::CaptureMode::Hooks) = (
+            self.table.as_ref(),
+            self.key_column.as_ref(),
+            self.value_column.as_ref(),
+            gucs::PGD_CAPTURE_MODE.get(),
+        ) {
+            if update::is_write(&query_desc) {
+                update::capture_rows(&query_desc, table, key, value);
+            }
+        }
+        HookResult::new(())
+    }
+
     fn executor_run(
         &mut self,
         query_desc: PgBox<pg_sys::QueryDesc>,
@@ -76,7 +164,11 @@ impl PgHooks for PRHook {
         ) -> pgrx::HookResult<()>,
     ) -> pgrx::HookResult<()> {
         let op = query_desc.operation;
-        if op == CmdType_CMD_SELECT && self.keep_running {
+        if op == CmdType_CMD_SELECT
+            && self.keep_running
+            && self.table.is_some()
+            && !readthrough::is_read_through(&query_desc)
+        {
             // A DestReceiver object receives any tuples emitted by the select query. Every
             // QueryDesc object contains a destreceiver object. In other to get the emitted
             // tuples, the querydesc destreceiver pointer needs to be updated to a custom
@@ -87,6 +179,7 @@ impl PgHooks for PRHook {
             let mut custom_receiver: CustomDestReceiver =
                 create_custom_dest_receiver(self.value_column.as_ref().unwrap());
             custom_receiver.original_dest = Some(query_desc.dest);
+            let snapshot = query_desc.snapshot;
             let new_query_desc;
             unsafe {
                 let d = *query_desc.dest;
@@ -98,56 +191,146 @@ impl PgHooks for PRHook {
                 new_query_desc = PgBox::from_pg(q);
             }
             prev_hook(new_query_desc, direction, count, execute_once);
-            self.custom_receiver = Some(custom_receiver);
+            // A SELECT that follows a write of this transaction to the table may have read
+            // uncommitted rows, those values are not cached. Neither are the values of a
+            // snapshot that may miss a committed write, see `utils::snapshot_version`, so a
+            // write always replaces the value it overtook.
+            if let (Some(where_clause), Some(value)) =
+                (&self.where_clause_receiver, &custom_receiver.value)
+            {
+                let key = format!("{}{}", gucs::key_prefix(), where_clause.1);
+                // A value too long for redis is simply not cached.
+                match unsafe { utils::snapshot_version(snapshot) }
+                    .and_then(|version| Info::selected(&key, value, version))
+                {
+                    Some(info) if !xact::has_mapped_write() => {
+                        xact::push(info.with_relation(gucs::table_relid()))
+                    }
+                    _ => {}
+                }
+            }
         } else {
             prev_hook(query_desc, direction, count, execute_once);
         }
         HookResult::new(())
     }
 
+    fn process_utility_hook(
+        &mut self,
+        pstmt: PgBox<pg_sys::PlannedStmt>,
+        query_string: &core::ffi::CStr,
+        read_only_tree: Option<bool>,
+        context: pg_sys::ProcessUtilityContext,
+        params: PgBox<pg_sys::ParamListInfoData>,
+        query_env: PgBox<pg_sys::QueryEnvironment>,
+        dest: PgBox<pg_sys::DestReceiver>,
+        completion_tag: *mut pg_sys::QueryCompletion,
+        prev_hook: fn(
+            pstmt: PgBox<pg_sys::PlannedStmt>,
+            query_string: &core::ffi::CStr,
+            read_only_tree: Option<bool>,
+            context: pg_sys::ProcessUtilityContext,
+            params: PgBox<pg_sys::ParamListInfoData>,
+            query_env: PgBox<pg_sys::QueryEnvironment>,
+            dest: PgBox<pg_sys::DestReceiver>,
+            completion_tag: *mut pg_sys::QueryCompletion,
+        ) -> HookResult<()>,
+    ) -> HookResult<()> {
+        // Changes of a prepared transaction must only reach redis once it is committed, which
+        // may happen in another session or after a restart. They are persisted with the GID
+        // when the transaction is prepared and queued again by `COMMIT PREPARED`.
+        let transaction_stmt = unsafe { utils::get_transaction_stmt(pstmt.utilityStmt) };
+        if let Some((pg_sys::TransactionStmtKind_TRANS_STMT_PREPARE, gid)) = &transaction_stmt {
+            if unsafe { !pg_sys::IsAbortedTransactionBlockState() } {
+                twophase::persist_prepared(gid);
+            }
+        }
+        // COPY FROM and TRUNCATE don't run through the executor. In the logical capture mode
+        // both are decoded from the WAL instead.
+        let bulk_write = match &self.table {
+            Some(table) if gucs::PGD_CAPTURE_MODE.get() == gucs::CaptureMode::Hooks => unsafe {
+                utils::get_bulk_write_relations(pstmt.utilityStmt).and_then(
+                    |(is_truncate, relids)| {
+                        relids
+                            .into_iter()
+                            .find(|relid| utils::is_mapped_relation(*relid, table))
+                            .map(|relid| (is_truncate, relid))
+                    },
+                )
+            },
+            _ => None,
+        };
+        // The rows of a COPY are captured as it loads them, unless the capture function isn't
+        // installed or can't read the columns. Its keys are then all deleted, like for a
+        // TRUNCATE.
+        let mut copy_captured = false;
+        let pstmt = match bulk_write {
+            Some((false, relid)) => unsafe {
+                match bulk::add_capture(
+                    pstmt.as_ptr(),
+                    relid,
+                    self.key_column.as_ref().unwrap(),
+                    self.value_column.as_ref().unwrap(),
+                ) {
+                    Some(copy) => {
+                        copy_captured = true;
+                        PgBox::from_pg(copy)
+                    }
+                    None => pstmt,
+                }
+            },
+            _ => pstmt,
+        };
+        let result = prev_hook(
+            pstmt,
+            query_string,
+            read_only_tree,
+            context,
+            params,
+            query_env,
+            dest,
+            completion_tag,
+        );
+        bulk::end_copy();
+        if let Some((pg_sys::TransactionStmtKind_TRANS_STMT_COMMIT_PREPARED, gid)) =
+            &transaction_stmt
+        {
+            twophase::release_prepared(gid);
+        }
+        if let Some((is_truncate, _)) = bulk_write {
+            xact::mark_mapped_write();
+            if is_truncate || !copy_captured {
+                match Info::truncate(&gucs::key_prefix()) {
+                    Some(info) => xact::push_table_change(info.with_relation(gucs::table_relid())),
+                    None => prshmem::warn_key_too_long(&gucs::key_prefix()),
+                }
+            }
+        }
+        result
+    }
+
     fn executor_end(
         &mut self,
         query_desc: PgBox<pg_sys::QueryDesc>,
         prev_hook: fn(query_desc: PgBox<pg_sys::QueryDesc>) -> pgrx::HookResult<()>,
     ) -> pgrx::HookResult<()> {
-        let op = query_desc.operation;
-        if op == CmdType_CMD_UPDATE && self.keep_running {
-            let mut new_update_receiver = UpdateDestReceiver {
-                value: None,
-                column: String::new(),
-            };
-            update::handle_update(
-                &query_desc,
-                self.value_column.as_ref().unwrap(),
-                &mut new_update_receiver,
-            );
-            self.update_receiver = Some(new_update_receiver);
+        if self.table.is_none() {
+            return prev_hook(query_desc);
         }
+        let writing_table = update::is_write(&query_desc)
+            && update::is_writing_table(&query_desc, self.table.as_ref().unwrap());
+        if writing_table {
+            xact::mark_mapped_write();
+        }
+        update::end_capture(&query_desc);
         prev_hook(query_desc)
     }
 
     fn commit(&mut self) {
-        // Get the values of the key and value columns from the where clause object and custom/update receiver objects
-        // respectively. After extracting these, add them to the shared memory array. If the extraction
-        // happens, set the objects to null.
-        if self.custom_receiver.is_some() {
-            let custom_receiver = self.custom_receiver.as_ref().unwrap();
-            let key_string = &(self.where_clause_receiver.as_ref().unwrap().1);
-            if custom_receiver.value.is_some() {
-                let t = custom_receiver.value.as_ref().unwrap();
-                add_item(Info::new(key_string, t));
-            }
-            self.custom_receiver = None;
-        } else if self.update_receiver.is_some() {
-            let update_receiver = self.update_receiver.as_ref().unwrap();
-            let key_string = &(self.where_clause_receiver.as_ref().unwrap().1);
-            if update_receiver.value.is_some() {
-                let t = update_receiver.value.as_ref().unwrap();
-                add_item(Info::new(key_string, t));
-            }
-            self.update_receiver = None;
-        }
-
+        // The captured changes are flushed by the transaction callbacks in the `xact` module,
+        // this only resets the per-statement state.
+        update::reset_capture();
+        bulk::end_copy();
         if self.where_clause_receiver.is_some() {
             self.where_clause_receiver = None;
         }
@@ -156,14 +339,8 @@ impl PgHooks for PRHook {
 
     fn abort(&mut self) {
         // Set all the objects to null if transaction aborts.
-        if self.custom_receiver.is_some() {
-            self.custom_receiver = None;
-        }
-
-        if self.update_receiver.is_some() {
-            self.update_receiver = None;
-        }
-
+        update::reset_capture();
+        bulk::end_copy();
         if self.where_clause_receiver.is_some() {
             self.where_clause_receiver = None;
         }
@@ -172,8 +349,6 @@ impl PgHooks for PRHook {
 }
 
 static mut HOOK: PRHook = PRHook {
-    custom_receiver: None,
-    update_receiver: None,
     where_clause_receiver: None,
     table: None,
     key_column: None,
@@ -186,7 +361,12 @@ fn hello_postgres_redis() -> &'static str {
     "Hello, postgres_redis"
 }
 
+/// The hooks are always registered, prepared transactions rely on them for the changes of
+/// tables tracked with `postgres_redis.track_table` too. Queries are only inspected if the
+/// table, key column and value column GUCs are all set.
 unsafe fn init_hook() {
+    xact::register_callbacks();
+    register_hook(&mut HOOK);
     if gucs::PGD_REDIS_TABLE.get().is_none() {
         log!("Table name is not set");
         return;
@@ -220,27 +400,51 @@ unsafe fn init_hook() {
     HOOK.table = Some(table_name.to_string());
     HOOK.key_column = Some(key_column.to_string());
     HOOK.value_column = Some(value_column.to_string());
-    register_hook(&mut HOOK);
 }
 
 #[pg_guard]
 pub unsafe extern "C" fn _PG_init() {
     gucs::init();
     init_redis_buffer();
+    stats::init();
+    hotkeys::init();
     init_hook();
-    BackgroundWorkerBuilder::new("PGRedis Experiment")
-        .set_function("postgres_redis_background")
-        .set_library("postgres_redis")
-        .enable_shmem_access(None)
-        .load();
+    utils::register_relcache_callback();
+    readthrough::register();
+    // A background worker per shard of the buffer, the first one keeps the original name.
+    for shard in 0..gucs::PGD_WORKERS.get() as usize {
+        let name = match shard {
+            0 => String::from("PGRedis Experiment"),
+            shard => format!("PGRedis Experiment {shard}"),
+        };
+        let mut worker = BackgroundWorkerBuilder::new(&name)
+            .set_function("postgres_redis_background")
+            .set_library("postgres_redis")
+            .set_argument(Some(pg_sys::Datum::from(shard)))
+            .enable_shmem_access(None);
+        // The logical capture mode reads its replication slot through SPI.
+        if shard == 0 && gucs::PGD_CAPTURE_MODE.get() == gucs::CaptureMode::Logical {
+            worker = worker.enable_spi_access();
+        }
+        worker.load();
+    }
+    if gucs::PGD_METRICS_PORT.get() > 0 {
+        BackgroundWorkerBuilder::new("PGRedis Metrics")
+            .set_function("postgres_redis_metrics")
+            .set_library("postgres_redis")
+            .enable_shmem_access(None)
+            .load();
+    }
 }
 
-// This runs a custom postgres background worker that gets all the data in the shared memory
-// arrays and sends them to the redis service. The worker wakes up every `bg_delay` seconds to check
-// if the shared memory contains any data.
+// This runs a custom postgres background worker that gets all the data in its shard of the
+// shared memory arrays and sends them to the redis service. The worker is woken up by the
+// committing backends, and every flush interval to check the replication slot of the logical
+// capture mode.
 #[pg_guard]
 #[no_mangle]
-pub extern "C" fn postgres_redis_background() {
+pub extern "C" fn postgres_redis_background(arg: pg_sys::Datum) {
+    let shard = arg.value();
     BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
     log!(
         "Hello from inside the {} BGWorker",
@@ -256,32 +460,196 @@ pub extern "C" fn postgres_redis_background() {
         .to_str()
         .expect("URL extraction failed");
     let client = redis::Client::open(url).unwrap();
-    let mut connection = client.get_connection().unwrap();
-    let mut pipe = redis::pipe();
-
-    let delay = gucs::PGD_BG_DELAY.get() as u64;
-
-    while BackgroundWorker::wait_latch(Some(Duration::from_secs(delay))) {
-        let results = move_redis_data();
-        for i in results.iter() {
-            let key: String = i.key[0..i.key_length as usize].iter().collect();
-            let value: String = i.value[0..i.value_length as usize].iter().collect();
-            log!("From bg: {key} => {value}");
-            pipe.set(key, value).ignore();
+    // In the logical capture mode the writes come from the replication slot, which only the
+    // first worker reads, the shared memory buffer then only holds the values read by SELECT
+    // queries.
+    let logical = shard == 0 && gucs::PGD_CAPTURE_MODE.get() == gucs::CaptureMode::Logical;
+    let mut writer = writer::Writer::new(client, (!logical).then_some(shard));
+
+    let slot = match logical {
+        false => None,
+        true => {
+            BackgroundWorker::connect_worker_to_spi(Some(&gucs::database()), None);
+            match logical::LogicalSlot::open(&gucs::slot_name()) {
+                Some(slot) => Some(slot),
+                None => return,
+            }
+        }
+    };
+
+    // Committing backends set the latch when the first changes come in, and once the changes
+    // reach a batch threshold. An idle server only wakes the worker every flush interval.
+    prshmem::register_worker(shard);
+    let mut timeout = gucs::flush_interval();
+
+    while BackgroundWorker::wait_latch(Some(timeout)) {
+        // Pick up the changes of the settings reloaded with the configuration.
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        }
+        let wait = prshmem::time_until_due(shard);
+        if !wait.is_zero() {
+            timeout = wait.min(gucs::flush_interval());
+            continue;
+        }
+        timeout = gucs::flush_interval();
+        let (mut results, seq) = move_redis_data(shard);
+        let mut confirmed_lsn = None;
+        // Changes that fail to reach redis are decoded again, they are counted once written.
+        let mut decoded = vec![];
+        if let Some(slot) = &slot {
+            let (changes, lsn) = slot.peek_changes();
+            decoded = changes;
+            results.extend(decoded.iter().copied());
+            confirmed_lsn = lsn;
         }
-        if results.len() > 0 {
-            let _: () = pipe.query(&mut connection).unwrap();
-            pipe.clear();
+        let captured = results.len();
+        let results = writer::coalesce(results);
+        stats::record_coalesced(captured - results.len());
+        results.iter().for_each(logging::log_change);
+        let started = Instant::now();
+        let result = writer.write(&results);
+        if !results.is_empty() {
+            stats::record_latency(started.elapsed());
+            logging::log_round(&results, started.elapsed(), &result);
+        }
+        stats::record_write(&results, &result);
+        prshmem::acknowledge(shard, seq, result.is_ok());
+        stats::record_connection(
+            shard,
+            match &result {
+                Ok(()) => true,
+                Err(e) => !(e.is_io_error() || e.is_connection_dropped()),
+            },
+        );
+        if let (Ok(()), Some(slot), Some(lsn)) = (&result, &slot, confirmed_lsn) {
+            slot.advance(lsn);
+            stats::record_decoded(&decoded);
         }
     }
 }
 
+extension_sql_file!("../sql/postgres_redis.sql", bootstrap);
 extension_sql_file!("../sql/test.sql");
 
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
+    use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder};
     use pgrx::prelude::*;
+    use pgrx::{is_a, PgList};
+    use std::io::BufRead;
+
+    /// Run `query` in a background worker and wait for it to commit, for the tests that need
+    /// a transaction other than their own.
+    pub fn commit_in_worker(query: &str) {
+        let worker = BackgroundWorkerBuilder::new("postgres_redis test")
+            .set_function("postgres_redis_test_commit")
+            .set_library("postgres_redis")
+            .set_argument(Some(pg_sys::Datum::from(unsafe {
+                pg_sys::MyDatabaseId.as_u32()
+            })))
+            .set_extra(query)
+            .enable_spi_access()
+            .set_notify_pid(unsafe { pg_sys::MyProcPid })
+            .load_dynamic();
+        worker.wait_for_startup().expect("test worker didn't start");
+        worker.wait_for_shutdown().expect("test worker didn't stop");
+    }
+
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn postgres_redis_test_commit(arg: pg_sys::Datum) {
+        unsafe {
+            pg_sys::BackgroundWorkerInitializeConnectionByOid(
+                pg_sys::Oid::from(arg.value() as u32),
+                pg_sys::InvalidOid,
+                0,
+            );
+        }
+        BackgroundWorker::transaction(|| Spi::run(BackgroundWorker::get_extra()).unwrap());
+    }
+
+    /// Run the `;` separated `queries` in a background worker, each in a transaction command
+    /// of its own like the statements of a client, and wait for it to finish. Transaction
+    /// control statements go through `ProcessUtility`, so they can open a transaction block
+    /// and prepare or finish a prepared transaction.
+    pub fn run_session_in_worker(queries: &str) {
+        let worker = BackgroundWorkerBuilder::new("postgres_redis test")
+            .set_function("postgres_redis_test_session")
+            .set_library("postgres_redis")
+            .set_argument(Some(pg_sys::Datum::from(unsafe {
+                pg_sys::MyDatabaseId.as_u32()
+            })))
+            .set_extra(queries)
+            .enable_spi_access()
+            .set_notify_pid(unsafe { pg_sys::MyProcPid })
+            .load_dynamic();
+        worker.wait_for_startup().expect("test worker didn't start");
+        worker.wait_for_shutdown().expect("test worker didn't stop");
+    }
+
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn postgres_redis_test_session(arg: pg_sys::Datum) {
+        unsafe {
+            pg_sys::BackgroundWorkerInitializeConnectionByOid(
+                pg_sys::Oid::from(arg.value() as u32),
+                pg_sys::InvalidOid,
+                0,
+            );
+        }
+        for query in BackgroundWorker::get_extra().split(';') {
+            let query_string = std::ffi::CString::new(query).unwrap();
+            unsafe {
+                pg_sys::StartTransactionCommand();
+                let parsed = pg_sys::pg_parse_query(query_string.as_ptr());
+                let raw = PgList::<pg_sys::RawStmt>::from_pg(parsed)
+                    .head()
+                    .expect("empty query");
+                if is_a((*raw).stmt, pg_sys::NodeTag::T_TransactionStmt) {
+                    let mut pstmt =
+                        PgBox::<pg_sys::PlannedStmt>::alloc_node(pg_sys::NodeTag::T_PlannedStmt);
+                    pstmt.commandType = pg_sys::CmdType_CMD_UTILITY;
+                    pstmt.canSetTag = true;
+                    pstmt.utilityStmt = (*raw).stmt;
+                    pg_sys::ProcessUtility(
+                        pstmt.as_ptr(),
+                        query_string.as_ptr(),
+                        false,
+                        pg_sys::ProcessUtilityContext_PROCESS_UTILITY_TOPLEVEL,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        pg_sys::None_Receiver,
+                        std::ptr::null_mut(),
+                    );
+                } else {
+                    Spi::run(query).unwrap();
+                }
+                pg_sys::CommitTransactionCommand();
+            }
+        }
+    }
+
+    /// Read a command sent to a fake redis server, as its arguments. Returns None once the
+    /// client closed the connection.
+    pub fn read_command(reader: &mut impl BufRead) -> Option<Vec<String>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = vec![];
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let length: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; length + 2];
+            reader.read_exact(&mut arg).ok()?;
+            args.push(String::from_utf8_lossy(&arg[..length]).into_owned());
+        }
+        Some(args)
+    }
 
     #[pg_test]
     fn test_hello_postgres_redis() {
@@ -298,7 +666,17 @@ pub mod pg_test {
     }
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        // return any postgresql.conf settings that are required for your tests
-        vec![]
+        // The hooks and the shared memory buffer are only set up by a preloaded library. The
+        // background workers don't start without a redis URL, so the changes stay in the
+        // buffer for the tests to look at.
+        vec![
+            "shared_preload_libraries = 'postgres_redis'",
+            "postgres_redis.table = 'users'",
+            "postgres_redis.key_column = 'id'",
+            "postgres_redis.value_column = 'first_name'",
+            "postgres_redis.key_prefix = 'users:'",
+            "max_prepared_transactions = 2",
+            "wal_level = logical",
+        ]
     }
 }
@@ -0,0 +1,102 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use pgrx::prelude::*;
+
+use crate::gucs::{self, LogLevel};
+use crate::prshmem::{Info, Operation};
+
+/// Returns true if the messages of `level` are logged at the `postgres_redis.log_level`.
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && gucs::PGD_LOG_LEVEL.get() >= level
+}
+
+/// A stable 64-bit FNV-1a hash of `key`. Log lines carry it instead of the key, which may hold
+/// personal data, and it is the same across servers and releases so lines can be matched.
+pub fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Returns true if the per-key lines of `key` are logged at the `postgres_redis.log_sample_rate`.
+/// The sample is taken from the hash of the key, so a sampled key is logged every time.
+fn is_sampled(key: &str) -> bool {
+    let rate = gucs::PGD_LOG_SAMPLE_RATE.get();
+    rate >= 1.0 || ((key_hash(key) % 10_000) as f64) < rate * 10_000.0
+}
+
+/// The `mapping=` field of a change, empty if it doesn't come from a mapped table.
+fn mapping(item: &Info) -> String {
+    match item.relid {
+        pg_sys::InvalidOid => String::new(),
+        relid => relid.as_u32().to_string(),
+    }
+}
+
+/// Log a change written to redis, at the debug level and if its key is sampled. The value is
+/// only logged with `postgres_redis.log_values`, its length otherwise.
+pub fn log_change(item: &Info) {
+    if !enabled(LogLevel::Debug) {
+        return;
+    }
+    let key = item.key_string();
+    if !is_sampled(&key) {
+        return;
+    }
+    let value = match item.operation {
+        Operation::Set if gucs::PGD_LOG_VALUES.get() => format!(" value={:?}", item.value_string()),
+        Operation::Set => format!(" value_length={}", item.value_length),
+        _ => String::new(),
+    };
+    log!(
+        "postgres_redis: event=change mapping={} op={} key_hash={:016x} version={} from_select={}{value}",
+        mapping(item),
+        item.operation.name(),
+        key_hash(&key),
+        item.version,
+        item.from_select,
+    );
+}
+
+/// Log a round of the background worker that wrote `items` to redis in `elapsed`, at the info
+/// level, or at the warning level if it failed.
+pub fn log_round<E: Display>(items: &[Info], elapsed: Duration, result: &Result<(), E>) {
+    let count = |operation: Operation| {
+        items
+            .iter()
+            .filter(|item| item.operation == operation)
+            .count()
+    };
+    let fields = format!(
+        "items={} sets={} deletes={} truncates={} duration_ms={:.3}",
+        items.len(),
+        count(Operation::Set),
+        count(Operation::Delete),
+        count(Operation::Truncate),
+        elapsed.as_secs_f64() * 1000.0,
+    );
+    match result {
+        Ok(()) if enabled(LogLevel::Info) => log!("postgres_redis: event=flush {fields}"),
+        Ok(()) => (),
+        Err(err) if enabled(LogLevel::Warning) => {
+            warning!(
+                "postgres_redis: event=flush_failed {fields} error={:?}",
+                err.to_string()
+            )
+        }
+        Err(_) => (),
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_logging_key_hash() {
+        assert_eq!(0xcbf29ce484222325, super::key_hash(""));
+        assert_eq!(0xaf63dc4c8601ec8c, super::key_hash("a"));
+    }
+}
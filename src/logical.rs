@@ -0,0 +1,100 @@
+use pgrx::bgworkers::BackgroundWorker;
+use pgrx::{prelude::*, warning};
+
+use crate::decoder::Record;
+use crate::prshmem::Info;
+
+/// The most changes read from the slot in one round of the background worker. Decoding never
+/// stops in the middle of a transaction, so a round can return a few more.
+const MAX_CHANGES_PER_ROUND: i32 = 400;
+
+/// A logical replication slot decoded with the `postgres_redis` output plugin. The changes
+/// are only peeked at, the slot is advanced once they are written to redis. Changes that
+/// were written but not confirmed are decoded again after a restart, which is harmless
+/// since redis ignores a version it already has.
+pub struct LogicalSlot {
+    name: String,
+}
+
+impl LogicalSlot {
+    /// Open the slot `name`, creating it if it doesn't exist. Returns None if the server
+    /// doesn't run with `wal_level = logical`.
+    pub fn open(name: &str) -> Option<LogicalSlot> {
+        let name = name.to_string();
+        let slot_name = name.clone();
+        BackgroundWorker::transaction(move || {
+            let wal_level = Spi::get_one::<String>("SELECT current_setting('wal_level')")
+                .unwrap_or(None)
+                .unwrap_or_default();
+            if wal_level != "logical" {
+                warning!("postgres_redis.capture_mode = logical requires wal_level = logical");
+                return false;
+            }
+            let exists = Spi::get_one_with_args::<bool>(
+                "SELECT EXISTS (SELECT 1 FROM pg_replication_slots WHERE slot_name = $1)",
+                vec![(PgBuiltInOids::TEXTOID.oid(), slot_name.clone().into_datum())],
+            )
+            .unwrap_or(None)
+            .unwrap_or(false);
+            if !exists {
+                Spi::run_with_args(
+                    "SELECT pg_create_logical_replication_slot($1, 'postgres_redis')",
+                    Some(vec![(PgBuiltInOids::TEXTOID.oid(), slot_name.into_datum())]),
+                )
+                .expect("failed to create the logical replication slot");
+            }
+            true
+        })
+        .then_some(LogicalSlot { name })
+    }
+
+    /// Decode the next changes of the slot. Returns the changes along with the WAL position
+    /// the slot can be advanced to once they are written.
+    pub fn peek_changes(&self) -> (Vec<Info>, Option<String>) {
+        let name = self.name.clone();
+        BackgroundWorker::transaction(move || {
+            Spi::connect(|client| {
+                let rows = client
+                    .select(
+                        "SELECT lsn::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2)",
+                        None,
+                        Some(vec![
+                            (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                            (
+                                PgBuiltInOids::INT4OID.oid(),
+                                MAX_CHANGES_PER_ROUND.into_datum(),
+                            ),
+                        ]),
+                    )
+                    .expect("failed to read the logical replication slot");
+                let mut changes = vec![];
+                let mut confirmed_lsn = None;
+                for row in rows {
+                    let lsn: Option<String> = row.get(1).unwrap_or(None);
+                    let data: Option<String> = row.get(2).unwrap_or(None);
+                    match data.as_deref().and_then(Record::parse) {
+                        Some(Record::Change(info)) => changes.push(*info),
+                        Some(Record::Commit(_)) => confirmed_lsn = lsn,
+                        None => {}
+                    }
+                }
+                (changes, confirmed_lsn)
+            })
+        })
+    }
+
+    /// Move the slot past `lsn`, the changes up to it are never decoded again.
+    pub fn advance(&self, lsn: String) {
+        let name = self.name.clone();
+        BackgroundWorker::transaction(move || {
+            Spi::run_with_args(
+                "SELECT pg_replication_slot_advance($1, $2::pg_lsn)",
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), lsn.into_datum()),
+                ]),
+            )
+            .expect("failed to advance the logical replication slot");
+        })
+    }
+}
@@ -0,0 +1,312 @@
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use pgrx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+use pgrx::prelude::*;
+
+use crate::gucs;
+use crate::prshmem;
+use crate::stats::{self, MappingStats, Stats, LATENCY_BUCKETS};
+
+/// How often the listener is polled for new connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The largest request read, the headers of a scrape are much smaller.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Microseconds between the unix epoch and the postgres epoch, 2000-01-01.
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// Render the counters of the sync pipeline in the Prometheus text format. The mapped tables
+/// are labelled with their oid, the metrics worker isn't connected to a database to read their
+/// names.
+pub fn render(stats: &Stats, queue_depth: i32) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        let _ = writeln!(out, "# HELP postgres_redis_{name} {help}");
+        let _ = writeln!(out, "# TYPE postgres_redis_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "postgres_redis_{name}{labels} {value}");
+        }
+    };
+    let value = |value: i64| vec![(String::new(), value.to_string())];
+
+    metric(
+        "captured_total",
+        "counter",
+        "Changes captured from committed transactions, by operation.",
+        &[
+            ("set", stats.captured_sets),
+            ("delete", stats.captured_deletes),
+            ("truncate", stats.captured_truncates),
+            ("select", stats.captured_selects),
+        ]
+        .map(|(operation, count)| (format!("{{operation=\"{operation}\"}}"), count.to_string())),
+    );
+    metric(
+        "enqueued_total",
+        "counter",
+        "Changes added to the shared memory buffer.",
+        &value(stats.enqueued),
+    );
+    metric(
+        "dropped_total",
+        "counter",
+        "Changes discarded because the shared memory buffer was full.",
+        &value(stats.dropped),
+    );
+    metric(
+        "coalesced_total",
+        "counter",
+        "Changes skipped for a newer change of the same key.",
+        &value(stats.coalesced),
+    );
+    metric(
+        "sent_total",
+        "counter",
+        "Changes written to redis.",
+        &value(stats.sent),
+    );
+    metric(
+        "failed_total",
+        "counter",
+        "Changes of the rounds that failed to write to redis.",
+        &value(stats.failed),
+    );
+    metric(
+        "retries_total",
+        "counter",
+        "Pipelines sent again after redis lost the compare-and-set script.",
+        &value(stats.retries),
+    );
+    metric(
+        "queue_depth",
+        "gauge",
+        "Changes waiting in the shared memory buffer.",
+        &value(queue_depth as i64),
+    );
+    metric(
+        "redis_up",
+        "gauge",
+        "Whether a background worker has a working redis connection.",
+        &stats.redis_connected[..gucs::PGD_WORKERS.get() as usize]
+            .iter()
+            .enumerate()
+            .map(|(worker, connected)| {
+                (
+                    format!("{{worker=\"{worker}\"}}"),
+                    (*connected as i64).to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    for (name, help, timestamp) in [
+        (
+            "last_flush_timestamp_seconds",
+            "When the background worker last wrote to redis.",
+            stats.last_flush_at,
+        ),
+        (
+            "last_error_timestamp_seconds",
+            "When the background worker last failed to write to redis.",
+            stats.last_error_at,
+        ),
+    ] {
+        let seconds = match timestamp {
+            0 => 0.0,
+            timestamp => (timestamp + POSTGRES_EPOCH_MICROS) as f64 / 1_000_000.0,
+        };
+        metric(name, "gauge", help, &[(String::new(), seconds.to_string())]);
+    }
+
+    let mut buckets = vec![];
+    let mut count = 0;
+    for (i, rounds) in stats.latency_buckets.iter().enumerate() {
+        count += rounds;
+        let bound = match LATENCY_BUCKETS.get(i) {
+            Some(bound) => (*bound as f64 / 1_000_000.0).to_string(),
+            None => String::from("+Inf"),
+        };
+        buckets.push((format!("_bucket{{le=\"{bound}\"}}"), count.to_string()));
+    }
+    buckets.push((
+        String::from("_sum"),
+        (stats.latency_sum as f64 / 1_000_000.0).to_string(),
+    ));
+    buckets.push((String::from("_count"), count.to_string()));
+    metric(
+        "write_duration_seconds",
+        "histogram",
+        "Time the background worker took to write a round of changes to redis.",
+        &buckets,
+    );
+
+    let mappings = &stats.mappings[..stats.mappings_length];
+    let label = |relid: pg_sys::Oid| match relid {
+        pg_sys::InvalidOid => String::new(),
+        relid => relid.as_u32().to_string(),
+    };
+    metric(
+        "mapping_captured_total",
+        "counter",
+        "Changes captured from a mapped table, by operation. An empty relid counts the changes that don't come from a mapped table.",
+        &mappings
+            .iter()
+            .flat_map(|mapping| {
+                [
+                    ("set", mapping.sets),
+                    ("delete", mapping.deletes),
+                    ("truncate", mapping.truncates),
+                    ("select", mapping.selects),
+                ]
+                .map(|(operation, count)| {
+                    (
+                        format!(
+                            "{{relid=\"{}\",operation=\"{operation}\"}}",
+                            label(mapping.relid)
+                        ),
+                        count.to_string(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    let per_mapping = |counter: fn(&MappingStats) -> i64| {
+        mappings
+            .iter()
+            .map(|mapping| {
+                (
+                    format!("{{relid=\"{}\"}}", label(mapping.relid)),
+                    counter(mapping).to_string(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "mapping_dropped_total",
+        "counter",
+        "Changes of a mapped table discarded because the shared memory buffer was full.",
+        &per_mapping(|mapping| mapping.dropped),
+    );
+    metric(
+        "mapping_sent_total",
+        "counter",
+        "Changes of a mapped table written to redis.",
+        &per_mapping(|mapping| mapping.sent),
+    );
+    metric(
+        "mapping_failed_total",
+        "counter",
+        "Changes of a mapped table in the rounds that failed to write to redis.",
+        &per_mapping(|mapping| mapping.failed),
+    );
+    metric(
+        "mapping_sent_bytes_total",
+        "counter",
+        "Bytes of the keys and values of a mapped table written to redis.",
+        &per_mapping(|mapping| mapping.sent_bytes),
+    );
+    out
+}
+
+/// Answer a request on `stream`: the metrics for `GET /metrics`, an error otherwise.
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let stats = stats::snapshot();
+            ("200 OK", render(&stats, prshmem::data_size()))
+        }
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found, use /metrics\n")),
+        _ => ("405 Method Not Allowed", String::from("Use GET /metrics\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// A background worker serving the counters of the sync pipeline in the Prometheus text format
+/// on `postgres_redis.metrics_port` of the local host. It only reads shared memory, so a slow
+/// scrape never holds up the writes to redis.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn postgres_redis_metrics() {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let port = gucs::PGD_METRICS_PORT.get() as u16;
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log!("postgres_redis: failed to listen for metrics on port {port}: {err}");
+            return;
+        }
+    };
+    if let Err(err) = listener.set_nonblocking(true) {
+        log!("postgres_redis: failed to set up the metrics listener: {err}");
+        return;
+    }
+    log!("postgres_redis: serving metrics on http://127.0.0.1:{port}/metrics");
+
+    while BackgroundWorker::wait_latch(Some(POLL_INTERVAL)) {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = serve(stream) {
+                        log!("postgres_redis: failed to serve metrics: {err}");
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log!("postgres_redis: failed to accept a metrics connection: {err}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_metrics_render() {
+        use crate::stats::Stats;
+        let stats = Stats {
+            sent: 3,
+            latency_buckets: [0, 1, 0, 0, 0, 0, 0, 0, 2],
+            latency_sum: 12_004_000,
+            ..Default::default()
+        };
+        let metrics = super::render(&stats, 5);
+        assert!(metrics.contains("\npostgres_redis_sent_total 3\n"));
+        assert!(metrics.contains("\npostgres_redis_queue_depth 5\n"));
+        assert!(metrics.contains("\npostgres_redis_redis_up{worker=\"0\"} 0\n"));
+        assert!(
+            metrics.contains("\npostgres_redis_write_duration_seconds_bucket{le=\"0.005\"} 1\n")
+        );
+        assert!(metrics.contains("\npostgres_redis_write_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(metrics.contains("\npostgres_redis_write_duration_seconds_sum 12.004\n"));
+    }
+}
@@ -1,62 +1,555 @@
+use std::time::Duration;
+
 use pgrx::{pg_guard, pg_shmem_init, prelude::*, shmem::*, warning, PGRXSharedMemory, PgLwLock};
 
+use crate::gucs::{self, WritePolicy};
+use crate::logging;
+
+/// The redis command the background worker runs for an `Info` object.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Set,
+    Delete,
+    /// Delete every key of a truncated table. The key of the `Info` holds the key prefix of
+    /// the table mapping.
+    Truncate,
+}
+
+impl Operation {
+    /// The name used when an operation is stored outside of shared memory.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Set => "set",
+            Operation::Delete => "delete",
+            Operation::Truncate => "truncate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Operation> {
+        match name {
+            "set" => Some(Operation::Set),
+            "delete" => Some(Operation::Delete),
+            "truncate" => Some(Operation::Truncate),
+            _ => None,
+        }
+    }
+}
+
+/// The longest key or value an `Info` holds, in characters.
+pub const MAX_INFO_LENGTH: usize = 127;
+
 /// A key value struct containing the redis key and value. The key and value strings are stored as array
 /// because this objects of this struct will be stored on the stack and not the heap. This implies that
 /// the size of this struct has to be known beforehand. The length of these strings is stored in the
 /// different length members.
 #[derive(Copy, Clone)]
 pub struct Info {
-    pub key: [char; 127],
-    pub value: [char; 127],
+    pub key: [char; MAX_INFO_LENGTH],
+    pub value: [char; MAX_INFO_LENGTH],
     pub key_length: i8,
     pub value_length: i8,
+    pub operation: Operation,
+    /// True if the value was read by a SELECT query rather than written by the table.
+    pub from_select: bool,
+    /// The WAL position the value belongs to. A write gets the end of its commit record and
+    /// a SELECT value the WAL insert position once it was read, see `utils::snapshot_version`. Redis keeps the
+    /// version next to the value so that an older value never replaces a newer one.
+    pub version: u64,
+    /// The mapped table the change was captured from, InvalidOid if it isn't known, e.g. for
+    /// `redis_enqueue` or the changes decoded from the replication slot.
+    pub relid: pg_sys::Oid,
+}
+
+/// Copy `string` into a fixed size array, returns None if it has more than
+/// `MAX_INFO_LENGTH` characters.
+fn to_chars(string: &str) -> Option<([char; MAX_INFO_LENGTH], i8)> {
+    let mut chars = [' '; MAX_INFO_LENGTH];
+    let mut length = 0;
+    for c in string.chars() {
+        *chars.get_mut(length)? = c;
+        length += 1;
+    }
+    Some((chars, length as i8))
 }
 
 impl Info {
     /// Create a new Info object that contains the `key_string` and
-    /// `value_string` along with their lengths. This object will be
-    /// returned from the function.
-    pub fn new(key_string: &str, value_string: &str) -> Info {
-        let mut key = [' '; 127];
-        for (i, c) in key_string.chars().enumerate() {
-            key[i] = c;
-        }
-        let mut value = [' '; 127];
-        for (i, c) in value_string.chars().enumerate() {
-            value[i] = c;
-        }
-        Info {
+    /// `value_string` along with their lengths. Returns None if either of them is longer
+    /// than `MAX_INFO_LENGTH` characters.
+    pub fn new(key_string: &str, value_string: &str) -> Option<Info> {
+        let (key, key_length) = to_chars(key_string)?;
+        let (value, value_length) = to_chars(value_string)?;
+        Some(Info {
             key,
-            key_length: key_string.len() as i8,
+            key_length,
             value,
-            value_length: value_string.len() as i8,
+            value_length,
+            operation: Operation::Set,
+            from_select: false,
+            version: 0,
+            relid: pg_sys::InvalidOid,
+        })
+    }
+
+    /// Create a new Info object for a value written to a mapped table. A value too long to
+    /// be held removes the key instead, so redis doesn't keep the previous value of the row.
+    /// Returns None if the key itself is too long.
+    pub fn write(key_string: &str, value_string: &str) -> Option<Info> {
+        Info::new(key_string, value_string).or_else(|| Info::delete(key_string))
+    }
+
+    /// Attribute the change to the mapped table `relid`.
+    pub fn with_relation(mut self, relid: pg_sys::Oid) -> Info {
+        self.relid = relid;
+        self
+    }
+
+    /// Apply the write `policy` of the mapped table: with `Invalidate`, a written value deletes
+    /// its key instead. The values read by SELECT queries are kept.
+    pub fn with_policy(mut self, policy: WritePolicy) -> Info {
+        if policy == WritePolicy::Invalidate
+            && self.operation == Operation::Set
+            && !self.from_select
+        {
+            self.operation = Operation::Delete;
+            self.value_length = 0;
         }
+        self
+    }
+
+    /// Create a new Info object for a value read by a SELECT query at `version`.
+    pub fn selected(key_string: &str, value_string: &str, version: u64) -> Option<Info> {
+        let mut info = Info::new(key_string, value_string)?;
+        info.from_select = true;
+        info.version = version;
+        Some(info)
+    }
+
+    /// Create a new Info object that removes `key_string` from redis.
+    pub fn delete(key_string: &str) -> Option<Info> {
+        let mut info = Info::new(key_string, "")?;
+        info.operation = Operation::Delete;
+        Some(info)
+    }
+
+    /// Create a new Info object that removes `key_string`, for a key found to have no row at
+    /// `version`. Like a SELECT value, it never replaces a change written after that.
+    pub fn deleted(key_string: &str, version: u64) -> Option<Info> {
+        let mut info = Info::delete(key_string)?;
+        info.from_select = true;
+        info.version = version;
+        Some(info)
+    }
+
+    /// Create a new Info object that removes every key starting with `key_prefix`.
+    pub fn truncate(key_prefix: &str) -> Option<Info> {
+        let mut info = Info::new(key_prefix, "")?;
+        info.operation = Operation::Truncate;
+        Some(info)
+    }
+
+    pub fn key_string(&self) -> String {
+        self.key[0..self.key_length as usize].iter().collect()
+    }
+
+    pub fn value_string(&self) -> String {
+        self.value[0..self.value_length as usize].iter().collect()
     }
 }
 
 unsafe impl PGRXSharedMemory for Info {}
 
-pub static REDIS_BUFFER: PgLwLock<heapless::Vec<Info, 400>> = PgLwLock::new();
+/// Warn that the change of `key_string` doesn't reach redis, since the key is longer than
+/// `MAX_INFO_LENGTH` characters.
+pub fn warn_key_too_long(key_string: &str) {
+    let shown: String = key_string.chars().take(32).collect();
+    warning!(
+        "postgres_redis: the key {shown}... is longer than {MAX_INFO_LENGTH} characters, its change is not sent to redis"
+    );
+}
+
+/// The most background workers, each one writes the items of its shard of the buffer.
+pub const MAX_WORKERS: usize = 8;
+
+/// The number of failed rounds of the background worker remembered for the waiting backends.
+const FAILED_ROUNDS: usize = 16;
+
+/// The most backends woken up once their changes are written, further ones poll.
+const MAX_WAITERS: usize = 32;
+
+/// How often a backend that couldn't register its latch checks if its changes are written.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How often a waiting backend checks the buffer without being woken up, in case the
+/// background worker stopped.
+const MAX_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The changes waiting for a background worker, and what it takes to wake it up.
+#[derive(Default)]
+pub struct RedisBuffer {
+    items: heapless::Vec<Info, 400>,
+    /// The length of the keys and values of `items`.
+    bytes: usize,
+    /// When the oldest of `items` was added, zero while there are none.
+    oldest: pg_sys::TimestampTz,
+    /// The address of the latch of the background worker, zero while it isn't running.
+    latch: usize,
+    /// The number of items ever added, i.e. the sequence number of the last one.
+    added: u64,
+    /// The sequence number up to which the background worker finished its rounds.
+    written: u64,
+    /// The sequence numbers `(after, up to)` of the last rounds that failed.
+    failed: [(u64, u64); FAILED_ROUNDS],
+    failed_next: usize,
+    /// The addresses of the latches of the backends waiting for their items to be written.
+    waiters: [usize; MAX_WAITERS],
+}
+
+unsafe impl PGRXSharedMemory for RedisBuffer {}
+
+impl RedisBuffer {
+    /// Returns how long the background worker may still wait before it writes the items, zero
+    /// once one of the `postgres_redis.max_batch_*` thresholds is reached.
+    fn time_until_due(&self, now: pg_sys::TimestampTz) -> Duration {
+        if self.items.len() >= gucs::PGD_MAX_BATCH_ITEMS.get() as usize
+            || self.bytes >= gucs::PGD_MAX_BATCH_BYTES.get() as usize
+        {
+            return Duration::ZERO;
+        }
+        let due = self.oldest + gucs::PGD_MAX_BATCH_DELAY.get() as i64 * 1000;
+        Duration::from_micros(due.saturating_sub(now).max(0) as u64)
+    }
+}
+
+/// The buffer, split into a shard per background worker with its own lock. Only the shards of
+/// the `postgres_redis.workers` are allocated.
+pub struct RedisBuffers([PgLwLock<RedisBuffer>; MAX_WORKERS]);
+
+impl RedisBuffers {
+    fn shard(&self, shard: usize) -> &PgLwLock<RedisBuffer> {
+        &self.0[shard]
+    }
+
+    fn shards(&self) -> &[PgLwLock<RedisBuffer>] {
+        &self.0[..shards()]
+    }
+}
+
+impl PgSharedMemoryInitialization for RedisBuffers {
+    fn pg_init(&'static self) {
+        self.shards().iter().for_each(|shard| shard.pg_init());
+    }
+
+    fn shmem_init(&'static self) {
+        self.shards().iter().for_each(|shard| shard.shmem_init());
+    }
+}
+
+pub static REDIS_BUFFER: RedisBuffers = RedisBuffers([const { PgLwLock::new() }; MAX_WORKERS]);
+
+/// The number of shards of the buffer, one per background worker.
+fn shards() -> usize {
+    gucs::PGD_WORKERS.get() as usize
+}
 
-/// Extract and remove all the items from the shared memory vector buffer
-pub fn move_redis_data() -> Vec<Info> {
-    let mut vec = REDIS_BUFFER.exclusive();
-    let r = vec.iter().copied().collect::<Vec<Info>>();
-    vec.clear();
-    r
+/// Returns the shard of `key`, and so the background worker writing it. All the changes of a
+/// key go through the same worker, which keeps them in order.
+pub fn shard(key: &str) -> usize {
+    (logging::key_hash(key) % shards() as u64) as usize
+}
+
+/// Extract and remove all the items from the `shard` of the buffer, along with the sequence
+/// number of the last one to pass to `acknowledge`.
+pub fn move_redis_data(shard: usize) -> (Vec<Info>, u64) {
+    let mut buffer = REDIS_BUFFER.shard(shard).exclusive();
+    let r = buffer.items.iter().copied().collect::<Vec<Info>>();
+    buffer.items.clear();
+    buffer.bytes = 0;
+    buffer.oldest = 0;
+    (r, buffer.added)
 }
 
 pub fn data_size() -> i32 {
-    REDIS_BUFFER.share().len() as i32
+    REDIS_BUFFER
+        .shards()
+        .iter()
+        .map(|shard| shard.share().items.len() as i32)
+        .sum()
 }
 
 pub fn add_item(item: Info) {
+    if !add_items([item]).dropped.is_empty() {
+        warning!("postgres_redis: the buffer is full, discarding a change");
+    }
+}
+
+/// Where `add_items` put the items of a transaction.
+pub struct Enqueued {
+    /// The positions of the items that were discarded because their shard is full.
+    pub dropped: Vec<usize>,
+    /// The shards the items were added to, with the sequence number of the last one to wait
+    /// for with `wait_for_write`.
+    pub seqs: Vec<(usize, u64)>,
+}
+
+/// Add all the `items` to the shards of their keys, under a single lock per shard. A truncate
+/// goes to every shard, each background worker deletes the keys of its own shard.
+///
+/// A background worker is woken up when the first items come in, so it can schedule its next
+/// write, and once the items reach one of the `postgres_redis.max_batch_*` thresholds. Once a
+/// shard is full, the backend waits for its background worker to take the items out, see
+/// `wait_for_room`. Items are only dropped if the worker doesn't run or the wait is
+/// interrupted.
+pub fn add_items(items: impl IntoIterator<Item = Info>) -> Enqueued {
+    let mut sharded = vec![vec![]; shards()];
+    for (position, item) in items.into_iter().enumerate() {
+        match item.operation {
+            Operation::Truncate => sharded
+                .iter_mut()
+                .for_each(|items| items.push((position, item))),
+            _ => sharded[shard(&item.key_string())].push((position, item)),
+        }
+    }
+    let mut enqueued = Enqueued {
+        dropped: vec![],
+        seqs: vec![],
+    };
+    for (shard, items) in sharded.into_iter().enumerate() {
+        let mut items = items.into_iter().peekable();
+        let mut seq = None;
+        while items.peek().is_some() {
+            let now = unsafe { pg_sys::GetCurrentTimestamp() };
+            let mut buffer = REDIS_BUFFER.shard(shard).exclusive();
+            let was_empty = buffer.items.is_empty();
+            while let Some((_, item)) = items.peek() {
+                if buffer.items.push(*item).is_err() {
+                    break;
+                }
+                buffer.bytes += item.key_length as usize + item.value_length as usize;
+                buffer.added += 1;
+                seq = Some(buffer.added);
+                items.next();
+            }
+            if was_empty && !buffer.items.is_empty() {
+                buffer.oldest = now;
+            }
+            let full = items.peek().is_some();
+            let latch = buffer.latch as *mut pg_sys::Latch;
+            let wake = was_empty || full || buffer.time_until_due(now).is_zero();
+            drop(buffer);
+            if wake && !latch.is_null() {
+                unsafe { pg_sys::SetLatch(latch) };
+            }
+            if full && wait_for_room(shard) != WriteOutcome::Written {
+                enqueued
+                    .dropped
+                    .extend(items.by_ref().map(|(position, _)| position));
+            }
+        }
+        if let Some(seq) = seq {
+            enqueued.seqs.push((shard, seq));
+        }
+    }
+    // A truncate dropped by several shards is counted once.
+    enqueued.dropped.sort_unstable();
+    enqueued.dropped.dedup();
+    enqueued
+}
+
+/// Returns how long the background worker of `shard` may still wait before it writes the
+/// buffered items, zero if there are none.
+pub fn time_until_due(shard: usize) -> Duration {
+    let buffer = REDIS_BUFFER.shard(shard).share();
+    if buffer.items.is_empty() {
+        return Duration::ZERO;
+    }
+    buffer.time_until_due(unsafe { pg_sys::GetCurrentTimestamp() })
+}
+
+#[pg_guard]
+unsafe extern "C" fn unregister_worker(_code: i32, arg: pg_sys::Datum) {
+    REDIS_BUFFER.shard(arg.value()).exclusive().latch = 0;
+}
+
+/// Let the committing backends wake up the calling background worker of `shard`.
+pub fn register_worker(shard: usize) {
+    REDIS_BUFFER.shard(shard).exclusive().latch = unsafe { pg_sys::MyLatch } as usize;
+    unsafe { pg_sys::on_shmem_exit(Some(unregister_worker), pg_sys::Datum::from(shard)) };
+}
+
+/// Returns a copy of the items waiting in every shard of the buffer.
+#[cfg(any(test, feature = "pg_test"))]
+pub fn buffered_items() -> Vec<Info> {
+    REDIS_BUFFER
+        .shards()
+        .iter()
+        .flat_map(|shard| shard.share().items.iter().copied().collect::<Vec<Info>>())
+        .collect()
+}
+
+/// Returns true while the background workers of all the shards run.
+pub fn is_worker_running() -> bool {
     REDIS_BUFFER
-        .exclusive()
-        .push(item)
-        .unwrap_or_else(|_| warning!("Vector is full, discarding update"));
+        .shards()
+        .iter()
+        .all(|shard| shard.share().latch != 0)
+}
+
+/// Record that the background worker of `shard` finished the round of the items up to the
+/// sequence number `seq`, whether they were `written` or not, and wake up the backends waiting
+/// for them.
+pub fn acknowledge(shard: usize, seq: u64, written: bool) {
+    let mut buffer = REDIS_BUFFER.shard(shard).exclusive();
+    if seq <= buffer.written {
+        return;
+    }
+    if !written {
+        let next = buffer.failed_next;
+        buffer.failed[next] = (buffer.written, seq);
+        buffer.failed_next = (next + 1) % FAILED_ROUNDS;
+    }
+    buffer.written = seq;
+    let waiters = buffer.waiters;
+    drop(buffer);
+    for latch in waiters.into_iter().filter(|latch| *latch != 0) {
+        unsafe { pg_sys::SetLatch(latch as *mut pg_sys::Latch) };
+    }
+}
+
+/// What became of the items a backend waited for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,
+    /// The round of the background worker writing them failed.
+    Failed,
+    TimedOut,
+    /// The wait was cancelled, or the server is shutting down.
+    Interrupted,
+}
+
+/// Wait up to `timeout` for the background workers to write the items up to the sequence
+/// numbers `seqs` of their shards, returns the outcome of every shard. This runs once the
+/// transaction committed, where an error can't be raised anymore: interrupts are left
+/// pending, ending the wait, and are processed after the commit.
+pub fn wait_for_write(seqs: &[(usize, u64)], timeout: Duration) -> Vec<(usize, WriteOutcome)> {
+    let deadline = std::time::Instant::now() + timeout;
+    seqs.iter()
+        .map(|(shard, seq)| (*shard, wait_for_shard(*shard, *seq, deadline)))
+        .collect()
+}
+
+/// Wait until `deadline` for the background worker of `shard` to write the items up to the
+/// sequence number `seq`.
+fn wait_for_shard(shard: usize, seq: u64, deadline: std::time::Instant) -> WriteOutcome {
+    wait_on_shard(shard, Some(deadline), |buffer| {
+        if buffer.written < seq {
+            return None;
+        }
+        let failed = buffer
+            .failed
+            .iter()
+            .any(|(after, up_to)| *after < seq && seq <= *up_to);
+        Some(if failed {
+            WriteOutcome::Failed
+        } else {
+            WriteOutcome::Written
+        })
+    })
+}
+
+/// Wait for the background worker of the full `shard` to take its items out, returns
+/// `Written` once there is room. The worker empties the shard at the start of every round,
+/// so the wait lasts up to a round. Fails right away if the worker isn't running.
+fn wait_for_room(shard: usize) -> WriteOutcome {
+    wait_on_shard(shard, None, |buffer| {
+        if !buffer.items.is_full() {
+            Some(WriteOutcome::Written)
+        } else if buffer.latch == 0 {
+            Some(WriteOutcome::Failed)
+        } else {
+            None
+        }
+    })
+}
+
+/// Wait until `outcome` returns the outcome of the wait from the state of `shard`, or until
+/// `deadline`. The backend is woken up once a round of the background worker ends, or polls
+/// if all the waiter slots are taken.
+fn wait_on_shard(
+    shard: usize,
+    deadline: Option<std::time::Instant>,
+    outcome: impl Fn(&RedisBuffer) -> Option<WriteOutcome>,
+) -> WriteOutcome {
+    let lock = REDIS_BUFFER.shard(shard);
+    let latch = unsafe { pg_sys::MyLatch } as usize;
+    let slot = {
+        let mut buffer = lock.exclusive();
+        let slot = buffer.waiters.iter().position(|waiter| *waiter == 0);
+        if let Some(slot) = slot {
+            buffer.waiters[slot] = latch;
+        }
+        slot
+    };
+    let outcome = loop {
+        if let Some(outcome) = outcome(&lock.share()) {
+            break outcome;
+        }
+        if unsafe { pg_sys::QueryCancelPending != 0 || pg_sys::ProcDiePending != 0 } {
+            break WriteOutcome::Interrupted;
+        }
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(std::time::Instant::now()),
+            None => MAX_WAIT_INTERVAL,
+        };
+        if remaining.is_zero() {
+            break WriteOutcome::TimedOut;
+        }
+        let wait = match slot {
+            Some(_) => remaining.min(MAX_WAIT_INTERVAL),
+            None => remaining.min(WAIT_POLL_INTERVAL),
+        };
+        let events = unsafe {
+            let events = pg_sys::WaitLatch(
+                pg_sys::MyLatch,
+                (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as i32,
+                wait.as_millis().max(1) as _,
+                pg_sys::PG_WAIT_EXTENSION,
+            );
+            pg_sys::ResetLatch(pg_sys::MyLatch);
+            events
+        };
+        if events & pg_sys::WL_POSTMASTER_DEATH as i32 != 0 {
+            break WriteOutcome::Interrupted;
+        }
+    };
+    if let Some(slot) = slot {
+        lock.exclusive().waiters[slot] = 0;
+    }
+    outcome
 }
 
 pub fn init_redis_buffer() {
     pg_shmem_init!(REDIS_BUFFER);
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_info_with_policy() {
+        use super::{Info, Operation};
+        use crate::gucs::WritePolicy;
+        let info = Info::new("user:1", "Ada")
+            .unwrap()
+            .with_policy(WritePolicy::Invalidate);
+        assert_eq!(Operation::Delete, info.operation);
+        assert_eq!("user:1", info.key_string());
+        assert_eq!("", info.value_string());
+        let selected = Info::selected("user:1", "Ada", 1)
+            .unwrap()
+            .with_policy(WritePolicy::Invalidate);
+        assert_eq!(Operation::Set, selected.operation);
+    }
+}
@@ -0,0 +1,537 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+use pgrx::{
+    debug1, is_a, list,
+    pg_sys::{self, Node, NodeTag, Oid},
+    PgBox, PgMemoryContexts, PgRelation,
+};
+
+use crate::client;
+use crate::gucs;
+use crate::prshmem::Info;
+use crate::select::slot_getattr;
+use crate::utils;
+use crate::xact;
+
+const SCAN_NAME: &CStr = c"PostgresRedisReadThrough";
+
+static mut SCAN_METHODS: pg_sys::CustomScanMethods = pg_sys::CustomScanMethods {
+    CustomName: SCAN_NAME.as_ptr(),
+    CreateCustomScanState: Some(create_scan_state),
+};
+
+static mut EXEC_METHODS: Option<pg_sys::CustomExecMethods> = None;
+
+/// Register the read-through custom scan.
+///
+/// # Safety
+///
+/// Must only be called once, from `_PG_init`.
+pub unsafe fn register() {
+    EXEC_METHODS = Some(pg_sys::CustomExecMethods {
+        CustomName: SCAN_NAME.as_ptr(),
+        BeginCustomScan: Some(begin_scan),
+        ExecCustomScan: Some(exec_scan),
+        EndCustomScan: Some(end_scan),
+        ReScanCustomScan: Some(rescan_scan),
+        ..Default::default()
+    });
+    pg_sys::RegisterCustomScanMethods(std::ptr::addr_of!(SCAN_METHODS));
+}
+
+/// Returns true if the query of `query_desc` runs the read-through custom scan. Its values
+/// come from redis, or are queued by the scan itself on a miss, so the SELECT capture of the
+/// hooks must skip it.
+pub fn is_read_through(query_desc: &PgBox<pg_sys::QueryDesc>) -> bool {
+    unsafe {
+        let stmt = query_desc.plannedstmt;
+        if stmt.is_null() || (*stmt).planTree.is_null() {
+            return false;
+        }
+        let plan = (*stmt).planTree;
+        is_a(plan.cast(), NodeTag::T_CustomScan)
+            && (*plan.cast::<pg_sys::CustomScan>()).methods == std::ptr::addr_of!(SCAN_METHODS)
+    }
+}
+
+/// Put the read-through custom scan on top of the plan of a point lookup on the tracked
+/// `table_name`, e.g. `SELECT value FROM table WHERE key = $1`. The original plan is kept as
+/// the child of the custom scan, which falls back to it on a miss.
+///
+/// Only a plain scan of the table qualifies, whose single qual compares the key column with
+/// a constant or a parameter and whose output columns are the key or the value column. The
+/// key column must have a unique index, since redis holds at most one value per key.
+///
+/// # Safety
+///
+/// `stmt` must be null or a valid plan returned by the planner.
+pub unsafe fn plan(
+    stmt: *mut pg_sys::PlannedStmt,
+    table_name: &str,
+    key_column: &str,
+    value_column: &str,
+    cursor_options: c_int,
+) {
+    if !gucs::PGD_READ_THROUGH.get() || stmt.is_null() {
+        return;
+    }
+    let stmt = &mut *stmt;
+    // A scrollable cursor needs backward scans, which the custom scan doesn't support.
+    if stmt.commandType != pg_sys::CmdType_CMD_SELECT
+        || stmt.hasModifyingCTE
+        || !stmt.rowMarks.is_null()
+        || cursor_options & pg_sys::CURSOR_OPT_SCROLL as c_int != 0
+        || stmt.planTree.is_null()
+        || !(*stmt.planTree).initPlan.is_null()
+    {
+        return;
+    }
+    let child = stmt.planTree;
+    let quals = if is_a(child.cast(), NodeTag::T_SeqScan) {
+        (*child).qual
+    } else if is_a(child.cast(), NodeTag::T_IndexScan) && (*child).qual.is_null() {
+        (*child.cast::<pg_sys::IndexScan>()).indexqualorig
+    } else if is_a(child.cast(), NodeTag::T_BitmapHeapScan) && (*child).qual.is_null() {
+        (*child.cast::<pg_sys::BitmapHeapScan>()).bitmapqualorig
+    } else {
+        return;
+    };
+    let qual = match list::List::<*mut core::ffi::c_void>::downcast_ptr(quals) {
+        Some(quals) if quals.len() == 1 => *quals.get(0).unwrap(),
+        _ => return,
+    };
+
+    let scanrelid = (*child.cast::<pg_sys::Scan>()).scanrelid;
+    let relid = (*pg_sys::rt_fetch(scanrelid, stmt.rtable)).relid;
+    if !utils::is_mapped_relation(relid, table_name) {
+        return;
+    }
+    let attnum = |column: &str| {
+        let column = CString::new(column).expect("column name contains a nul byte");
+        pg_sys::get_attnum(relid, column.as_ptr())
+    };
+    let key_attno = attnum(key_column);
+    let value_attno = attnum(value_column);
+    if key_attno == pg_sys::InvalidAttrNumber as i16 {
+        return;
+    }
+    let key_expr = match key_lookup(qual.cast(), scanrelid, key_attno) {
+        Some(key_expr) => key_expr,
+        None => return,
+    };
+
+    let targets = match list::List::<*mut core::ffi::c_void>::downcast_ptr((*child).targetlist) {
+        Some(targets) => targets,
+        None => return,
+    };
+    let is_output_column = |target: &*mut core::ffi::c_void| {
+        let target = (*target).cast::<pg_sys::TargetEntry>();
+        let var = (*target).expr.cast::<pg_sys::Var>();
+        !(*target).resjunk
+            && is_a(var.cast(), NodeTag::T_Var)
+            && i64::from((*var).varno) == i64::from(scanrelid)
+            && ((*var).varattno == key_attno || (*var).varattno == value_attno)
+    };
+    if !targets.iter().all(is_output_column) || !has_unique_index(relid, key_attno) {
+        return;
+    }
+
+    // The key of the row read on a miss is needed to populate redis, so the child reads it
+    // into an extra junk column. The custom scan projects the original columns.
+    let mut scan_targets = std::ptr::null_mut();
+    let mut output_targets = std::ptr::null_mut();
+    for (i, target) in targets.iter().enumerate() {
+        let target = (*target).cast::<pg_sys::TargetEntry>();
+        let expr = (*target).expr.cast::<Node>();
+        let var = pg_sys::makeVar(
+            pg_sys::INDEX_VAR,
+            i as i16 + 1,
+            pg_sys::exprType(expr),
+            pg_sys::exprTypmod(expr),
+            pg_sys::exprCollation(expr),
+            0,
+        );
+        output_targets = pg_sys::lappend(
+            output_targets,
+            pg_sys::makeTargetEntry(var.cast(), i as i16 + 1, (*target).resname, false).cast(),
+        );
+        scan_targets = pg_sys::lappend(scan_targets, pg_sys::copyObjectImpl(target.cast()));
+    }
+    let relation = PgRelation::open(relid);
+    let tuple_desc = relation.tuple_desc();
+    let key_attr = tuple_desc
+        .get(key_attno as usize - 1)
+        .expect("key column is missing from the table");
+    let key_var = pg_sys::makeVar(
+        scanrelid as _,
+        key_attno,
+        key_attr.atttypid,
+        key_attr.atttypmod,
+        key_attr.attcollation,
+        0,
+    );
+    scan_targets = pg_sys::lappend(
+        scan_targets,
+        pg_sys::makeTargetEntry(
+            key_var.cast(),
+            targets.len() as i16 + 1,
+            std::ptr::null_mut(),
+            true,
+        )
+        .cast(),
+    );
+    (*child).targetlist = scan_targets;
+
+    let cscan =
+        pg_sys::palloc0(std::mem::size_of::<pg_sys::CustomScan>()).cast::<pg_sys::CustomScan>();
+    let plan = &mut (*cscan).scan.plan;
+    plan.type_ = NodeTag::T_CustomScan;
+    plan.startup_cost = (*child).startup_cost;
+    plan.total_cost = (*child).total_cost;
+    plan.plan_rows = (*child).plan_rows;
+    plan.plan_width = (*child).plan_width;
+    plan.plan_node_id = (*child).plan_node_id;
+    plan.extParam = pg_sys::bms_copy((*child).extParam);
+    plan.allParam = pg_sys::bms_copy((*child).allParam);
+    plan.targetlist = output_targets;
+    (*cscan).custom_plans = pg_sys::lappend(std::ptr::null_mut(), child.cast());
+    (*cscan).custom_exprs = pg_sys::lappend(std::ptr::null_mut(), key_expr.cast());
+    (*cscan).custom_private = pg_sys::lappend(
+        pg_sys::lappend(std::ptr::null_mut(), int4_const(key_attno as i32).cast()),
+        int4_const(value_attno as i32).cast(),
+    );
+    (*cscan).custom_scan_tlist = pg_sys::copyObjectImpl(scan_targets.cast()).cast();
+    (*cscan).custom_relids = pg_sys::bms_make_singleton(scanrelid as c_int);
+    (*cscan).methods = std::ptr::addr_of!(SCAN_METHODS);
+    stmt.planTree = cscan.cast();
+}
+
+/// Returns the constant or parameter `qual` compares the `key_attno` column of the scanned
+/// relation with, if `qual` is an equality. The comparison must be between values of the
+/// same type, or text and varchar, so that the text of the constant is the text of the key
+/// of the matching row.
+unsafe fn key_lookup(qual: *mut Node, scanrelid: u32, key_attno: i16) -> Option<*mut Node> {
+    if !is_a(qual, NodeTag::T_OpExpr) {
+        return None;
+    }
+    let op_expr = qual.cast::<pg_sys::OpExpr>();
+    let args = list::List::<*mut core::ffi::c_void>::downcast_ptr((*op_expr).args)?;
+    if args.len() != 2 {
+        return None;
+    }
+    let strip = |node: *mut Node| {
+        if is_a(node, NodeTag::T_RelabelType) {
+            (*node.cast::<pg_sys::RelabelType>()).arg.cast::<Node>()
+        } else {
+            node
+        }
+    };
+    let mut var = strip((*args.get(0)?).cast());
+    let mut value = strip((*args.get(1)?).cast());
+    if is_a(value, NodeTag::T_Var) {
+        std::mem::swap(&mut var, &mut value);
+    }
+    if !is_a(var, NodeTag::T_Var) {
+        return None;
+    }
+    let var_ref = &*var.cast::<pg_sys::Var>();
+    if i64::from(var_ref.varno) != i64::from(scanrelid)
+        || var_ref.varattno != key_attno
+        || var_ref.varlevelsup != 0
+    {
+        return None;
+    }
+    let is_lookup_value = is_a(value, NodeTag::T_Const)
+        || (is_a(value, NodeTag::T_Param)
+            && (*value.cast::<pg_sys::Param>()).paramkind == pg_sys::ParamKind_PARAM_EXTERN);
+    let is_text = |typoid: Oid| typoid == pg_sys::TEXTOID || typoid == pg_sys::VARCHAROID;
+    let value_type = pg_sys::exprType(value);
+    let same_type =
+        value_type == var_ref.vartype || (is_text(value_type) && is_text(var_ref.vartype));
+    (is_lookup_value && same_type && pg_sys::op_mergejoinable((*op_expr).opno, var_ref.vartype))
+        .then_some(value)
+}
+
+/// Returns true if `relid` has a valid unique index on the `key_attno` column alone.
+unsafe fn has_unique_index(relid: Oid, key_attno: i16) -> bool {
+    let lockmode = pg_sys::AccessShareLock as pg_sys::LOCKMODE;
+    let relation = PgRelation::with_lock(relid, lockmode);
+    let is_unique = relation.indices(lockmode).any(|index| {
+        let form = &*index.rd_index;
+        form.indisunique
+            && form.indisvalid
+            && form.indnkeyatts == 1
+            && form.indkey.values.as_slice(1)[0] == key_attno
+            && pg_sys::RelationGetIndexPredicate(index.as_ptr()).is_null()
+    });
+    is_unique
+}
+
+unsafe fn int4_const(value: i32) -> *mut pg_sys::Const {
+    pg_sys::makeConst(
+        pg_sys::INT4OID,
+        -1,
+        pg_sys::InvalidOid,
+        4,
+        pg_sys::Datum::from(value),
+        false,
+        true,
+    )
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Redis wasn't asked yet.
+    Start,
+    /// Redis has the key, the row built from it is returned next.
+    Hit,
+    /// The rows come from the table.
+    Table,
+    Done,
+}
+
+/// The state of a read-through scan. It is allocated by Postgres, so every field must be
+/// valid when zeroed.
+#[repr(C)]
+struct ReadThroughState {
+    css: pg_sys::CustomScanState,
+    key_expr: *mut pg_sys::ExprState,
+    key_attno: i16,
+    value_attno: i16,
+    phase: Phase,
+    /// The redis key of the lookup, null until redis was asked.
+    key: *mut c_char,
+    rows: u64,
+    /// The key and value of the first row read from the table.
+    row_key: *mut c_char,
+    row_value: *mut c_char,
+}
+
+#[pgrx::pg_guard]
+unsafe extern "C" fn create_scan_state(_cscan: *mut pg_sys::CustomScan) -> *mut Node {
+    let state = pg_sys::palloc0(std::mem::size_of::<ReadThroughState>()).cast::<ReadThroughState>();
+    (*state).css.ss.ps.type_ = NodeTag::T_CustomScanState;
+    (*state).css.methods = (*std::ptr::addr_of!(EXEC_METHODS)).as_ref().unwrap();
+    state.cast()
+}
+
+#[pgrx::pg_guard]
+unsafe extern "C" fn begin_scan(
+    node: *mut pg_sys::CustomScanState,
+    estate: *mut pg_sys::EState,
+    eflags: c_int,
+) {
+    let state = &mut *node.cast::<ReadThroughState>();
+    let cscan = &*state.css.ss.ps.plan.cast::<pg_sys::CustomScan>();
+    let first = |nodes: *mut pg_sys::List| {
+        list::List::<*mut core::ffi::c_void>::downcast_ptr(nodes)
+            .and_then(|nodes| nodes.get(0).copied())
+            .expect("read-through scan is missing its child plan or key")
+    };
+    let child = pg_sys::ExecInitNode(first(cscan.custom_plans).cast(), estate, eflags);
+    state.css.custom_ps = pg_sys::lappend(std::ptr::null_mut(), child.cast());
+    state.key_expr = pg_sys::ExecInitExpr(first(cscan.custom_exprs).cast(), &mut state.css.ss.ps);
+    let private = list::List::<*mut core::ffi::c_void>::downcast_ptr(cscan.custom_private)
+        .expect("read-through scan without columns");
+    let attno = |i: usize| {
+        (*(*private.get(i).unwrap()).cast::<pg_sys::Const>())
+            .constvalue
+            .value() as i16
+    };
+    state.key_attno = attno(0);
+    state.value_attno = attno(1);
+}
+
+unsafe fn child_state(state: &ReadThroughState) -> *mut pg_sys::PlanState {
+    let children = list::List::<*mut core::ffi::c_void>::downcast_ptr(state.css.custom_ps)
+        .expect("read-through scan without a child plan");
+    (*children.get(0).unwrap()).cast()
+}
+
+#[pgrx::pg_guard]
+unsafe extern "C" fn exec_scan(node: *mut pg_sys::CustomScanState) -> *mut pg_sys::TupleTableSlot {
+    let state = &mut *node.cast::<ReadThroughState>();
+    if state.phase == Phase::Start {
+        state.phase = lookup(state);
+    }
+    match state.phase {
+        Phase::Hit => {
+            state.phase = Phase::Done;
+            project(state, state.css.ss.ss_ScanTupleSlot)
+        }
+        Phase::Table => {
+            let child = child_state(state);
+            let slot = (*child).ExecProcNode.unwrap()(child);
+            if slot.is_null() || (*slot).tts_flags & pg_sys::TTS_FLAG_EMPTY as u16 != 0 {
+                state.phase = Phase::Done;
+                populate(state);
+                return std::ptr::null_mut();
+            }
+            let scan_slot = state.css.ss.ss_ScanTupleSlot;
+            (*(*scan_slot).tts_ops).copyslot.unwrap()(scan_slot, slot);
+            state.rows += 1;
+            if state.rows == 1 {
+                remember_row(state, scan_slot);
+            }
+            project(state, scan_slot)
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Ask redis for the key of the lookup. Own uncommitted writes to the table aren't in redis
+/// yet, so a transaction that made them reads the table, as do lookups while redis recently
+/// failed to answer.
+unsafe fn lookup(state: &mut ReadThroughState) -> Phase {
+    if !gucs::PGD_READ_THROUGH.get() || xact::has_mapped_write() || !client::is_available() {
+        return Phase::Table;
+    }
+    let econtext = state.css.ss.ps.ps_ExprContext;
+    let mut is_null = false;
+    let key_expr = state.key_expr;
+    let datum = PgMemoryContexts::For((*econtext).ecxt_per_tuple_memory)
+        .switch_to(|_| (*key_expr).evalfunc.unwrap()(key_expr, econtext, &mut is_null));
+    if is_null {
+        return Phase::Table;
+    }
+    let key_text = utils::output_datum(datum, pg_sys::exprType((*key_expr).expr.cast()));
+    let key = format!("{}{key_text}", gucs::key_prefix());
+    state.key = PgMemoryContexts::CurrentMemoryContext.pstrdup(&key);
+
+    let value = match client::with_connection(|connection| {
+        redis::cmd("GET")
+            .arg(&key)
+            .query::<Option<String>>(connection)
+    }) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Phase::Table,
+        Err(err) => {
+            debug1!("postgres_redis: read-through lookup of {key} failed: {err}");
+            return Phase::Table;
+        }
+    };
+    if store_row(state, &key_text, &value) {
+        Phase::Hit
+    } else {
+        Phase::Table
+    }
+}
+
+/// Build the row of a hit in the scan slot. Every column is the key or the value column.
+unsafe fn store_row(state: &mut ReadThroughState, key: &str, value: &str) -> bool {
+    let slot = state.css.ss.ss_ScanTupleSlot;
+    (*(*slot).tts_ops).clear.unwrap()(slot);
+    let tuple_desc = &*(*slot).tts_tupleDescriptor;
+    let attrs = tuple_desc.attrs.as_slice(tuple_desc.natts as usize);
+    let targets = list::List::<*mut core::ffi::c_void>::downcast_ptr(
+        (*state.css.ss.ps.plan.cast::<pg_sys::CustomScan>()).custom_scan_tlist,
+    )
+    .expect("read-through scan without columns");
+    for (i, (attr, target)) in attrs.iter().zip(targets.iter()).enumerate() {
+        let var = (*(*target).cast::<pg_sys::TargetEntry>())
+            .expr
+            .cast::<pg_sys::Var>();
+        let text = if (*var).varattno == state.key_attno {
+            key
+        } else {
+            value
+        };
+        match utils::input_datum(text, attr.atttypid, attr.atttypmod) {
+            Some(datum) => *(*slot).tts_values.add(i) = datum,
+            None => return false,
+        }
+        *(*slot).tts_isnull.add(i) = false;
+    }
+    pg_sys::ExecStoreVirtualTuple(slot);
+    true
+}
+
+/// Keep the key and value of the first row read from the table, redis is populated with
+/// them if the lookup returns no other row.
+unsafe fn remember_row(state: &mut ReadThroughState, slot: *mut pg_sys::TupleTableSlot) {
+    let tuple_desc = &*(*slot).tts_tupleDescriptor;
+    let attrs = tuple_desc.attrs.as_slice(tuple_desc.natts as usize);
+    let targets = list::List::<*mut core::ffi::c_void>::downcast_ptr(
+        (*state.css.ss.ps.plan.cast::<pg_sys::CustomScan>()).custom_scan_tlist,
+    )
+    .expect("read-through scan without columns");
+    for (i, (attr, target)) in attrs.iter().zip(targets.iter()).enumerate() {
+        let var = (*(*target).cast::<pg_sys::TargetEntry>())
+            .expr
+            .cast::<pg_sys::Var>();
+        let text = match slot_getattr(slot, i + 1) {
+            Some(datum) => utils::output_datum(datum, attr.atttypid),
+            None => continue,
+        };
+        let text = PgMemoryContexts::CurrentMemoryContext.pstrdup(&text);
+        if (*var).varattno == state.key_attno {
+            state.row_key = text;
+        } else if (*var).varattno == state.value_attno {
+            state.row_value = text;
+        }
+    }
+}
+
+/// Queue the value read on a miss, like the value of any other SELECT on the table. It is
+/// versioned from the snapshot of the scan, and not queued if the snapshot may miss a commit.
+unsafe fn populate(state: &ReadThroughState) {
+    if state.rows != 1
+        || state.key.is_null()
+        || state.row_key.is_null()
+        || state.row_value.is_null()
+        || xact::has_mapped_write()
+    {
+        return;
+    }
+    let key = format!(
+        "{}{}",
+        gucs::key_prefix(),
+        CStr::from_ptr(state.row_key).to_string_lossy()
+    );
+    let value = CStr::from_ptr(state.row_value).to_string_lossy();
+    let snapshot = (*state.css.ss.ps.state).es_snapshot;
+    if let Some(info) =
+        utils::snapshot_version(snapshot).and_then(|version| Info::selected(&key, &value, version))
+    {
+        xact::push(info.with_relation(gucs::table_relid()));
+    }
+}
+
+/// Project the original output columns out of `slot`, a row of the scan tuple.
+unsafe fn project(
+    state: &mut ReadThroughState,
+    slot: *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+    let projection = state.css.ss.ps.ps_ProjInfo;
+    if projection.is_null() {
+        return slot;
+    }
+    let econtext = (*projection).pi_exprContext;
+    (*econtext).ecxt_scantuple = slot;
+    let projection_state = &mut (*projection).pi_state;
+    let result = projection_state.resultslot;
+    (*(*result).tts_ops).clear.unwrap()(result);
+    let mut is_null = false;
+    projection_state.evalfunc.unwrap()(projection_state, econtext, &mut is_null);
+    (*result).tts_flags &= !(pg_sys::TTS_FLAG_EMPTY as u16);
+    (*result).tts_nvalid = (*(*result).tts_tupleDescriptor).natts as i16;
+    result
+}
+
+#[pgrx::pg_guard]
+unsafe extern "C" fn rescan_scan(node: *mut pg_sys::CustomScanState) {
+    let state = &mut *node.cast::<ReadThroughState>();
+    state.phase = Phase::Start;
+    state.key = std::ptr::null_mut();
+    state.rows = 0;
+    state.row_key = std::ptr::null_mut();
+    state.row_value = std::ptr::null_mut();
+    pg_sys::ExecReScan(child_state(state));
+}
+
+#[pgrx::pg_guard]
+unsafe extern "C" fn end_scan(node: *mut pg_sys::CustomScanState) {
+    pg_sys::ExecEndNode(child_state(&*node.cast::<ReadThroughState>()));
+}
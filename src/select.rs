@@ -135,7 +135,7 @@ pub extern "C" fn destroy(receiver: *mut DestReceiver) {
 
 /// A Rust port of the same named Postgres function
 /// https://github.com/postgres/postgres/blob/422041542f313f23ca66cad26e9b2b99c4d1999a/src/include/executor/tuptable.h#L396
-unsafe fn slot_getattr(slot: *mut TupleTableSlot, attnum: usize) -> Option<Datum> {
+pub unsafe fn slot_getattr(slot: *mut TupleTableSlot, attnum: usize) -> Option<Datum> {
     let real_slot = &(*slot);
     if attnum as i16 > real_slot.tts_nvalid {
         slot_getsomeattrs_int(slot, attnum as i32);
@@ -149,3 +149,36 @@ unsafe fn slot_getattr(slot: *mut TupleTableSlot, attnum: usize) -> Option<Datum
     let datum = *real_slot.tts_values.offset(attnum as isize - 1);
     Some(datum)
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::tests::commit_in_worker;
+
+    #[pg_test]
+    fn test_select_missing_a_commit_is_not_cached() {
+        // The SELECT runs read only, with the snapshot of the test statement, which was taken
+        // before the worker committed the update.
+        commit_in_worker("UPDATE users SET first_name = 'Robert' WHERE id = 3");
+        let name = Spi::connect(|client| {
+            client
+                .select("SELECT first_name FROM users WHERE id = 3", None, None)?
+                .first()
+                .get_one::<String>()
+        });
+        assert_eq!(Ok(Some(String::from("Bob"))), name);
+        assert!(!crate::xact::pending_infos()
+            .iter()
+            .any(|info| info.from_select));
+
+        // The update reaches the buffer with the version of its commit.
+        let update = crate::prshmem::buffered_items()
+            .into_iter()
+            .find(|info| info.key_string() == "users:3")
+            .expect("the update wasn't captured");
+        assert_eq!("Robert", update.value_string());
+        assert!(update.version > 0);
+    }
+}
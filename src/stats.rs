@@ -0,0 +1,622 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use pgrx::{extension_sql, pg_shmem_init, prelude::*, shmem::*, PGRXSharedMemory, PgLwLock};
+
+use crate::gucs;
+use crate::hotkeys;
+use crate::prshmem::{self, Info, Operation};
+
+/// The longest last error kept, in bytes.
+const LAST_ERROR_SIZE: usize = 256;
+
+/// The most mapped tables counted separately, the changes of further tables are only part of
+/// the global counters.
+const MAX_MAPPINGS: usize = 64;
+
+/// The upper bounds, in microseconds, of the buckets of the redis write latency histogram. The
+/// last bucket counts the slower writes.
+pub const LATENCY_BUCKETS: [i64; 8] = [
+    1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+/// The counters of the changes captured from a mapped table.
+#[derive(Copy, Clone, Default)]
+pub struct MappingStats {
+    /// InvalidOid for the changes that don't come from a mapped table.
+    pub relid: pg_sys::Oid,
+    pub sets: i64,
+    pub deletes: i64,
+    pub truncates: i64,
+    pub selects: i64,
+    pub dropped: i64,
+    pub sent: i64,
+    pub failed: i64,
+    pub sent_bytes: i64,
+}
+
+/// The counters and the state of the sync pipeline at one point, see `snapshot`.
+#[derive(Copy, Clone)]
+pub struct Stats {
+    pub captured_sets: i64,
+    pub captured_deletes: i64,
+    pub captured_truncates: i64,
+    pub captured_selects: i64,
+    pub enqueued: i64,
+    pub coalesced: i64,
+    pub dropped: i64,
+    pub sent: i64,
+    pub failed: i64,
+    pub retries: i64,
+    pub last_error: [u8; LAST_ERROR_SIZE],
+    pub last_error_length: usize,
+    /// Zero until the first error.
+    pub last_error_at: pg_sys::TimestampTz,
+    /// Zero until the first write.
+    pub last_flush_at: pg_sys::TimestampTz,
+    pub stats_reset: pg_sys::TimestampTz,
+    pub mappings: [MappingStats; MAX_MAPPINGS],
+    pub mappings_length: usize,
+    /// The rounds of the background worker that wrote to redis, by latency.
+    pub latency_buckets: [i64; LATENCY_BUCKETS.len() + 1],
+    pub latency_sum: i64,
+    /// Whether each background worker has a working redis connection.
+    pub redis_connected: [bool; prshmem::MAX_WORKERS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let state = WorkerStats::default();
+        Stats {
+            captured_sets: 0,
+            captured_deletes: 0,
+            captured_truncates: 0,
+            captured_selects: 0,
+            enqueued: 0,
+            coalesced: 0,
+            dropped: 0,
+            sent: 0,
+            failed: 0,
+            retries: 0,
+            last_error: state.last_error,
+            last_error_length: state.last_error_length,
+            last_error_at: state.last_error_at,
+            last_flush_at: state.last_flush_at,
+            stats_reset: state.stats_reset,
+            mappings: [MappingStats::default(); MAX_MAPPINGS],
+            mappings_length: 0,
+            latency_buckets: state.latency_buckets,
+            latency_sum: state.latency_sum,
+            redis_connected: state.redis_connected,
+        }
+    }
+}
+
+/// The state of the sync pipeline that isn't a counter of changes, updated by the background
+/// workers once per round.
+#[derive(Copy, Clone)]
+struct WorkerStats {
+    last_error: [u8; LAST_ERROR_SIZE],
+    last_error_length: usize,
+    last_error_at: pg_sys::TimestampTz,
+    last_flush_at: pg_sys::TimestampTz,
+    stats_reset: pg_sys::TimestampTz,
+    latency_buckets: [i64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: i64,
+    redis_connected: [bool; prshmem::MAX_WORKERS],
+}
+
+impl Default for WorkerStats {
+    fn default() -> Self {
+        WorkerStats {
+            last_error: [0; LAST_ERROR_SIZE],
+            last_error_length: 0,
+            last_error_at: 0,
+            last_flush_at: 0,
+            stats_reset: 0,
+            latency_buckets: [0; LATENCY_BUCKETS.len() + 1],
+            latency_sum: 0,
+            redis_connected: [false; prshmem::MAX_WORKERS],
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for WorkerStats {}
+
+static STATS: PgLwLock<WorkerStats> = PgLwLock::new();
+
+/// The counters of the changes of a mapped table. `slot` holds the oid of the table plus one,
+/// zero while the slot is free.
+#[derive(Default)]
+struct MappingCounters {
+    slot: AtomicU64,
+    sets: AtomicI64,
+    deletes: AtomicI64,
+    truncates: AtomicI64,
+    selects: AtomicI64,
+    dropped: AtomicI64,
+    sent: AtomicI64,
+    failed: AtomicI64,
+    sent_bytes: AtomicI64,
+}
+
+struct MappingSlots([MappingCounters; MAX_MAPPINGS]);
+
+impl Default for MappingSlots {
+    fn default() -> Self {
+        MappingSlots(std::array::from_fn(|_| MappingCounters::default()))
+    }
+}
+
+/// The counters of the sync pipeline. Every commit that captured changes counts them, so they
+/// are atomics that the backends add to without a lock, and `snapshot` sums them up.
+#[derive(Default)]
+struct Counters {
+    captured_sets: AtomicI64,
+    captured_deletes: AtomicI64,
+    captured_truncates: AtomicI64,
+    captured_selects: AtomicI64,
+    enqueued: AtomicI64,
+    coalesced: AtomicI64,
+    dropped: AtomicI64,
+    sent: AtomicI64,
+    failed: AtomicI64,
+    retries: AtomicI64,
+    mappings: MappingSlots,
+}
+
+impl Counters {
+    /// The counters of the mapped table `relid`, None if too many tables are counted already.
+    /// A free slot is taken with a compare-and-swap, the slots are therefore taken in order
+    /// and a table never gets two.
+    fn mapping(&self, relid: pg_sys::Oid) -> Option<&MappingCounters> {
+        let slot = u64::from(relid.as_u32()) + 1;
+        self.mappings.0.iter().find(|mapping| {
+            match mapping
+                .slot
+                .compare_exchange(0, slot, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => true,
+                Err(taken) => taken == slot,
+            }
+        })
+    }
+}
+
+/// The `Counters` in shared memory.
+struct SharedCounters(OnceLock<&'static Counters>);
+
+impl PgSharedMemoryInitialization for SharedCounters {
+    fn pg_init(&'static self) {
+        unsafe { pg_sys::RequestAddinShmemSpace(std::mem::size_of::<Counters>()) };
+    }
+
+    fn shmem_init(&'static self) {
+        unsafe {
+            // `AddinShmemInitLock`, a macro the bindings don't have.
+            let lock = std::ptr::addr_of_mut!((*pg_sys::MainLWLockArray.add(21)).lock);
+            pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            let mut found = false;
+            let counters = pg_sys::ShmemInitStruct(
+                c"postgres_redis counters".as_ptr(),
+                std::mem::size_of::<Counters>(),
+                &mut found,
+            )
+            .cast::<Counters>();
+            if !found {
+                std::ptr::write(counters, Counters::default());
+            }
+            pg_sys::LWLockRelease(lock);
+            let _ = self.0.set(&*counters);
+        }
+    }
+}
+
+static COUNTERS: SharedCounters = SharedCounters(OnceLock::new());
+
+fn counters() -> &'static Counters {
+    COUNTERS
+        .0
+        .get()
+        .expect("postgres_redis: the stats counters are not in shared memory")
+}
+
+fn add(counter: &AtomicI64, count: i64) {
+    counter.fetch_add(count, Ordering::Relaxed);
+}
+
+fn load(counter: &AtomicI64) -> i64 {
+    counter.load(Ordering::Relaxed)
+}
+
+pub fn init() {
+    pg_shmem_init!(STATS);
+    pg_shmem_init!(COUNTERS);
+}
+
+fn count_captured(items: &[Info]) {
+    let counters = counters();
+    for item in items {
+        add(
+            match item.operation {
+                Operation::Set if item.from_select => &counters.captured_selects,
+                Operation::Set => &counters.captured_sets,
+                Operation::Delete => &counters.captured_deletes,
+                Operation::Truncate => &counters.captured_truncates,
+            },
+            1,
+        );
+        if let Some(mapping) = counters.mapping(item.relid) {
+            add(
+                match item.operation {
+                    Operation::Set if item.from_select => &mapping.selects,
+                    Operation::Set => &mapping.sets,
+                    Operation::Delete => &mapping.deletes,
+                    Operation::Truncate => &mapping.truncates,
+                },
+                1,
+            );
+        }
+    }
+    hotkeys::record(items);
+}
+
+/// Count the changes of a committed transaction, the ones at the positions `dropped` didn't fit
+/// in the shared memory buffer.
+pub fn record_commit(items: &[Info], dropped: &[usize]) {
+    let counters = counters();
+    count_captured(items);
+    add(&counters.enqueued, (items.len() - dropped.len()) as i64);
+    add(&counters.dropped, dropped.len() as i64);
+    for item in dropped.iter().map(|position| &items[*position]) {
+        if let Some(mapping) = counters.mapping(item.relid) {
+            add(&mapping.dropped, 1);
+        }
+    }
+}
+
+/// Count the changes decoded from the logical replication slot.
+pub fn record_decoded(items: &[Info]) {
+    count_captured(items);
+}
+
+/// Count the changes the background worker merged into a newer change of the same key.
+pub fn record_coalesced(count: usize) {
+    add(&counters().coalesced, count as i64);
+}
+
+/// Count a pipeline sent again, e.g. after redis lost the compare-and-set script.
+pub fn record_retry() {
+    add(&counters().retries, 1);
+}
+
+/// Count the outcome of writing `items` to redis.
+pub fn record_write<E: std::fmt::Display>(items: &[Info], result: &Result<(), E>) {
+    let now = unsafe { pg_sys::GetCurrentTimestamp() };
+    let counters = counters();
+    for item in items {
+        if let Some(mapping) = counters.mapping(item.relid) {
+            match result {
+                Ok(()) => {
+                    add(&mapping.sent, 1);
+                    add(
+                        &mapping.sent_bytes,
+                        (item.key_length as i64) + (item.value_length as i64),
+                    );
+                }
+                Err(_) => add(&mapping.failed, 1),
+            }
+        }
+    }
+    match result {
+        Ok(()) => {
+            add(&counters.sent, items.len() as i64);
+            STATS.exclusive().last_flush_at = now;
+        }
+        Err(err) => {
+            add(&counters.failed, items.len() as i64);
+            let message = err.to_string();
+            // Cut on a character boundary, the message is read back as UTF-8.
+            let mut length = message.len().min(LAST_ERROR_SIZE);
+            while !message.is_char_boundary(length) {
+                length -= 1;
+            }
+            let mut stats = STATS.exclusive();
+            stats.last_error[..length].copy_from_slice(&message.as_bytes()[..length]);
+            stats.last_error_length = length;
+            stats.last_error_at = now;
+        }
+    }
+}
+
+/// Count a round of the background worker that took `elapsed` to write to redis.
+pub fn record_latency(elapsed: Duration) {
+    let micros = elapsed.as_micros().min(i64::MAX as u128) as i64;
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| micros <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+    let mut stats = STATS.exclusive();
+    stats.latency_buckets[bucket] += 1;
+    stats.latency_sum = stats.latency_sum.saturating_add(micros);
+}
+
+/// Record whether the background worker of `shard` can reach redis.
+pub fn record_connection(shard: usize, connected: bool) {
+    STATS.exclusive().redis_connected[shard] = connected;
+}
+
+/// Returns true while every background worker has a working redis connection.
+pub fn is_redis_connected() -> bool {
+    let workers = gucs::PGD_WORKERS.get() as usize;
+    STATS.share().redis_connected[..workers]
+        .iter()
+        .all(|connected| *connected)
+}
+
+/// Returns the counters and the state of the sync pipeline. The counters are read one by one
+/// while the backends keep adding to them, so they may be a few changes apart.
+pub fn snapshot() -> Stats {
+    let state = *STATS.share();
+    let counters = counters();
+    let mut mappings = [MappingStats::default(); MAX_MAPPINGS];
+    let mut mappings_length = 0;
+    for mapping in counters.mappings.0.iter() {
+        let slot = mapping.slot.load(Ordering::Relaxed);
+        if slot == 0 {
+            continue;
+        }
+        mappings[mappings_length] = MappingStats {
+            relid: pg_sys::Oid::from((slot - 1) as u32),
+            sets: load(&mapping.sets),
+            deletes: load(&mapping.deletes),
+            truncates: load(&mapping.truncates),
+            selects: load(&mapping.selects),
+            dropped: load(&mapping.dropped),
+            sent: load(&mapping.sent),
+            failed: load(&mapping.failed),
+            sent_bytes: load(&mapping.sent_bytes),
+        };
+        mappings_length += 1;
+    }
+    Stats {
+        captured_sets: load(&counters.captured_sets),
+        captured_deletes: load(&counters.captured_deletes),
+        captured_truncates: load(&counters.captured_truncates),
+        captured_selects: load(&counters.captured_selects),
+        enqueued: load(&counters.enqueued),
+        coalesced: load(&counters.coalesced),
+        dropped: load(&counters.dropped),
+        sent: load(&counters.sent),
+        failed: load(&counters.failed),
+        retries: load(&counters.retries),
+        last_error: state.last_error,
+        last_error_length: state.last_error_length,
+        last_error_at: state.last_error_at,
+        last_flush_at: state.last_flush_at,
+        stats_reset: state.stats_reset,
+        mappings,
+        mappings_length,
+        latency_buckets: state.latency_buckets,
+        latency_sum: state.latency_sum,
+        redis_connected: state.redis_connected,
+    }
+}
+
+fn timestamp(timestamp: pg_sys::TimestampTz) -> Option<TimestampWithTimeZone> {
+    if timestamp == 0 {
+        return None;
+    }
+    TimestampWithTimeZone::try_from(timestamp).ok()
+}
+
+/// Returns the counters of the sync pipeline and the number of changes waiting in the shared
+/// memory buffer. Read it through the `postgres_redis.stats` view.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."get_stats"()
+    RETURNS TABLE (
+        "captured_sets" bigint,
+        "captured_deletes" bigint,
+        "captured_truncates" bigint,
+        "captured_selects" bigint,
+        "enqueued" bigint,
+        "coalesced" bigint,
+        "dropped" bigint,
+        "sent" bigint,
+        "failed" bigint,
+        "retries" bigint,
+        "last_error" text,
+        "last_error_at" timestamptz,
+        "last_flush_at" timestamptz,
+        "queue_depth" int,
+        "stats_reset" timestamptz
+    )
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'get_stats_wrapper';
+"#)]
+#[allow(clippy::type_complexity)]
+fn get_stats() -> TableIterator<
+    'static,
+    (
+        name!(captured_sets, i64),
+        name!(captured_deletes, i64),
+        name!(captured_truncates, i64),
+        name!(captured_selects, i64),
+        name!(enqueued, i64),
+        name!(coalesced, i64),
+        name!(dropped, i64),
+        name!(sent, i64),
+        name!(failed, i64),
+        name!(retries, i64),
+        name!(last_error, Option<String>),
+        name!(last_error_at, Option<TimestampWithTimeZone>),
+        name!(last_flush_at, Option<TimestampWithTimeZone>),
+        name!(queue_depth, i32),
+        name!(stats_reset, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let stats = snapshot();
+    let last_error = (stats.last_error_at != 0).then(|| {
+        String::from_utf8_lossy(&stats.last_error[..stats.last_error_length]).into_owned()
+    });
+    TableIterator::once((
+        stats.captured_sets,
+        stats.captured_deletes,
+        stats.captured_truncates,
+        stats.captured_selects,
+        stats.enqueued,
+        stats.coalesced,
+        stats.dropped,
+        stats.sent,
+        stats.failed,
+        stats.retries,
+        last_error,
+        timestamp(stats.last_error_at),
+        timestamp(stats.last_flush_at),
+        prshmem::data_size(),
+        timestamp(stats.stats_reset),
+    ))
+}
+
+/// Set every counter of the sync pipeline back to zero.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."reset_stats"() RETURNS void
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'reset_stats_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."reset_stats"() FROM PUBLIC;
+"#)]
+fn reset_stats() {
+    let counters = counters();
+    for counter in [
+        &counters.captured_sets,
+        &counters.captured_deletes,
+        &counters.captured_truncates,
+        &counters.captured_selects,
+        &counters.enqueued,
+        &counters.coalesced,
+        &counters.dropped,
+        &counters.sent,
+        &counters.failed,
+        &counters.retries,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    // A change counted meanwhile may be lost, or land in the slot of another table.
+    for mapping in counters.mappings.0.iter() {
+        for counter in [
+            &mapping.sets,
+            &mapping.deletes,
+            &mapping.truncates,
+            &mapping.selects,
+            &mapping.dropped,
+            &mapping.sent,
+            &mapping.failed,
+            &mapping.sent_bytes,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        mapping.slot.store(0, Ordering::Relaxed);
+    }
+    let mut stats = STATS.exclusive();
+    // The connection state isn't a counter, it outlives the reset.
+    *stats = WorkerStats {
+        redis_connected: stats.redis_connected,
+        ..WorkerStats::default()
+    };
+    stats.stats_reset = unsafe { pg_sys::GetCurrentTimestamp() };
+    hotkeys::reset();
+}
+
+/// Returns the counters of every mapped table with captured changes since the last reset. The
+/// changes that don't come from a mapped table, e.g. from `redis_enqueue`, prepared
+/// transactions or the replication slot, are counted in a row without a mapping.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."mapping_stats"()
+    RETURNS TABLE (
+        "mapping" regclass,
+        "sets" bigint,
+        "deletes" bigint,
+        "truncates" bigint,
+        "selects" bigint,
+        "dropped" bigint,
+        "sent" bigint,
+        "failed" bigint,
+        "sent_bytes" bigint
+    )
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'mapping_stats_wrapper';
+"#)]
+#[allow(clippy::type_complexity)]
+fn mapping_stats() -> TableIterator<
+    'static,
+    (
+        name!(mapping, Option<pg_sys::Oid>),
+        name!(sets, i64),
+        name!(deletes, i64),
+        name!(truncates, i64),
+        name!(selects, i64),
+        name!(dropped, i64),
+        name!(sent, i64),
+        name!(failed, i64),
+        name!(sent_bytes, i64),
+    ),
+> {
+    let stats = snapshot();
+    let rows: Vec<_> = stats.mappings[..stats.mappings_length]
+        .iter()
+        .map(|mapping| {
+            (
+                (mapping.relid != pg_sys::InvalidOid).then_some(mapping.relid),
+                mapping.sets,
+                mapping.deletes,
+                mapping.truncates,
+                mapping.selects,
+                mapping.dropped,
+                mapping.sent,
+                mapping.failed,
+                mapping.sent_bytes,
+            )
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW postgres_redis.stats AS SELECT * FROM postgres_redis.get_stats();
+"#,
+    name = "stats_view",
+    requires = [get_stats]
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::tests::commit_in_worker;
+
+    #[pg_test]
+    fn test_commit_is_counted() {
+        let relid = Spi::get_one::<pg_sys::Oid>("SELECT 'users'::regclass")
+            .unwrap()
+            .unwrap();
+        let users = |stats: &super::Stats| {
+            stats.mappings[..stats.mappings_length]
+                .iter()
+                .find(|mapping| mapping.relid == relid)
+                .copied()
+                .unwrap_or_default()
+        };
+        let before = super::snapshot();
+        commit_in_worker("INSERT INTO users (id, first_name) VALUES (2201, 'Counted')");
+        commit_in_worker("DELETE FROM users WHERE id = 2201");
+        let after = super::snapshot();
+        assert_eq!(before.captured_sets + 1, after.captured_sets);
+        assert_eq!(before.captured_deletes + 1, after.captured_deletes);
+        assert_eq!(before.enqueued + 2, after.enqueued);
+        assert_eq!(users(&before).sets + 1, users(&after).sets);
+        assert_eq!(users(&before).deletes + 1, users(&after).deletes);
+    }
+}
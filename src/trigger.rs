@@ -0,0 +1,569 @@
+use pgrx::{prelude::*, spi::SpiHeapTupleData, PgTupleDesc};
+
+use crate::gucs::{self, SyncMode, WritePolicy};
+use crate::prshmem::{self, Info};
+use crate::utils;
+use crate::xact;
+
+/// Every table tracked with `track_table` gets a row trigger with this name.
+const TRIGGER_NAME: &str = "postgres_redis_capture";
+
+/// The statement trigger that turns a TRUNCATE of a tracked table into a key prefix delete.
+const TRUNCATE_TRIGGER_NAME: &str = "postgres_redis_capture_truncate";
+
+/// How the rows of a table tracked with `track_table` map to redis keys and values. The
+/// mapping is passed to the trigger as its arguments: the key prefix, the options as
+/// comma separated `name=value` pairs, the number of key columns, the key columns and then the
+/// value columns.
+pub struct Mapping {
+    pub key_prefix: String,
+    pub key_columns: Vec<String>,
+    pub value_columns: Vec<String>,
+    pub sync_mode: SyncMode,
+    pub write_policy: WritePolicy,
+}
+
+impl Mapping {
+    pub fn from_args(args: &[String]) -> Option<Mapping> {
+        let (key_prefix, rest) = args.split_first()?;
+        let (options, rest) = rest.split_first()?;
+        let (key_count, columns) = rest.split_first()?;
+        let key_count: usize = key_count.parse().ok()?;
+        if key_count == 0 || key_count >= columns.len() {
+            return None;
+        }
+        let mut mapping = Mapping {
+            key_prefix: key_prefix.clone(),
+            key_columns: columns[..key_count].to_vec(),
+            value_columns: columns[key_count..].to_vec(),
+            sync_mode: SyncMode::default(),
+            write_policy: WritePolicy::default(),
+        };
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=')? {
+                ("sync_mode", value) => mapping.sync_mode = SyncMode::from_name(value)?,
+                ("write_policy", value) => mapping.write_policy = WritePolicy::from_name(value)?,
+                _ => return None,
+            }
+        }
+        Some(mapping)
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            self.key_prefix.clone(),
+            format!(
+                "sync_mode={},write_policy={}",
+                self.sync_mode.name(),
+                self.write_policy.name()
+            ),
+            self.key_columns.len().to_string(),
+        ];
+        args.extend(self.key_columns.iter().cloned());
+        args.extend(self.value_columns.iter().cloned());
+        args
+    }
+
+    /// Queue `info`, a change of the mapped table, on the current transaction.
+    fn push(&self, info: Info) {
+        let info = info.with_policy(self.write_policy);
+        match self.sync_mode {
+            SyncMode::Synchronous => xact::push_synchronous(info),
+            SyncMode::Async => xact::push(info),
+        }
+    }
+
+    /// The redis key of `tuple`: the key prefix followed by the key columns joined with `:`.
+    /// Returns None if any key column is NULL.
+    ///
+    /// # Safety
+    ///
+    /// `tuple` must be a valid heap tuple described by `tuple_desc`.
+    pub unsafe fn key(
+        &self,
+        tuple: *mut pg_sys::HeapTupleData,
+        tuple_desc: &PgTupleDesc,
+    ) -> Option<String> {
+        let mut parts = vec![];
+        for column in self.key_columns.iter() {
+            let (datum, attr) = utils::heap_tuple_attr(tuple, tuple_desc, column)?;
+            parts.push(utils::output_datum(datum, attr.atttypid));
+        }
+        Some(self.key_from_texts(&parts))
+    }
+
+    /// The redis key made of the texts of the key columns.
+    pub fn key_from_texts(&self, parts: &[String]) -> String {
+        format!("{}{}", self.key_prefix, parts.join(":"))
+    }
+
+    /// The redis value of `tuple`. A single value column is stored as its text, several are
+    /// stored as a JSON object of the column names and texts. Returns None if a single value
+    /// column is NULL.
+    ///
+    /// # Safety
+    ///
+    /// `tuple` must be a valid heap tuple described by `tuple_desc`.
+    pub unsafe fn value(
+        &self,
+        tuple: *mut pg_sys::HeapTupleData,
+        tuple_desc: &PgTupleDesc,
+    ) -> Option<String> {
+        let texts: Vec<Option<String>> = self
+            .value_columns
+            .iter()
+            .map(|column| {
+                utils::heap_tuple_attr(tuple, tuple_desc, column)
+                    .map(|(datum, attr)| utils::output_datum(datum, attr.atttypid))
+            })
+            .collect();
+        self.value_from_texts(&texts)
+    }
+
+    /// The redis value made of the texts of the value columns, NULL columns being None.
+    pub fn value_from_texts(&self, texts: &[Option<String>]) -> Option<String> {
+        if let [text] = texts {
+            return text.clone();
+        }
+        let fields: Vec<String> = self
+            .value_columns
+            .iter()
+            .zip(texts)
+            .map(|(column, text)| {
+                let value = text
+                    .as_ref()
+                    .map(|value| utils::json_string(value))
+                    .unwrap_or_else(|| String::from("null"));
+                format!("{}:{value}", utils::json_string(column))
+            })
+            .collect();
+        Some(format!("{{{}}}", fields.join(",")))
+    }
+
+    /// The key and value columns cast to text, the select list of the queries reading the rows
+    /// of the mapping.
+    pub fn select_list(&self) -> String {
+        self.key_columns
+            .iter()
+            .chain(self.value_columns.iter())
+            .map(|column| format!("{}::text", utils::quote_identifier(column)))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// The texts of the key and value columns of a `row` read with `select_list`.
+    pub fn row_texts(&self, row: &SpiHeapTupleData) -> (Vec<String>, Vec<Option<String>>) {
+        let key_count = self.key_columns.len();
+        let texts: Vec<Option<String>> = (1..=key_count + self.value_columns.len())
+            .map(|i| row.get::<String>(i).unwrap_or(None))
+            .collect();
+        let parts = texts[..key_count]
+            .iter()
+            .map(|part| part.clone().unwrap_or_default())
+            .collect();
+        (parts, texts[key_count..].to_vec())
+    }
+
+    /// The types of the key columns of `relid`, named `table_name`, as `format_type` names.
+    pub fn key_types(&self, relid: pg_sys::Oid, table_name: &str) -> Vec<String> {
+        let key_types = Spi::get_one_with_args::<Vec<String>>(
+            "SELECT array_agg(format_type(a.atttypid, a.atttypmod) ORDER BY k.n)
+             FROM unnest($2::text[]) WITH ORDINALITY k(c, n)
+             JOIN pg_attribute a ON a.attrelid = $1 AND a.attname = k.c
+                 AND a.attnum > 0 AND NOT a.attisdropped",
+            vec![
+                (PgBuiltInOids::REGCLASSOID.oid(), relid.into_datum()),
+                (
+                    PgBuiltInOids::TEXTARRAYOID.oid(),
+                    self.key_columns.clone().into_datum(),
+                ),
+            ],
+        )
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+        if key_types.len() != self.key_columns.len() {
+            error!(
+                "postgres_redis: the key columns of {table_name} changed, run track_table again"
+            );
+        }
+        key_types
+    }
+
+    /// The mapping of `relid`: the one stored by `track_table`, or the one of the
+    /// `postgres_redis.table` parameters. Returns None if the table isn't mapped.
+    pub fn lookup(relid: pg_sys::Oid) -> Option<Mapping> {
+        let tracked = Spi::connect(|client| {
+            let row = client
+                .select(
+                    "SELECT key_columns, value_columns, key_prefix, sync_mode, write_policy
+                     FROM postgres_redis.mappings WHERE relid = $1",
+                    Some(1),
+                    Some(vec![(PgBuiltInOids::REGCLASSOID.oid(), relid.into_datum())]),
+                )
+                .ok()?
+                .next()?;
+            Some(Mapping {
+                key_columns: row.get::<Vec<String>>(1).ok()??,
+                value_columns: row.get::<Vec<String>>(2).ok()??,
+                key_prefix: row.get::<String>(3).ok()??,
+                sync_mode: SyncMode::from_name(&row.get::<String>(4).ok()??)?,
+                write_policy: WritePolicy::from_name(&row.get::<String>(5).ok()??)?,
+            })
+        });
+        if tracked.is_some() {
+            return tracked;
+        }
+        let table = gucs::PGD_REDIS_TABLE.get()?;
+        let key_column = gucs::PGD_KEY_COLUMN.get()?;
+        let value_column = gucs::PGD_VALUE_COLUMN.get()?;
+        if !unsafe { utils::is_mapped_relation(relid, &table.to_string_lossy()) } {
+            return None;
+        }
+        Some(Mapping {
+            key_prefix: gucs::key_prefix(),
+            key_columns: vec![key_column.to_string_lossy().into_owned()],
+            value_columns: vec![value_column.to_string_lossy().into_owned()],
+            sync_mode: gucs::PGD_SYNC_MODE.get(),
+            write_policy: gucs::PGD_WRITE_POLICY.get(),
+        })
+    }
+}
+
+/// The row trigger installed by `track_table`. The changes are queued on the current
+/// transaction like the changes captured by the executor hooks, so they only reach redis once
+/// the transaction commits. An UPDATE that changes the key removes the old key, and a TRUNCATE
+/// removes every key starting with the key prefix.
+#[pg_trigger(sql = r#"
+CREATE FUNCTION postgres_redis."capture"()
+    RETURNS TRIGGER
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'capture_wrapper';
+"#)]
+fn capture<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    let mapping = match Mapping::from_args(&trigger.extra_args()?) {
+        Some(mapping) => mapping,
+        None => error!("postgres_redis.capture has invalid arguments, run track_table again"),
+    };
+    let data = trigger.trigger_data();
+    let relid = unsafe { (*data.tg_relation).rd_id };
+    // A change whose key is too long for redis is left out rather than failing the statement.
+    let push = |key: &str, info: Option<Info>| match info {
+        Some(info) => mapping.push(info.with_relation(relid)),
+        None => prshmem::warn_key_too_long(key),
+    };
+    if trigger.event().fired_by_truncate() {
+        push(&mapping.key_prefix, Info::truncate(&mapping.key_prefix));
+        return Ok(None);
+    }
+    unsafe {
+        let tuple_desc = PgTupleDesc::from_pg_unchecked((*data.tg_relation).rd_att);
+        let event = trigger.event();
+        if event.fired_by_delete() {
+            if let Some(key) = mapping.key(data.tg_trigtuple, &tuple_desc) {
+                push(&key, Info::delete(&key));
+            }
+            return Ok(None);
+        }
+
+        let new_tuple = if event.fired_by_update() {
+            let old_key = mapping.key(data.tg_trigtuple, &tuple_desc);
+            let new_key = mapping.key(data.tg_newtuple, &tuple_desc);
+            if let Some(old_key) = old_key.filter(|old_key| Some(old_key) != new_key.as_ref()) {
+                push(&old_key, Info::delete(&old_key));
+            }
+            data.tg_newtuple
+        } else {
+            data.tg_trigtuple
+        };
+        if let Some(key) = mapping.key(new_tuple, &tuple_desc) {
+            match mapping.value(new_tuple, &tuple_desc) {
+                Some(value) => push(&key, Info::write(&key, &value)),
+                // The key may hold the value of the row before the update.
+                None => push(&key, Info::delete(&key)),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Track `table_name` with a row trigger and a TRUNCATE trigger. Calling it again for the same
+/// table replaces the mapping. With `sync_mode = 'synchronous'`, the commits writing to the
+/// table wait for the changes to reach redis. With `write_policy = 'invalidate'`, the changes
+/// delete their keys instead of setting them.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."track_table"(
+    "table_name" regclass,
+    "key_columns" text[],
+    "value_columns" text[],
+    "key_prefix" text,
+    "sync_mode" text DEFAULT 'async',
+    "write_policy" text DEFAULT 'set'
+) RETURNS void
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'track_table_wrapper';
+"#)]
+fn track_table(
+    table_name: pg_sys::Oid,
+    key_columns: Vec<String>,
+    value_columns: Vec<String>,
+    key_prefix: &str,
+    sync_mode: &str,
+    write_policy: &str,
+) {
+    if key_columns.is_empty() || value_columns.is_empty() {
+        error!("track_table needs at least one key column and one value column");
+    }
+    let sync_mode = match SyncMode::from_name(sync_mode) {
+        Some(sync_mode) => sync_mode,
+        None => error!("track_table: unknown sync_mode {sync_mode}, use async or synchronous"),
+    };
+    let write_policy = match WritePolicy::from_name(write_policy) {
+        Some(write_policy) => write_policy,
+        None => error!("track_table: unknown write_policy {write_policy}, use set or invalidate"),
+    };
+    check_key_prefix(table_name, key_prefix);
+    let table_oid = (PgBuiltInOids::REGCLASSOID.oid(), table_name.into_datum());
+    let columns: Vec<String> = key_columns
+        .iter()
+        .chain(value_columns.iter())
+        .cloned()
+        .collect();
+    let missing = Spi::get_one_with_args::<String>(
+        "SELECT string_agg(c, ', ') FROM unnest($2::text[]) c
+         WHERE NOT EXISTS (SELECT 1 FROM pg_attribute
+                           WHERE attrelid = $1 AND attname = c AND attnum > 0 AND NOT attisdropped)",
+        vec![table_oid, (PgBuiltInOids::TEXTARRAYOID.oid(), columns.into_datum())],
+    )
+    .unwrap_or(None);
+    if let Some(missing) = missing {
+        error!("track_table: unknown column(s) {missing}");
+    }
+
+    let mapping = Mapping {
+        key_prefix: key_prefix.to_string(),
+        key_columns,
+        value_columns,
+        sync_mode,
+        write_policy,
+    };
+    Spi::run_with_args(
+        "INSERT INTO postgres_redis.mappings
+             (relid, key_columns, value_columns, key_prefix, sync_mode, write_policy)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (relid) DO UPDATE SET key_columns = excluded.key_columns,
+             value_columns = excluded.value_columns, key_prefix = excluded.key_prefix,
+             sync_mode = excluded.sync_mode, write_policy = excluded.write_policy",
+        Some(vec![
+            table_oid,
+            (
+                PgBuiltInOids::TEXTARRAYOID.oid(),
+                mapping.key_columns.clone().into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTARRAYOID.oid(),
+                mapping.value_columns.clone().into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                mapping.key_prefix.clone().into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                mapping.sync_mode.name().into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                mapping.write_policy.name().into_datum(),
+            ),
+        ]),
+    )
+    .expect("failed to store the table mapping");
+
+    drop_trigger(table_name);
+    for (name, events) in [
+        (
+            TRIGGER_NAME,
+            "INSERT OR UPDATE OR DELETE ON %s FOR EACH ROW",
+        ),
+        (TRUNCATE_TRIGGER_NAME, "TRUNCATE ON %s FOR EACH STATEMENT"),
+    ] {
+        let create_trigger = Spi::get_one_with_args::<String>(
+            &format!(
+                "SELECT format('CREATE TRIGGER {name} AFTER {events} \
+                 EXECUTE FUNCTION postgres_redis.capture(%s)', $1, \
+                 (SELECT string_agg(quote_literal(a), ', ') FROM unnest($2::text[]) a))"
+            ),
+            vec![
+                table_oid,
+                (
+                    PgBuiltInOids::TEXTARRAYOID.oid(),
+                    mapping.to_args().into_datum(),
+                ),
+            ],
+        )
+        .expect("failed to build the trigger definition")
+        .expect("failed to build the trigger definition");
+        Spi::run(&create_trigger).expect("failed to create the capture trigger");
+    }
+}
+
+/// Reject a `key_prefix` for `table_name` that doesn't end with the `:` separator, or that
+/// starts the prefix of another mapping or starts with one, the `postgres_redis.table`
+/// parameters included. A TRUNCATE deletes every key under the prefix of its table, which
+/// would delete the keys of the other mapping as well.
+fn check_key_prefix(table_name: pg_sys::Oid, key_prefix: &str) {
+    if !key_prefix.ends_with(':') {
+        error!("track_table: the key prefix must end with the ':' separator, e.g. 'user:'");
+    }
+    if let Some(overlapping) = overlapping_mappings(table_name, key_prefix) {
+        error!("track_table: the key prefix {key_prefix} overlaps the one of {overlapping}");
+    }
+    if let Some(table) = gucs::PGD_REDIS_TABLE.get() {
+        let table_prefix = gucs::key_prefix();
+        if table_prefix.starts_with(key_prefix) || key_prefix.starts_with(&table_prefix) {
+            error!(
+                "track_table: the key prefix {key_prefix} overlaps the one of {} ({table_prefix}), set postgres_redis.key_prefix",
+                table.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Returns the tables tracked with `track_table`, other than `relid`, whose key prefix starts
+/// `key_prefix` or starts with it, with their prefixes.
+pub fn overlapping_mappings(relid: pg_sys::Oid, key_prefix: &str) -> Option<String> {
+    Spi::get_one_with_args::<String>(
+        "SELECT string_agg(format('%s (%s)', relid, key_prefix), ', ')
+         FROM postgres_redis.mappings
+         WHERE relid <> $1 AND (starts_with($2, key_prefix) OR starts_with(key_prefix, $2))",
+        vec![
+            (PgBuiltInOids::REGCLASSOID.oid(), relid.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), key_prefix.into_datum()),
+        ],
+    )
+    .unwrap_or(None)
+}
+
+/// Stop tracking `table_name`, removing its triggers and mapping.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."untrack_table"("table_name" regclass) RETURNS void
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'untrack_table_wrapper';
+"#)]
+fn untrack_table(table_name: pg_sys::Oid) {
+    drop_trigger(table_name);
+    Spi::run_with_args(
+        "DELETE FROM postgres_redis.mappings WHERE relid = $1",
+        Some(vec![(
+            PgBuiltInOids::REGCLASSOID.oid(),
+            table_name.into_datum(),
+        )]),
+    )
+    .expect("failed to remove the table mapping");
+}
+
+fn drop_trigger(table_name: pg_sys::Oid) {
+    for name in [TRIGGER_NAME, TRUNCATE_TRIGGER_NAME] {
+        let drop_trigger = Spi::get_one_with_args::<String>(
+            &format!("SELECT format('DROP TRIGGER IF EXISTS {name} ON %s', $1)"),
+            vec![(PgBuiltInOids::REGCLASSOID.oid(), table_name.into_datum())],
+        )
+        .expect("failed to build the trigger definition")
+        .expect("failed to build the trigger definition");
+        Spi::run(&drop_trigger).expect("failed to drop the capture trigger");
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_track_table() {
+        use crate::prshmem::Operation;
+
+        Spi::run("CREATE TABLE tracked (id int PRIMARY KEY, name text)").unwrap();
+        Spi::run(
+            "SELECT postgres_redis.track_table('tracked', ARRAY['id'], ARRAY['name'], 'user:')",
+        )
+        .unwrap();
+        let triggers = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_trigger WHERE tgrelid = 'tracked'::regclass AND tgname = 'postgres_redis_capture'",
+        );
+        assert_eq!(Ok(Some(1)), triggers);
+        let last_change = || {
+            let info = *crate::xact::pending_infos().last().unwrap();
+            (info.operation, info.key_string(), info.value_string())
+        };
+        let change =
+            |operation, value: &str| (operation, String::from("user:1"), value.to_string());
+
+        Spi::run("INSERT INTO tracked VALUES (1, 'Ada')").unwrap();
+        assert_eq!(change(Operation::Set, "Ada"), last_change());
+        // A NULL value leaves nothing to set, the old value is deleted.
+        Spi::run("UPDATE tracked SET name = NULL WHERE id = 1").unwrap();
+        assert_eq!(change(Operation::Delete, ""), last_change());
+        assert_eq!(2, crate::xact::pending_infos().len());
+        Spi::run("DELETE FROM tracked WHERE id = 1").unwrap();
+        assert_eq!(change(Operation::Delete, ""), last_change());
+        assert_eq!(3, crate::xact::pending_infos().len());
+
+        Spi::run("SELECT postgres_redis.untrack_table('tracked')").unwrap();
+        let mappings = Spi::get_one::<i64>("SELECT count(*) FROM postgres_redis.mappings");
+        assert_eq!(Ok(Some(0)), mappings);
+    }
+
+    #[pg_test(error = "track_table: unknown sync_mode eventual, use async or synchronous")]
+    fn test_track_table_rejects_sync_mode() {
+        Spi::run("CREATE TABLE tracked (id int PRIMARY KEY, name text)").unwrap();
+        Spi::run(
+            "SELECT postgres_redis.track_table('tracked', ARRAY['id'], ARRAY['name'], '', 'eventual')",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "track_table: the key prefix must end with the ':' separator, e.g. 'user:'")]
+    fn test_track_table_rejects_empty_prefix() {
+        Spi::run("CREATE TABLE tracked (id int PRIMARY KEY, name text)").unwrap();
+        Spi::run("SELECT postgres_redis.track_table('tracked', ARRAY['id'], ARRAY['name'], '')")
+            .unwrap();
+    }
+
+    #[pg_test(
+        error = "track_table: the key prefix users:tracked: overlaps the one of users (users:), set postgres_redis.key_prefix"
+    )]
+    fn test_track_table_rejects_overlapping_prefix() {
+        Spi::run("CREATE TABLE tracked (id int PRIMARY KEY, name text)").unwrap();
+        Spi::run(
+            "SELECT postgres_redis.track_table('tracked', ARRAY['id'], ARRAY['name'], 'users:tracked:')",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_mapping_args() {
+        use super::{Mapping, SyncMode};
+        use crate::gucs::WritePolicy;
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+
+        let options = "sync_mode=synchronous,write_policy=invalidate";
+        let mapping = Mapping::from_args(&args(&["user:", options, "1", "id", "name"])).unwrap();
+        assert_eq!(SyncMode::Synchronous, mapping.sync_mode);
+        assert_eq!(WritePolicy::Invalidate, mapping.write_policy);
+        assert_eq!(
+            args(&["user:", options, "1", "id", "name"]),
+            mapping.to_args()
+        );
+
+        assert!(
+            Mapping::from_args(&args(&["user:", "sync_mode=eventual", "1", "id", "name"]))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use pgrx::{prelude::*, warning};
+
+use crate::prshmem::{self, Info, Operation};
+use crate::xact;
+
+/// Changes of prepared transactions are stored in this table as part of the prepared
+/// transaction itself. The rows only become visible if the transaction is committed with
+/// `COMMIT PREPARED`, and they vanish with it on `ROLLBACK PREPARED`. Both survive a restart.
+const PREPARED_CHANGES_TABLE: &str = "postgres_redis.prepared_changes";
+
+/// Returns the owner of the prepared changes table, None if the extension isn't installed in
+/// this database.
+fn table_owner() -> Option<pg_sys::Oid> {
+    let query = format!(
+        "SELECT relowner FROM pg_catalog.pg_class WHERE oid = pg_catalog.to_regclass('{PREPARED_CHANGES_TABLE}')"
+    );
+    Spi::get_one::<pg_sys::Oid>(&query).unwrap_or(None)
+}
+
+/// Run `f` as the `owner` of the prepared changes table. Any role may prepare a transaction
+/// that wrote to a tracked table, but only the extension can read and write the table.
+fn as_owner<R>(owner: pg_sys::Oid, f: impl FnOnce() -> R) -> R {
+    let mut user = pg_sys::InvalidOid;
+    let mut context = 0;
+    unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut user, &mut context);
+        pg_sys::SetUserIdAndSecContext(
+            owner,
+            context
+                | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32
+                | pg_sys::SECURITY_RESTRICTED_OPERATION as i32,
+        );
+    }
+    // An error aborts the transaction, which restores the user itself.
+    let result = f();
+    unsafe { pg_sys::SetUserIdAndSecContext(user, context) };
+    result
+}
+
+/// Move the pending changes of the current transaction into the prepared changes table under
+/// `gid`. This runs while `PREPARE TRANSACTION` is processed, so the rows are part of the
+/// transaction that is about to be prepared.
+pub fn persist_prepared(gid: &str) {
+    let changes = xact::take_pending();
+    if changes.is_empty() {
+        return;
+    }
+    let Some(owner) = table_owner() else {
+        warning!(
+            "postgres_redis extension is not installed in this database, discarding {} change(s) of prepared transaction {gid}",
+            changes.len()
+        );
+        return;
+    };
+
+    let query = format!(
+        "INSERT INTO {PREPARED_CHANGES_TABLE} (gid, position, operation, key, value, from_select, version, relid, synchronous) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+    );
+    as_owner(owner, || {
+        Spi::connect(|mut client| {
+            for (position, change) in changes.iter().enumerate() {
+                let info = &change.info;
+                let value = match info.operation {
+                    Operation::Set => Some(info.value_string()),
+                    Operation::Delete | Operation::Truncate => None,
+                };
+                let args = vec![
+                    (PgBuiltInOids::TEXTOID.oid(), gid.into_datum()),
+                    (PgBuiltInOids::INT4OID.oid(), (position as i32).into_datum()),
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        info.operation.name().into_datum(),
+                    ),
+                    (PgBuiltInOids::TEXTOID.oid(), info.key_string().into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), value.into_datum()),
+                    (PgBuiltInOids::BOOLOID.oid(), info.from_select.into_datum()),
+                    // A WAL position always fits, the bits are read back unchanged.
+                    (
+                        PgBuiltInOids::INT8OID.oid(),
+                        (info.version as i64).into_datum(),
+                    ),
+                    (PgBuiltInOids::OIDOID.oid(), info.relid.into_datum()),
+                    (
+                        PgBuiltInOids::BOOLOID.oid(),
+                        change.synchronous.into_datum(),
+                    ),
+                ];
+                client
+                    .update(&query, None, Some(args))
+                    .expect("failed to persist the changes of a prepared transaction");
+            }
+        })
+    });
+}
+
+/// Queue the changes stored for the prepared transaction `gid` on the current transaction.
+/// This runs right after `COMMIT PREPARED` succeeded, so the changes are sent to redis when
+/// the `COMMIT PREPARED` command itself commits.
+pub fn release_prepared(gid: &str) {
+    let Some(owner) = table_owner() else {
+        return;
+    };
+
+    let query = format!(
+        "DELETE FROM {PREPARED_CHANGES_TABLE} WHERE gid = $1 RETURNING position, operation, key, value, from_select, version, relid, synchronous"
+    );
+    let mut changes = as_owner(owner, || {
+        Spi::connect(|mut client| {
+            let rows = client
+                .update(
+                    &query,
+                    None,
+                    Some(vec![(PgBuiltInOids::TEXTOID.oid(), gid.into_datum())]),
+                )
+                .expect("failed to read the changes of a prepared transaction");
+            let mut changes = vec![];
+            for row in rows {
+                let position: i32 = row.get(1).unwrap_or(None).unwrap_or_default();
+                let operation: Option<String> = row.get(2).unwrap_or(None);
+                let key: Option<String> = row.get(3).unwrap_or(None);
+                let value: Option<String> = row.get(4).unwrap_or(None);
+                let from_select: bool = row.get(5).unwrap_or(None).unwrap_or_default();
+                let version: i64 = row.get(6).unwrap_or(None).unwrap_or_default();
+                let relid: pg_sys::Oid = row.get(7).unwrap_or(None).unwrap_or(pg_sys::InvalidOid);
+                let synchronous: bool = row.get(8).unwrap_or(None).unwrap_or_default();
+                let (Some(operation), Some(key)) = (operation, key) else {
+                    continue;
+                };
+                let info = match Operation::from_name(&operation) {
+                    Some(Operation::Set) => Info::write(&key, &value.unwrap_or_default()),
+                    Some(Operation::Delete) => Info::delete(&key),
+                    Some(Operation::Truncate) => Info::truncate(&key),
+                    None => continue,
+                };
+                let Some(mut info) = info else {
+                    prshmem::warn_key_too_long(&key);
+                    continue;
+                };
+                // A SELECT value keeps the version it was read at, a write gets the one of the
+                // `COMMIT PREPARED` record when it is flushed.
+                info.from_select = from_select;
+                info.version = version as u64;
+                changes.push((position, info.with_relation(relid), synchronous));
+            }
+            changes
+        })
+    });
+
+    changes.sort_by_key(|(position, ..)| *position);
+    for (_, info, synchronous) in changes {
+        match synchronous {
+            true => xact::push_synchronous(info),
+            false => xact::push(info),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::prshmem::{self, Info, Operation};
+    use crate::tests::run_session_in_worker;
+    use crate::xact;
+
+    fn prepared_count(gid: &str) -> i64 {
+        // Not read only, so the rows committed by the test workers are seen.
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT count(*) FROM postgres_redis.prepared_changes WHERE gid = $1",
+                    None,
+                    Some(vec![(PgBuiltInOids::TEXTOID.oid(), gid.into_datum())]),
+                )?
+                .first()
+                .get_one::<i64>()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn buffered_keys() -> Vec<String> {
+        prshmem::buffered_items()
+            .iter()
+            .map(|info| info.key_string())
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_commit_prepared() {
+        // The row is gone once the prepared transaction commits, so the table is unchanged.
+        run_session_in_worker(
+            "BEGIN;\
+             INSERT INTO users (id, first_name) VALUES (2001, 'Prepared');\
+             DELETE FROM users WHERE id = 2001;\
+             PREPARE TRANSACTION 'postgres_redis_commit'",
+        );
+        assert!(!buffered_keys().contains(&String::from("users:2001")));
+        assert_eq!(2, prepared_count("postgres_redis_commit"));
+
+        run_session_in_worker("COMMIT PREPARED 'postgres_redis_commit'");
+        assert_eq!(0, prepared_count("postgres_redis_commit"));
+        let changes: Vec<Info> = prshmem::buffered_items()
+            .into_iter()
+            .filter(|info| info.key_string() == "users:2001")
+            .collect();
+        assert_eq!(2, changes.len());
+        assert_eq!(Operation::Set, changes[0].operation);
+        assert_eq!("Prepared", changes[0].value_string());
+        assert_eq!(Operation::Delete, changes[1].operation);
+        assert!(changes[0].version > 0);
+    }
+
+    #[pg_test]
+    fn test_prepare_as_a_regular_role() {
+        // The role can't read or write the prepared changes table itself.
+        run_session_in_worker(
+            "CREATE ROLE postgres_redis_writer;\
+             GRANT SELECT, INSERT, DELETE ON users TO postgres_redis_writer;\
+             SET ROLE postgres_redis_writer;\
+             BEGIN;\
+             INSERT INTO users (id, first_name) VALUES (2003, 'Prepared');\
+             DELETE FROM users WHERE id = 2003;\
+             PREPARE TRANSACTION 'postgres_redis_role';\
+             COMMIT PREPARED 'postgres_redis_role';\
+             RESET ROLE;\
+             REVOKE ALL ON users FROM postgres_redis_writer;\
+             DROP ROLE postgres_redis_writer",
+        );
+        assert_eq!(0, prepared_count("postgres_redis_role"));
+        let changes: Vec<Info> = prshmem::buffered_items()
+            .into_iter()
+            .filter(|info| info.key_string() == "users:2003")
+            .collect();
+        assert_eq!(2, changes.len());
+        assert_eq!(Operation::Set, changes[0].operation);
+        assert_eq!(Operation::Delete, changes[1].operation);
+    }
+
+    #[pg_test]
+    fn test_rollback_prepared() {
+        run_session_in_worker(
+            "BEGIN;\
+             INSERT INTO users (id, first_name) VALUES (2002, 'Prepared');\
+             PREPARE TRANSACTION 'postgres_redis_rollback'",
+        );
+        assert_eq!(1, prepared_count("postgres_redis_rollback"));
+
+        run_session_in_worker("ROLLBACK PREPARED 'postgres_redis_rollback'");
+        assert_eq!(0, prepared_count("postgres_redis_rollback"));
+        assert!(!buffered_keys().contains(&String::from("users:2002")));
+    }
+
+    // The flags of the changes are checked on the steps `PREPARE TRANSACTION` and
+    // `COMMIT PREPARED` run, since the changes of the workers are only seen once flushed.
+    #[pg_test]
+    fn test_prepared_changes_keep_their_flags() {
+        Spi::run("UPDATE users SET first_name = 'Ada' WHERE id = 1").unwrap();
+        xact::push(Info::selected("2", "Usman", 42).unwrap());
+        xact::push_synchronous(Info::write("user:1", "Ada").unwrap());
+        super::persist_prepared("tx1");
+        assert!(xact::pending_infos().is_empty());
+        assert_eq!(3, prepared_count("tx1"));
+
+        super::release_prepared("tx1");
+        assert_eq!(0, prepared_count("tx1"));
+        let changes = xact::take_pending();
+        assert_eq!(3, changes.len());
+
+        let write = &changes[0];
+        assert_eq!(Operation::Set, write.info.operation);
+        assert_eq!("users:1", write.info.key_string());
+        assert_eq!("Ada", write.info.value_string());
+        assert!(!write.info.from_select);
+        assert_eq!(crate::gucs::table_relid(), write.info.relid);
+        assert!(!write.synchronous);
+
+        let selected = &changes[1];
+        assert!(selected.info.from_select);
+        assert_eq!(42, selected.info.version);
+
+        let synchronous = &changes[2];
+        assert!(synchronous.synchronous);
+        assert_eq!("user:1", synchronous.info.key_string());
+    }
+}
@@ -1,67 +1,806 @@
 use pgrx::{
-    pg_sys::{self, getTypeOutputInfo, Oid, OidOutputFunctionCall},
+    is_a, list,
+    pg_sys::{self, Oid},
     prelude::*,
-    PgTupleDesc,
 };
-use std::ffi::CStr;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
 
-#[derive(Debug)]
-pub struct UpdateDestReceiver {
-    pub value: Option<String>,
-    pub column: String,
+use crate::gucs;
+use crate::prshmem::{self, Info, Operation};
+use crate::select::slot_getattr;
+use crate::utils;
+use crate::xact;
+
+/// The names of the entries the planner adds to the RETURNING list of a write to the tracked
+/// table, and of the old key it adds to the rows of an UPDATE of the key column.
+const KEY_ENTRY: &str = "postgres_redis_key";
+const VALUE_ENTRY: &str = "postgres_redis_value";
+const OLD_KEY_ENTRY: &str = "postgres_redis_old_key";
+
+/// Add the key and value columns of the tracked `table_name` to the RETURNING list of
+/// `parse` if it writes to the table, and return true if the statement had no RETURNING list
+/// of its own. The rows of a write are captured from its RETURNING list, see `capture_rows`.
+/// The columns are added as resjunk entries to a RETURNING list of the statement, which
+/// `capture_rows` filters out again. Without one they become the RETURNING list and the
+/// caller marks the plan as returning nothing, so the rows never reach the client.
+///
+/// An UPDATE of the key column only returns the new key of a row, so the old key is added to
+/// the rows the UPDATE reads as a resjunk entry too. A MERGE has no RETURNING list, only the
+/// old key is added to the rows it reads, see `capture_merge_action`.
+///
+/// # Safety
+///
+/// `parse` must be a valid INSERT, UPDATE, DELETE or MERGE query.
+pub unsafe fn add_returning(
+    parse: &mut pg_sys::Query,
+    table_name: &str,
+    key_column: &str,
+    value_column: &str,
+) -> bool {
+    if parse.resultRelation <= 0 {
+        return false;
+    }
+    let relid = match relation_id(parse.rtable, parse.resultRelation) {
+        Some(relid) => relid,
+        None => return false,
+    };
+    if !utils::is_mapped_relation(relid, table_name) {
+        return false;
+    }
+    let (Some(key), Some(value)) = (
+        column_var(relid, parse.resultRelation, key_column),
+        column_var(relid, parse.resultRelation, value_column),
+    ) else {
+        return false;
+    };
+    let merge = is_merge_query(parse);
+    let own_returning = !parse.returningList.is_null();
+    if !merge {
+        let mut returning = parse.returningList;
+        for (var, column, entry) in [
+            (key, key_column, KEY_ENTRY),
+            (value, value_column, VALUE_ENTRY),
+        ] {
+            let resno = list_length(returning) + 1;
+            let name = if own_returning { entry } else { column };
+            returning = pg_sys::lappend(returning, target_entry(var, resno, name, own_returning));
+        }
+        parse.returningList = returning;
+    }
+
+    let targetlist = list::List::<*mut core::ffi::c_void>::downcast_ptr(parse.targetList);
+    let assigns_key = merge
+        || parse.commandType == pg_sys::CmdType_CMD_UPDATE
+            && targetlist.iter().flat_map(|list| list.iter()).any(|entry| {
+                let entry = &*(*entry).cast::<pg_sys::TargetEntry>();
+                !entry.resjunk && entry.resno == (*key).varattno
+            });
+    if assigns_key {
+        let resno = list_length(parse.targetList) + 1;
+        let old_key = pg_sys::copyObjectImpl(key.cast()).cast::<pg_sys::Var>();
+        parse.targetList = pg_sys::lappend(
+            parse.targetList,
+            target_entry(old_key, resno, OLD_KEY_ENTRY, true),
+        );
+    }
+    !own_returning && !merge
+}
+
+/// Returns true if `parse` is a MERGE.
+pub fn is_merge_query(parse: &pg_sys::Query) -> bool {
+    #[cfg(any(feature = "pg15", feature = "pg16"))]
+    if parse.commandType == pg_sys::CmdType_CMD_MERGE {
+        return true;
+    }
+    let _ = parse;
+    false
+}
+
+/// Add the key and value columns to the RETURNING lists of the data-modifying WITH queries
+/// of `parse` that write to the tracked `table_name`, see [`add_returning`].
+///
+/// # Safety
+///
+/// `parse` must be a valid query.
+pub unsafe fn add_cte_returning(
+    parse: &mut pg_sys::Query,
+    table_name: &str,
+    key_column: &str,
+    value_column: &str,
+) {
+    if !parse.hasModifyingCTE {
+        return;
+    }
+    let ctes = list::List::<*mut core::ffi::c_void>::downcast_ptr(parse.cteList);
+    for cte in ctes.iter().flat_map(|list| list.iter()) {
+        let query = (*(*cte).cast::<pg_sys::CommonTableExpr>()).ctequery;
+        if query.is_null() || !is_a(query, pg_sys::NodeTag::T_Query) {
+            continue;
+        }
+        let query = &mut *query.cast::<pg_sys::Query>();
+        add_cte_returning(query, table_name, key_column, value_column);
+        if [
+            pg_sys::CmdType_CMD_INSERT,
+            pg_sys::CmdType_CMD_UPDATE,
+            pg_sys::CmdType_CMD_DELETE,
+        ]
+        .contains(&query.commandType)
+        {
+            // Nothing reads the rows of a WITH query without a RETURNING list.
+            add_returning(query, table_name, key_column, value_column);
+        }
+    }
+}
+
+fn list_length(list: *mut pg_sys::List) -> pg_sys::AttrNumber {
+    if list.is_null() {
+        0
+    } else {
+        unsafe { (*list).length as pg_sys::AttrNumber }
+    }
+}
+
+/// A Var of `column` of the relation `relid` at `rti` in the range table.
+unsafe fn column_var(relid: Oid, rti: i32, column: &str) -> Option<*mut pg_sys::Var> {
+    let name = CString::new(column).expect("column name contains a null byte");
+    let attnum = pg_sys::get_attnum(relid, name.as_ptr());
+    if attnum == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return None;
+    }
+    let mut typid = pg_sys::InvalidOid;
+    let mut typmod = -1;
+    let mut collid = pg_sys::InvalidOid;
+    pg_sys::get_atttypetypmodcoll(relid, attnum, &mut typid, &mut typmod, &mut collid);
+    Some(pg_sys::makeVar(rti as _, attnum, typid, typmod, collid, 0))
 }
 
-impl UpdateDestReceiver {
-    pub fn update_values_column(&mut self, column: &str) {
-        self.value = None;
-        self.column = String::from(column);
+unsafe fn target_entry(
+    var: *mut pg_sys::Var,
+    resno: pg_sys::AttrNumber,
+    name: &str,
+    resjunk: bool,
+) -> *mut core::ffi::c_void {
+    let name = CString::new(name).expect("column name contains a null byte");
+    pg_sys::makeTargetEntry(var.cast(), resno, pg_sys::pstrdup(name.as_ptr()), resjunk).cast()
+}
+
+/// Where the rows written by a statement to the tracked table are found: every row of the
+/// RETURNING list queues a change of its key, the column at position `key`. An inserted or
+/// updated row sets the value at position `value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    pub key: usize,
+    pub value: usize,
+}
+
+/// Returns how to capture the rows the ModifyTable `plan` writes to the tracked
+/// `table_name`, or None if it doesn't write to it. `rtable` is the range table of the
+/// statement.
+unsafe fn write_capture(
+    plan: *mut pg_sys::Plan,
+    rtable: *mut pg_sys::List,
+    key_column: &str,
+    value_column: &str,
+) -> Option<Capture> {
+    let mut key = None;
+    let mut value = None;
+    let targetlist = list::List::<*mut core::ffi::c_void>::downcast_ptr((*plan).targetlist);
+    for (position, entry) in targetlist.iter().flat_map(|list| list.iter()).enumerate() {
+        let expr = (*(*entry).cast::<pg_sys::TargetEntry>()).expr;
+        if expr.is_null() || !is_a(expr.cast(), pg_sys::NodeTag::T_Var) {
+            continue;
+        }
+        let var = &*expr.cast::<pg_sys::Var>();
+        let column = match relation_id(rtable, var.varno as i32) {
+            Some(relid) => attribute_name(relid, var.varattno),
+            None => continue,
+        };
+        match column.as_deref() {
+            Some(column) if column == key_column => key = key.or(Some(position + 1)),
+            Some(column) if column == value_column => value = value.or(Some(position + 1)),
+            _ => {}
+        }
     }
+    Some(Capture {
+        key: key?,
+        value: value?,
+    })
 }
 
-pub fn handle_update(
+/// Returns the position of the old key in the rows the ModifyTable `plan` reads, if it
+/// changes the key column of the rows it writes: the entry added by `add_returning` for an
+/// UPDATE, or the proposed key of an `INSERT ... ON CONFLICT DO UPDATE` that assigns the key
+/// column. The latter is only the key of the updated row if the key column is the conflict
+/// target.
+unsafe fn old_key_position(
+    modify: &pg_sys::ModifyTable,
+    rtable: *mut pg_sys::List,
+    key_column: &str,
+) -> Option<usize> {
+    let subplan = modify.plan.lefttree;
+    if subplan.is_null() {
+        return None;
+    }
+    let name = CString::new(OLD_KEY_ENTRY).unwrap();
+    let position = pg_sys::ExecFindJunkAttributeInTlist((*subplan).targetlist, name.as_ptr());
+    if position != pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        return Some(position as usize);
+    }
+    if modify.onConflictAction != pg_sys::OnConflictAction_ONCONFLICT_UPDATE {
+        return None;
+    }
+    let rti = list::List::<i32>::downcast_ptr(modify.resultRelations)?
+        .get(0)
+        .copied()?;
+    let relid = relation_id(rtable, rti)?;
+    let name = CString::new(key_column).expect("column name contains a null byte");
+    let attnum = pg_sys::get_attnum(relid, name.as_ptr());
+    let on_conflict_set = list::List::<*mut core::ffi::c_void>::downcast_ptr(modify.onConflictSet);
+    let assigns_key = on_conflict_set
+        .iter()
+        .flat_map(|list| list.iter())
+        .any(|entry| {
+            let entry = &*(*entry).cast::<pg_sys::TargetEntry>();
+            !entry.resjunk && entry.resno == attnum
+        });
+    if !assigns_key {
+        return None;
+    }
+    let arbiters = list::List::<Oid>::downcast_ptr(modify.arbiterIndexes);
+    let on_key = arbiters.iter().flat_map(|list| list.iter()).all(|index| {
+        let index = pgrx::PgRelation::open(*index);
+        let form = &*index.rd_index;
+        form.indnkeyatts == 1 && form.indkey.values.as_slice(1)[0] == attnum
+    });
+    if !on_key {
+        error!("postgres_redis: ON CONFLICT DO UPDATE can only change the key column of the tracked table if it is the conflict target");
+    }
+    // The rows of an INSERT hold every column of the table in order.
+    Some(attnum as usize)
+}
+
+/// The oid of the relation at `rti` in `rtable`, None for a special varno.
+unsafe fn relation_id(rtable: *mut pg_sys::List, rti: i32) -> Option<Oid> {
+    if rti <= 0 {
+        return None;
+    }
+    let rtable = list::List::<*mut core::ffi::c_void>::downcast_ptr(rtable)?;
+    let rte = *rtable.get(rti as usize - 1)?;
+    Some((*rte.cast::<pg_sys::RangeTblEntry>()).relid)
+}
+
+unsafe fn attribute_name(relid: Oid, attnum: pg_sys::AttrNumber) -> Option<String> {
+    let name = pg_sys::get_attname(relid, attnum, true);
+    if name.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+}
+
+/// A ModifyTable node whose returned rows are captured, with its own function to run it.
+struct Capturing {
+    query_desc: *mut pg_sys::QueryDesc,
+    node: *mut pg_sys::PlanState,
+    exec: pg_sys::ExecProcNodeMtd,
+    capture: Option<Capture>,
+    operation: Operation,
+    /// Removes the entries added by `add_returning` from the rows sent to the client.
+    junk_filter: *mut pg_sys::JunkFilter,
+    /// The node the ModifyTable node reads its rows from, with its own function to run it
+    /// and the position of the old key in its rows, if the statement changes keys.
+    subplan: *mut pg_sys::PlanState,
+    subplan_exec: pg_sys::ExecProcNodeMtd,
+    old_key_position: usize,
+    /// The old key of the row the ModifyTable node read last.
+    old_key: Option<String>,
+    /// The old keys of the rows whose key changed, and the keys the statement wrote. An old
+    /// key is only deleted once the statement is done, if no row took it over.
+    moved: Vec<String>,
+    written: HashSet<String>,
+    /// The actions of a MERGE node, which returns no rows.
+    merge: Option<Merge>,
+}
+
+/// The rows a MERGE node inserted, updated and deleted so far, and the positions of the key
+/// and value columns in the relations it writes to.
+struct Merge {
+    key_column: String,
+    value_column: String,
+    counts: [f64; 3],
+    columns: Vec<(Oid, usize, usize)>,
+}
+
+/// The nodes capturing their rows. A statement run by a trigger of another one adds its own
+/// nodes while the outer one still runs. Entries of statements that failed are only cleared
+/// at the end of the transaction, a node allocated at the same address later on comes after
+/// them.
+static mut CAPTURING: Vec<Capturing> = Vec::new();
+
+// A backend is single threaded, so there is never more than one user of the static.
+fn capturing() -> &'static mut Vec<Capturing> {
+    unsafe { &mut *std::ptr::addr_of_mut!(CAPTURING) }
+}
+
+/// The ModifyTable nodes of `query_desc`: the top node of an INSERT, UPDATE or DELETE, and
+/// the ones of the data-modifying WITH queries.
+unsafe fn modify_nodes(query_desc: &PgBox<pg_sys::QueryDesc>) -> Vec<*mut pg_sys::PlanState> {
+    let mut nodes = vec![query_desc.planstate];
+    let subplans =
+        list::List::<*mut core::ffi::c_void>::downcast_ptr((*query_desc.estate).es_subplanstates);
+    nodes.extend(
+        subplans
+            .iter()
+            .flat_map(|list| list.iter())
+            .map(|node| (*node).cast::<pg_sys::PlanState>()),
+    );
+    nodes.retain(|node| !node.is_null() && is_a(node.cast(), pg_sys::NodeTag::T_ModifyTableState));
+    nodes
+}
+
+/// Queue a change for every row the ModifyTable nodes of `query_desc` write to the tracked
+/// `table_name`, from the rows they return, see [`Capture`]. The executor only sends the rows
+/// to the destination if the statement has a RETURNING list of its own, the nodes
+/// themselves return them either way.
+pub fn capture_rows(
     query_desc: &PgBox<pg_sys::QueryDesc>,
-    expected_column: &str,
-    update_receiver: &mut UpdateDestReceiver,
+    table_name: &str,
+    key_column: &str,
+    value_column: &str,
 ) {
     unsafe {
-        let estate = *(query_desc.estate);
+        let rtable = (*query_desc.plannedstmt).rtable;
+        for node in modify_nodes(query_desc) {
+            let state = &*node.cast::<pg_sys::ModifyTableState>();
+            let root = state.rootResultRelInfo;
+            if root.is_null()
+                || !utils::is_mapped_relation((*(*root).ri_RelationDesc).rd_id, table_name)
+            {
+                continue;
+            }
+            let plan = (*node).plan;
+            let modify = &*plan.cast::<pg_sys::ModifyTable>();
+            let merge = match is_merge_node(modify) {
+                true => Some(Merge {
+                    key_column: key_column.to_string(),
+                    value_column: value_column.to_string(),
+                    counts: [0.0; 3],
+                    columns: vec![],
+                }),
+                false => None,
+            };
+            let capture = write_capture(plan, rtable, key_column, value_column);
+            if capture.is_none() && merge.is_none() {
+                warning!("postgres_redis: the rows written to {table_name} can't be captured, the statement was planned without its key and value columns");
+                continue;
+            }
+            let operation = match modify.operation {
+                pg_sys::CmdType_CMD_DELETE => Operation::Delete,
+                _ => Operation::Set,
+            };
+            // The entries added to the RETURNING list of the statement are removed from the
+            // rows sent to the client.
+            let has_junk = list::List::<*mut core::ffi::c_void>::downcast_ptr((*plan).targetlist)
+                .iter()
+                .flat_map(|list| list.iter())
+                .any(|entry| (*(*entry).cast::<pg_sys::TargetEntry>()).resjunk);
+            let junk_filter = if has_junk && node == query_desc.planstate {
+                let context = pg_sys::MemoryContextSwitchTo((*query_desc.estate).es_query_cxt);
+                let junk_filter =
+                    pg_sys::ExecInitJunkFilter((*plan).targetlist, std::ptr::null_mut());
+                pg_sys::MemoryContextSwitchTo(context);
+                (*query_desc.as_ptr()).tupDesc = (*junk_filter).jf_cleanTupType;
+                junk_filter
+            } else {
+                std::ptr::null_mut()
+            };
+            let mut entry = Capturing {
+                query_desc: query_desc.as_ptr(),
+                node,
+                exec: (*node).ExecProcNodeReal,
+                capture,
+                operation,
+                junk_filter,
+                subplan: std::ptr::null_mut(),
+                subplan_exec: None,
+                old_key_position: 0,
+                old_key: None,
+                moved: vec![],
+                written: HashSet::new(),
+                merge,
+            };
+            let subplan = (*node).lefttree;
+            if let (Some(position), false) = (
+                old_key_position(modify, rtable, key_column),
+                subplan.is_null(),
+            ) {
+                entry.subplan = subplan;
+                entry.subplan_exec = (*subplan).ExecProcNodeReal;
+                entry.old_key_position = position;
+                (*subplan).ExecProcNodeReal = Some(capture_old_key);
+            } else if entry.merge.is_some() {
+                warning!("postgres_redis: the rows written to {table_name} can't be captured, the statement was planned without its key column");
+                continue;
+            }
+            if entry.capture.is_some() {
+                (*node).ExecProcNodeReal = Some(capture_row);
+            }
+            capturing().push(entry);
+        }
+    }
+}
+
+/// Stop capturing the rows of `query_desc` once the executor is done with it, and delete the
+/// old keys of the rows that moved to another key.
+pub fn end_capture(query_desc: &PgBox<pg_sys::QueryDesc>) {
+    let query_desc = query_desc.as_ptr();
+    let mut ended = vec![];
+    capturing().retain_mut(|capturing| {
+        if capturing.query_desc != query_desc {
+            return true;
+        }
+        ended.append(&mut capturing.moved);
+        ended.retain(|key| !capturing.written.contains(key));
+        false
+    });
+    for key in ended {
+        push_change(Info::delete(&key), &key);
+    }
+}
+
+/// Forget the nodes of the statements of the finished transaction.
+pub fn reset_capture() {
+    capturing().clear();
+}
+
+fn push_change(info: Option<Info>, key: &str) {
+    match info {
+        Some(info) => xact::push_table_change(
+            info.with_relation(gucs::table_relid())
+                .with_policy(gucs::PGD_WRITE_POLICY.get()),
+        ),
+        None => prshmem::warn_key_too_long(key),
+    }
+}
+
+/// The text of the column at `position` of `slot`, None if it is NULL.
+unsafe fn slot_text(slot: *mut pg_sys::TupleTableSlot, position: usize) -> Option<String> {
+    let attr = (*(*slot).tts_tupleDescriptor)
+        .attrs
+        .as_slice(position)
+        .last()
+        .copied()?;
+    slot_getattr(slot, position).map(|datum| utils::output_datum(datum, attr.atttypid))
+}
+
+fn is_empty(slot: *mut pg_sys::TupleTableSlot) -> bool {
+    slot.is_null() || unsafe { (*slot).tts_flags } & pg_sys::TTS_FLAG_EMPTY as u16 != 0
+}
+
+#[pg_guard]
+unsafe extern "C" fn capture_row(node: *mut pg_sys::PlanState) -> *mut pg_sys::TupleTableSlot {
+    let index = capturing()
+        .iter()
+        .rposition(|capturing| capturing.node == node)
+        .expect("postgres_redis: the captured node is unknown");
+    let exec = capturing()[index]
+        .exec
+        .expect("the node has no function to run it");
+    let slot = exec(node);
+    if is_empty(slot) {
+        return slot;
+    }
+    let capturing = &mut capturing()[index];
+    let capture = capturing
+        .capture
+        .expect("postgres_redis: the captured node returns no rows");
+    let key_prefix = gucs::key_prefix();
+    if let Some(key) = slot_text(slot, capture.key) {
+        let key = format!("{key_prefix}{key}");
+        let info = match (capturing.operation, slot_text(slot, capture.value)) {
+            (Operation::Set, Some(value)) => Info::write(&key, &value),
+            _ => Info::delete(&key),
+        };
+        if let Some(old_key) = capturing.old_key.take() {
+            if old_key != key {
+                capturing.moved.push(old_key);
+            }
+            capturing.written.insert(key.clone());
+        }
+        push_change(info, &key);
+    }
+    if capturing.junk_filter.is_null() {
+        slot
+    } else {
+        pg_sys::ExecFilterJunk(capturing.junk_filter, slot)
+    }
+}
+
+/// Record the old key of every row the ModifyTable node of a statement that changes keys
+/// reads, the row it returns next is the new version of that row.
+#[pg_guard]
+unsafe extern "C" fn capture_old_key(node: *mut pg_sys::PlanState) -> *mut pg_sys::TupleTableSlot {
+    let index = capturing()
+        .iter()
+        .rposition(|capturing| capturing.subplan == node)
+        .expect("postgres_redis: the captured node is unknown");
+    if capturing()[index].merge.is_some() {
+        capture_merge_action(&mut capturing()[index]);
+    }
+    let exec = capturing()[index]
+        .subplan_exec
+        .expect("the node has no function to run it");
+    let slot = exec(node);
+    let capturing = &mut capturing()[index];
+    capturing.old_key = match is_empty(slot) {
+        true => None,
+        false => slot_text(slot, capturing.old_key_position)
+            .map(|key| format!("{}{key}", gucs::key_prefix())),
+    };
+    slot
+}
 
-        let result_rel_info = estate.es_result_relations;
-
-        if !result_rel_info.is_null() {
-            let relation_rel = *result_rel_info;
-            let relation_desc = (*relation_rel).ri_RelationDesc;
-
-            let tuple_new_slot = *((*relation_rel).ri_newTupleSlot);
-
-            if !relation_desc.is_null() {
-                let relation_descp: pgrx::prelude::pg_sys::RelationData = *relation_desc;
-                let tuple_desc = PgTupleDesc::from_pg_unchecked(relation_descp.rd_att);
-                let natts = tuple_desc.natts;
-                for i in 0..natts {
-                    let is_null = *tuple_new_slot.tts_isnull.add(i as usize);
-                    if !is_null {
-                        let desc_pointer = tuple_desc.attrs.as_ptr();
-                        let desc_attr = *desc_pointer.add(i as usize);
-                        let attr = desc_attr.name();
-                        let value_pointer = *tuple_new_slot.tts_values.add(i as usize);
-                        let mut foutoid: Oid = Oid::default();
-                        let mut typisvarlena: bool = false;
-                        let typoid: Oid = desc_attr.atttypid;
-                        getTypeOutputInfo(typoid, &mut foutoid, &mut typisvarlena);
-                        let output = OidOutputFunctionCall(foutoid, value_pointer);
-                        let output_value = CStr::from_ptr(output);
-                        let output_value = output_value
-                            .to_str()
-                            .expect("Failed to convert Postgres query string for rust");
-
-                        if attr == expected_column {
-                            update_receiver.value = Some(output_value.to_string());
-                            update_receiver.column = attr.to_string();
-                        }
-                    }
-                }
+/// Queue the change of the action a MERGE took for the row it read last, which it takes
+/// before reading the next one. The node counts the rows it inserted, updated and deleted,
+/// and leaves the new version of an inserted or updated row in the slot of its relation.
+unsafe fn capture_merge_action(capturing: &mut Capturing) {
+    let state = &*capturing.node.cast::<pg_sys::ModifyTableState>();
+    let Some(merge) = capturing.merge.as_mut() else {
+        return;
+    };
+    let (inserted, updated, deleted) = merge_counts(state);
+    let [was_inserted, was_updated, was_deleted] =
+        std::mem::replace(&mut merge.counts, [inserted, updated, deleted]);
+    let old_key = capturing.old_key.take();
+    let relation = if inserted > was_inserted {
+        state.rootResultRelInfo
+    } else if updated > was_updated {
+        state.resultRelInfo.add(state.mt_lastResultIndex as usize)
+    } else {
+        if deleted > was_deleted {
+            if let Some(old_key) = old_key {
+                push_change(Info::delete(&old_key), &old_key);
             }
         }
+        return;
+    };
+    let relid = (*(*relation).ri_RelationDesc).rd_id;
+    let (key, value) = match merge.columns.iter().find(|(oid, _, _)| *oid == relid) {
+        Some((_, key, value)) => (*key, *value),
+        None => {
+            let attnum = |column: &str| {
+                let name = CString::new(column).expect("column name contains a null byte");
+                pg_sys::get_attnum(relid, name.as_ptr()) as usize
+            };
+            let columns = (attnum(&merge.key_column), attnum(&merge.value_column));
+            merge.columns.push((relid, columns.0, columns.1));
+            columns
+        }
+    };
+    let slot = (*relation).ri_newTupleSlot;
+    if is_empty(slot) {
+        return;
+    }
+    let Some(key) = slot_text(slot, key) else {
+        return;
+    };
+    let key = format!("{}{key}", gucs::key_prefix());
+    let info = match slot_text(slot, value) {
+        Some(value) => Info::write(&key, &value),
+        None => Info::delete(&key),
+    };
+    if let Some(old_key) = old_key {
+        if old_key != key {
+            capturing.moved.push(old_key);
+        }
+    }
+    capturing.written.insert(key.clone());
+    push_change(info, &key);
+}
+
+#[cfg(any(feature = "pg15", feature = "pg16"))]
+fn merge_counts(state: &pg_sys::ModifyTableState) -> (f64, f64, f64) {
+    (
+        state.mt_merge_inserted,
+        state.mt_merge_updated,
+        state.mt_merge_deleted,
+    )
+}
+
+#[cfg(not(any(feature = "pg15", feature = "pg16")))]
+fn merge_counts(_state: &pg_sys::ModifyTableState) -> (f64, f64, f64) {
+    (0.0, 0.0, 0.0)
+}
+
+fn is_merge_node(modify: &pg_sys::ModifyTable) -> bool {
+    #[cfg(any(feature = "pg15", feature = "pg16"))]
+    if modify.operation == pg_sys::CmdType_CMD_MERGE {
+        return true;
+    }
+    let _ = modify;
+    false
+}
+
+/// Returns true if the query writes to the tracked `table_name` or one of its partitions.
+pub fn is_writing_table(query_desc: &PgBox<pg_sys::QueryDesc>, table_name: &str) -> bool {
+    unsafe {
+        let estate = *(query_desc.estate);
+        let result_rels = match list::List::<*mut core::ffi::c_void>::downcast_ptr(
+            estate.es_opened_result_relations,
+        ) {
+            Some(result_rels) => result_rels,
+            None => return false,
+        };
+        let is_writing = result_rels.iter().any(|relation_rel| {
+            let relation_desc = (*(*relation_rel).cast::<pg_sys::ResultRelInfo>()).ri_RelationDesc;
+            !relation_desc.is_null()
+                && utils::is_mapped_relation((*relation_desc).rd_id, table_name)
+        });
+        is_writing
+    }
+}
+
+/// Returns true if the statement of `query_desc` writes rows: an INSERT, UPDATE, DELETE or
+/// MERGE, or a statement with a data-modifying WITH query. The RETURNING list of an
+/// `INSERT ... ON CONFLICT DO UPDATE` holds the inserted and the updated rows.
+pub fn is_write(query_desc: &PgBox<pg_sys::QueryDesc>) -> bool {
+    let op = query_desc.operation;
+    if is_merge(query_desc) || unsafe { (*query_desc.plannedstmt).hasModifyingCTE } {
+        return true;
+    }
+    [
+        pg_sys::CmdType_CMD_INSERT,
+        pg_sys::CmdType_CMD_UPDATE,
+        pg_sys::CmdType_CMD_DELETE,
+    ]
+    .contains(&op)
+}
+
+/// Returns true if the statement of `query_desc` is a MERGE.
+fn is_merge(query_desc: &PgBox<pg_sys::QueryDesc>) -> bool {
+    #[cfg(any(feature = "pg15", feature = "pg16"))]
+    if query_desc.operation == pg_sys::CmdType_CMD_MERGE {
+        return true;
+    }
+    let _ = query_desc;
+    false
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::prshmem::Operation;
+    use crate::xact;
+
+    /// The pending changes as (operation, key, value).
+    fn pending() -> Vec<(Operation, String, String)> {
+        xact::pending_infos()
+            .iter()
+            .map(|info| (info.operation, info.key_string(), info.value_string()))
+            .collect()
+    }
+
+    fn change(operation: Operation, key: &str, value: &str) -> (Operation, String, String) {
+        (operation, String::from(key), String::from(value))
+    }
+
+    #[pg_test]
+    fn test_capture_every_written_row() {
+        Spi::run("INSERT INTO users (id, first_name) VALUES (10, 'Ada'), (11, 'Grace')").unwrap();
+        Spi::run("UPDATE users SET first_name = first_name || '!' WHERE id IN (1, 2)").unwrap();
+        Spi::run("DELETE FROM users WHERE id >= 10").unwrap();
+        let changes = pending();
+        assert_eq!(6, changes.len());
+        for expected in [
+            change(Operation::Set, "users:10", "Ada"),
+            change(Operation::Set, "users:11", "Grace"),
+            change(Operation::Set, "users:1", "Adebayo!"),
+            change(Operation::Set, "users:2", "Usman!"),
+            change(Operation::Delete, "users:10", ""),
+            change(Operation::Delete, "users:11", ""),
+        ] {
+            assert!(changes.contains(&expected), "{expected:?} wasn't captured");
+        }
+    }
+
+    #[pg_test]
+    fn test_capture_keeps_returning() {
+        // The added RETURNING list isn't sent anywhere.
+        let returned_rows = Spi::connect(|mut client| {
+            client
+                .update("UPDATE users SET last_name = 'A' WHERE id <= 2", None, None)
+                .map(|_| unsafe { !pg_sys::SPI_tuptable.is_null() })
+        });
+        assert_eq!(Ok(false), returned_rows);
+        // The key and value added to a RETURNING list aren't returned.
+        let columns = Spi::connect(|mut client| {
+            client
+                .update(
+                    "UPDATE users SET first_name = 'Robert' WHERE id = 3 RETURNING last_name",
+                    None,
+                    None,
+                )?
+                .columns()
+        });
+        assert_eq!(Ok(1), columns);
+        let changes = pending();
+        assert_eq!(3, changes.len());
+        assert_eq!(change(Operation::Set, "users:3", "Robert"), changes[2]);
+    }
+
+    #[pg_test]
+    fn test_capture_key_update_moves_the_key() {
+        Spi::run("UPDATE users SET id = id + 100 WHERE id IN (1, 2)").unwrap();
+        let changes = pending();
+        assert_eq!(4, changes.len());
+        for expected in [
+            change(Operation::Set, "users:101", "Adebayo"),
+            change(Operation::Set, "users:102", "Usman"),
+            change(Operation::Delete, "users:1", ""),
+            change(Operation::Delete, "users:2", ""),
+        ] {
+            assert!(changes.contains(&expected), "{expected:?} wasn't captured");
+        }
+    }
+
+    #[pg_test]
+    fn test_capture_upsert_moves_the_key() {
+        Spi::run(
+            "INSERT INTO users (id, first_name) VALUES (1, 'Ada') ON CONFLICT (id) DO UPDATE SET id = 50",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                change(Operation::Set, "users:50", "Adebayo"),
+                change(Operation::Delete, "users:1", ""),
+            ],
+            pending()
+        );
+    }
+
+    #[pg_test]
+    fn test_capture_upsert() {
+        Spi::run(
+            "INSERT INTO users (id, first_name) VALUES (1, 'Ada'), (20, 'Grace')
+             ON CONFLICT (id) DO UPDATE SET first_name = excluded.first_name",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO users (id, first_name) VALUES (2, 'Alan') ON CONFLICT DO NOTHING")
+            .unwrap();
+        assert_eq!(
+            vec![
+                change(Operation::Set, "users:1", "Ada"),
+                change(Operation::Set, "users:20", "Grace"),
+            ],
+            pending()
+        );
+    }
+
+    #[cfg(any(feature = "pg15", feature = "pg16"))]
+    #[pg_test]
+    fn test_capture_merge_actions() {
+        Spi::run(
+            "MERGE INTO users u
+             USING (VALUES (1, 'Ada'), (2, NULL), (3, 'Grace'), (30, 'Alan')) v (id, name)
+             ON u.id = v.id
+             WHEN MATCHED AND v.id = 2 THEN DELETE
+             WHEN MATCHED AND v.id = 3 THEN UPDATE SET id = 33, first_name = v.name
+             WHEN MATCHED THEN UPDATE SET first_name = v.name
+             WHEN NOT MATCHED THEN INSERT (id, first_name) VALUES (v.id, v.name)",
+        )
+        .unwrap();
+        let changes = pending();
+        assert_eq!(5, changes.len());
+        for expected in [
+            change(Operation::Set, "users:1", "Ada"),
+            change(Operation::Delete, "users:2", ""),
+            change(Operation::Set, "users:33", "Grace"),
+            change(Operation::Set, "users:30", "Alan"),
+            change(Operation::Delete, "users:3", ""),
+        ] {
+            assert!(changes.contains(&expected), "{expected:?} wasn't captured");
+        }
     }
 }
@@ -1,14 +1,63 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::num::NonZeroUsize;
 
 use pgrx::{
     is_a, list,
     pg_sys::{
         self, eval_const_expressions, getTypeOutputInfo, get_attname, rt_fetch, BoolExpr, FromExpr,
-        List, Node, NodeTag, Oid, OidOutputFunctionCall, OpExpr, TextEqualOperator,
-        RELKIND_RELATION,
+        List, Node, NodeTag, Oid, OidOutputFunctionCall, OpExpr, RelnameGetRelid,
+        TextEqualOperator, RELKIND_PARTITIONED_TABLE, RELKIND_RELATION,
     },
+    PgTryBuilder, PgTupleDesc,
 };
 
+// These live in `catalog/pg_inherits.h`, which isn't part of the generated pgrx bindings.
+// Calls must go through `pg_guard_ffi_boundary` since both can raise a Postgres error.
+extern "C" {
+    fn has_subclass(relation_id: Oid) -> bool;
+    fn find_all_inheritors(
+        parent_rel_id: Oid,
+        lockmode: pg_sys::LOCKMODE,
+        numparents: *mut *mut List,
+    ) -> *mut List;
+}
+
+// From `utils/inval.h`, which isn't part of the generated pgrx bindings either.
+extern "C" {
+    fn CacheRegisterRelcacheCallback(
+        func: unsafe extern "C" fn(arg: pg_sys::Datum, relid: Oid),
+        arg: pg_sys::Datum,
+    );
+}
+
+/// The tracked table and the relations inheriting from it, as found by the last call of
+/// `is_mapped_relation`. Dropped when the relcache entry of any of them is invalidated, which
+/// happens when a partition or child is attached, detached, created or dropped.
+static mut INHERITORS: Option<(Oid, Vec<Oid>)> = None;
+
+// A backend is single threaded, so there is never more than one user of the static.
+fn inheritors() -> &'static mut Option<(Oid, Vec<Oid>)> {
+    unsafe { &mut *std::ptr::addr_of_mut!(INHERITORS) }
+}
+
+/// Forget the cached inheritors of the tracked table when their relcache entries change.
+/// Must be called once from `_PG_init`.
+pub fn register_relcache_callback() {
+    unsafe { CacheRegisterRelcacheCallback(relcache_callback, pg_sys::Datum::from(0)) };
+}
+
+unsafe extern "C" fn relcache_callback(_arg: pg_sys::Datum, relid: Oid) {
+    let stale = match inheritors() {
+        Some((table_oid, children)) => {
+            relid == pg_sys::InvalidOid || relid == *table_oid || children.contains(&relid)
+        }
+        None => false,
+    };
+    if stale {
+        *inheritors() = None;
+    }
+}
+
 /// This function returns the column name and value of the WHERE clause of the query.
 /// This column name is the `key_column_name` argument. This function only extracts
 /// the needed result if the filter is an equal filter. It does not work for like filter.
@@ -93,16 +142,9 @@ pub unsafe fn get_where_object(
 
                 let rte = rt_fetch(varno, range_table);
 
-                if (*rte).relkind as u8 != RELKIND_RELATION {
-                    continue;
-                }
-                let tbl_data = *(*rte).eref;
-                let tbl_name = CStr::from_ptr(tbl_data.aliasname);
-                let tbl_name = tbl_name
-                    .to_str()
-                    .expect("Failed to convert Postgres query string for rust");
-
-                if table_name != tbl_name {
+                if !is_table_kind((*rte).relkind as u8)
+                    || !is_mapped_relation((*rte).relid, table_name)
+                {
                     continue;
                 }
 
@@ -141,7 +183,8 @@ unsafe fn node_fetch(nodes: *mut List, index: usize) -> *mut Node {
         .cast()
 }
 
-/// This function returns true if the `expected_table_name` is in the list of tables.
+/// This function returns true if the `expected_table_name` table, or one of its partitions or
+/// inheritance children, is in the list of tables.
 pub fn is_contain_table(table_lists: *mut List, expected_table_name: &str) -> bool {
     let mut result = false;
     unsafe {
@@ -151,15 +194,10 @@ pub fn is_contain_table(table_lists: *mut List, expected_table_name: &str) -> bo
         }
         for i in 1..=length {
             let table_entry = *rt_fetch(i as u32, table_lists);
-            if table_entry.relkind as u8 != RELKIND_RELATION {
+            if !is_table_kind(table_entry.relkind as u8) {
                 continue;
             }
-            let table_data = *table_entry.eref;
-            let name = CStr::from_ptr(table_data.aliasname);
-            let name = name
-                .to_str()
-                .expect("Failed to convert Postgres query string for rust");
-            if name == expected_table_name {
+            if is_mapped_relation(table_entry.relid, expected_table_name) {
                 result = true;
                 break;
             }
@@ -167,3 +205,383 @@ pub fn is_contain_table(table_lists: *mut List, expected_table_name: &str) -> bo
     }
     result
 }
+
+/// Only plain tables and partitioned parents can hold the tracked rows.
+fn is_table_kind(relkind: u8) -> bool {
+    relkind == RELKIND_RELATION || relkind == RELKIND_PARTITIONED_TABLE
+}
+
+/// This function returns true if `relid` is the `table_name` table itself or any relation
+/// that inherits from it. Partitions are inheritance children too, so a mapping on a
+/// partitioned table covers every partition below it. The children are cached until the
+/// relcache entry of the table or one of them is invalidated, so partitions attached after
+/// the backend started are picked up as well.
+pub unsafe fn is_mapped_relation(relid: Oid, table_name: &str) -> bool {
+    let table_name = CString::new(table_name).expect("table name contains a nul byte");
+    let table_oid = RelnameGetRelid(table_name.as_ptr());
+    if table_oid == pg_sys::InvalidOid {
+        return false;
+    }
+    if relid == table_oid {
+        return true;
+    }
+    match inheritors() {
+        Some((cached, children)) if *cached == table_oid => return children.contains(&relid),
+        _ => {}
+    }
+    let children = table_inheritors(table_oid);
+    let is_child = children.contains(&relid);
+    *inheritors() = Some((table_oid, children));
+    is_child
+}
+
+unsafe fn table_inheritors(table_oid: Oid) -> Vec<Oid> {
+    if !pg_sys::ffi::pg_guard_ffi_boundary(|| has_subclass(table_oid)) {
+        return vec![];
+    }
+    // The tables in the query are already locked by the time this runs, so no extra lock
+    // is needed to walk the inheritance tree.
+    let children = pg_sys::ffi::pg_guard_ffi_boundary(|| {
+        find_all_inheritors(
+            table_oid,
+            pg_sys::NoLock as pg_sys::LOCKMODE,
+            std::ptr::null_mut(),
+        )
+    });
+    list::List::<Oid>::downcast_ptr(children)
+        .map(|children| children.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+/// Returns the kind and GID of `node` if it is a `TransactionStmt` for one of the two-phase
+/// commit commands.
+pub unsafe fn get_transaction_stmt(
+    node: *mut Node,
+) -> Option<(pg_sys::TransactionStmtKind, String)> {
+    if node.is_null() || !is_a(node, NodeTag::T_TransactionStmt) {
+        return None;
+    }
+    let stmt = *node.cast::<pg_sys::TransactionStmt>();
+    if stmt.gid.is_null() {
+        return None;
+    }
+    let gid = CStr::from_ptr(stmt.gid)
+        .to_str()
+        .expect("Failed to convert Postgres query string for rust");
+    Some((stmt.kind, gid.to_string()))
+}
+
+/// Returns the current position in the WAL. Every commit record written before this call ends
+/// at or before the returned position. A transaction only becomes visible to new snapshots
+/// after its commit record is written, so values read with a snapshot are versioned with
+/// `snapshot_version` instead.
+pub fn current_lsn() -> pg_sys::XLogRecPtr {
+    unsafe {
+        if pg_sys::RecoveryInProgress() {
+            pg_sys::GetXLogReplayRecPtr(std::ptr::null_mut())
+        } else {
+            pg_sys::GetXLogInsertRecPtr()
+        }
+    }
+}
+
+/// Returns the version of the values read with `snapshot`: the WAL position once the read is
+/// done, or None if a commit the snapshot doesn't see may end before that position.
+///
+/// The commit record of a transaction is written before the transaction stops being in
+/// progress for new snapshots, so a snapshot can miss a commit that ends before the position.
+/// The position is therefore only used if no other transaction had a transaction id from
+/// the time the snapshot was taken until now. Every commit the snapshot misses then belongs
+/// to a transaction that gets its id, and writes its commit record, later on.
+///
+/// # Safety
+///
+/// `snapshot` must be null or a valid snapshot.
+pub unsafe fn snapshot_version(snapshot: pg_sys::Snapshot) -> Option<u64> {
+    if snapshot.is_null() {
+        return None;
+    }
+    let version = current_lsn();
+    (!misses_commits(&*snapshot, next_transaction_id())).then_some(version)
+}
+
+/// Returns true if a transaction other than the current one was in progress when `snapshot`
+/// was taken, or got its transaction id since, up to `next_xid`. Aborted transactions never
+/// commit and are left out.
+///
+/// # Safety
+///
+/// The transaction ids of `snapshot` must be valid.
+pub unsafe fn misses_commits(
+    snapshot: &pg_sys::SnapshotData,
+    next_xid: pg_sys::TransactionId,
+) -> bool {
+    let may_commit = |xid: pg_sys::TransactionId| {
+        !pg_sys::TransactionIdIsCurrentTransactionId(xid) && !pg_sys::TransactionIdDidAbort(xid)
+    };
+    let xids = |xids: *mut pg_sys::TransactionId, count: usize| match count {
+        0 => &[][..],
+        count => std::slice::from_raw_parts(xids, count),
+    };
+    // The subtransactions of an overflowed snapshot aren't all known.
+    if snapshot.suboverflowed
+        || xids(snapshot.xip, snapshot.xcnt as usize)
+            .iter()
+            .chain(xids(snapshot.subxip, snapshot.subxcnt as usize))
+            .any(|xid| may_commit(*xid))
+    {
+        return true;
+    }
+    let mut xid = snapshot.xmax;
+    while pg_sys::TransactionIdPrecedes(xid, next_xid) {
+        if may_commit(xid) {
+            return true;
+        }
+        xid = xid.wrapping_add(1).max(pg_sys::FirstNormalTransactionId);
+    }
+    false
+}
+
+/// Returns a version for the values `caller` reads with new snapshots from now on, after
+/// waiting for every transaction that may have written its commit record before the returned
+/// position but isn't visible yet.
+///
+/// The values must be read with a snapshot taken after the wait, so only at the READ COMMITTED
+/// isolation level and not with a read-only SPI query, which reuses the snapshot of the
+/// statement. During recovery a transaction is visible once its commit record is replayed, so
+/// there is nothing to wait for.
+///
+/// # Safety
+///
+/// Must be called inside a transaction.
+pub unsafe fn fence(caller: &str) -> u64 {
+    if pg_sys::XactIsoLevel >= pg_sys::XACT_REPEATABLE_READ as i32 {
+        pgrx::error!("postgres_redis: {caller} must run at the READ COMMITTED isolation level");
+    }
+    let version = current_lsn();
+    if pg_sys::RecoveryInProgress() {
+        return version;
+    }
+    let snapshot = &*pg_sys::GetLatestSnapshot();
+    let mut xids = match snapshot.xcnt {
+        0 => vec![],
+        count => std::slice::from_raw_parts(snapshot.xip, count as usize).to_vec(),
+    };
+    // A transaction that completes after the snapshot's latest completed one isn't listed.
+    let next_xid = next_transaction_id();
+    let mut xid = snapshot.xmax;
+    while pg_sys::TransactionIdPrecedes(xid, next_xid) {
+        xids.push(xid);
+        xid = xid.wrapping_add(1).max(pg_sys::FirstNormalTransactionId);
+    }
+    for xid in xids {
+        if !pg_sys::TransactionIdIsCurrentTransactionId(xid) {
+            pg_sys::XactLockTableWait(
+                xid,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                pg_sys::XLTW_Oper_XLTW_None,
+            );
+        }
+    }
+    version
+}
+
+/// Returns the transaction id the next transaction gets.
+fn next_transaction_id() -> pg_sys::TransactionId {
+    #[cfg(feature = "pg11")]
+    unsafe {
+        pg_sys::ReadNewTransactionId()
+    }
+    #[cfg(not(feature = "pg11"))]
+    unsafe {
+        pg_sys::ReadNextFullTransactionId().value as pg_sys::TransactionId
+    }
+}
+
+/// Render `datum` of type `typoid` with the output function of the type, the same text
+/// Postgres returns for the value.
+///
+/// # Safety
+///
+/// `datum` must be a valid, non-null value of type `typoid`.
+pub unsafe fn output_datum(datum: pg_sys::Datum, typoid: Oid) -> String {
+    let mut foutoid: Oid = Oid::default();
+    let mut typisvarlena: bool = false;
+    getTypeOutputInfo(typoid, &mut foutoid, &mut typisvarlena);
+    let output = OidOutputFunctionCall(foutoid, datum);
+    CStr::from_ptr(output)
+        .to_str()
+        .expect("Failed to convert Postgres query string for rust")
+        .to_string()
+}
+
+/// Parse `text` with the input function of the type `typoid`, the reverse of
+/// `output_datum`. Returns None if `text` contains a nul byte.
+pub fn input_datum(text: &str, typoid: Oid, typmod: i32) -> Option<pg_sys::Datum> {
+    let text = CString::new(text).ok()?;
+    let mut finoid: Oid = Oid::default();
+    let mut typioparam: Oid = Oid::default();
+    unsafe {
+        pg_sys::getTypeInputInfo(typoid, &mut finoid, &mut typioparam);
+        Some(pg_sys::OidInputFunctionCall(
+            finoid,
+            text.as_ptr().cast_mut(),
+            typioparam,
+            typmod,
+        ))
+    }
+}
+
+/// Returns the datum and attribute of the `column` attribute of `tuple`, or None if the
+/// column doesn't exist or is NULL.
+///
+/// # Safety
+///
+/// `tuple` must be a valid heap tuple described by `tuple_desc`.
+pub unsafe fn heap_tuple_attr<'a>(
+    tuple: *mut pg_sys::HeapTupleData,
+    tuple_desc: &'a PgTupleDesc,
+    column: &str,
+) -> Option<(pg_sys::Datum, &'a pg_sys::FormData_pg_attribute)> {
+    let (i, attr) = tuple_desc
+        .iter()
+        .enumerate()
+        .find(|(_, attr)| !attr.attisdropped && attr.name() == column)?;
+    let datum = pgrx::heap_getattr_raw(tuple, NonZeroUsize::new(i + 1)?, tuple_desc.as_ptr())?;
+    Some((datum, attr))
+}
+
+/// Quote `value` as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the relations a `TruncateStmt` or a `COPY ... FROM` statement writes to, and
+/// whether it is a TRUNCATE. Relations that don't exist are left out, the statement itself
+/// reports them.
+///
+/// # Safety
+///
+/// `node` must be null or a valid utility statement node.
+pub unsafe fn get_bulk_write_relations(node: *mut Node) -> Option<(bool, Vec<Oid>)> {
+    let range_var_oid = |range_var: *mut pg_sys::RangeVar| {
+        pg_sys::RangeVarGetRelidExtended(
+            range_var,
+            pg_sys::NoLock as pg_sys::LOCKMODE,
+            pg_sys::RVROption_RVR_MISSING_OK,
+            None,
+            std::ptr::null_mut(),
+        )
+    };
+    if node.is_null() {
+        return None;
+    }
+    if is_a(node, NodeTag::T_TruncateStmt) {
+        let stmt = *node.cast::<pg_sys::TruncateStmt>();
+        let relations = list::List::<*mut core::ffi::c_void>::downcast_ptr(stmt.relations)?;
+        let oids = relations
+            .iter()
+            .map(|range_var| range_var_oid((*range_var).cast()))
+            .filter(|oid| *oid != pg_sys::InvalidOid)
+            .collect();
+        return Some((true, oids));
+    }
+    if is_a(node, NodeTag::T_CopyStmt) {
+        let stmt = *node.cast::<pg_sys::CopyStmt>();
+        if !stmt.is_from || stmt.relation.is_null() {
+            return None;
+        }
+        let oid = range_var_oid(stmt.relation);
+        return (oid != pg_sys::InvalidOid).then(|| (false, vec![oid]));
+    }
+    None
+}
+
+/// Run `f` in a subtransaction. Returns None, once the subtransaction is rolled back, if `f`
+/// raises an error.
+pub fn try_in_subtransaction<T>(f: impl FnOnce() -> T) -> Option<T> {
+    unsafe {
+        let context = pg_sys::CurrentMemoryContext;
+        let owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        let result = PgTryBuilder::new(std::panic::AssertUnwindSafe(|| Some(f())))
+            .catch_others(|_| None)
+            .execute();
+        if result.is_some() {
+            pg_sys::ReleaseCurrentSubTransaction();
+        } else {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+        }
+        pg_sys::MemoryContextSwitchTo(context);
+        pg_sys::CurrentResourceOwner = owner;
+        result
+    }
+}
+
+/// Quote `ident` as an SQL identifier if needed.
+pub fn quote_identifier(ident: &str) -> String {
+    let ident = CString::new(ident).expect("identifier contains a nul byte");
+    unsafe {
+        CStr::from_ptr(pg_sys::quote_identifier(ident.as_ptr()))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Quote `literal` as an SQL string literal.
+pub fn quote_literal(literal: &str) -> String {
+    let literal = CString::new(literal).expect("literal contains a nul byte");
+    unsafe {
+        CStr::from_ptr(pg_sys::quote_literal_cstr(literal.as_ptr()))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_misses_commits() {
+        // The rows of the users table were committed by the transaction installing the
+        // extension.
+        let committed = Spi::get_one::<i64>("SELECT xmin::text::int8 FROM users LIMIT 1")
+            .unwrap()
+            .unwrap() as pg_sys::TransactionId;
+        let mut snapshot: pg_sys::SnapshotData = unsafe { std::mem::zeroed() };
+        snapshot.xmin = committed;
+        snapshot.xmax = committed + 1;
+        unsafe {
+            // No transaction got an id since the snapshot was taken.
+            assert!(!super::misses_commits(&snapshot, committed + 1));
+
+            // The transaction got its id after the snapshot was taken.
+            snapshot.xmax = committed;
+            assert!(super::misses_commits(&snapshot, committed + 1));
+
+            // The transaction was in progress when the snapshot was taken.
+            let mut in_progress = [committed];
+            snapshot.xmax = committed + 1;
+            snapshot.xip = in_progress.as_mut_ptr();
+            snapshot.xcnt = 1;
+            assert!(super::misses_commits(&snapshot, committed + 1));
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use pgrx::prelude::*;
+
+use crate::client;
+use crate::gucs::{self, WritePolicy};
+use crate::prshmem::{Info, MAX_INFO_LENGTH};
+use crate::trigger::{self, Mapping};
+use crate::utils::{self, quote_identifier};
+use crate::writer::{self, VERSION_KEY_SUFFIX};
+use crate::xact;
+
+/// The number of rows, or redis keys, compared at a time.
+const VERIFY_BATCH_SIZE: usize = 1000;
+
+/// Run `command` on the redis connection of the backend, raising an error if redis fails.
+fn run<T>(command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>) -> T {
+    match client::with_connection(command) {
+        Ok(result) => result,
+        Err(err) => error!("postgres_redis: redis command failed: {err}"),
+    }
+}
+
+/// Compare the rows of the mapped table `mapping` with redis and return the keys that differ:
+/// `missing` for a row redis has no key for, `stale` for a key whose value isn't the value of
+/// its row, and `orphaned` for a versioned key under the key prefix that has no row. Only
+/// `sample_pct` percent of the rows and of the redis keys are compared.
+///
+/// With `repair`, every difference is fixed through the shared memory buffer once the
+/// transaction commits: a SET of the value of the row, or a DEL. The repairs are versioned with
+/// the WAL position taken before the comparison, once the transactions in progress at that
+/// point finished, so they never replace a newer change. A repair therefore needs the READ
+/// COMMITTED isolation level.
+///
+/// Orphans are only looked for under a key prefix that no other mapping shares, since the
+/// keys of that mapping would have no row in this table.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."verify"(
+    "mapping" regclass,
+    "sample_pct" float8 DEFAULT 100,
+    "repair" bool DEFAULT false
+) RETURNS TABLE (
+    "key" text,
+    "status" text,
+    "table_value" text,
+    "redis_value" text
+)
+    STRICT
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'verify_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."verify"(regclass, float8, bool) FROM PUBLIC;
+"#)]
+fn verify(
+    mapping: pg_sys::Oid,
+    sample_pct: f64,
+    repair: bool,
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(status, String),
+        name!(table_value, Option<String>),
+        name!(redis_value, Option<String>),
+    ),
+> {
+    if !(sample_pct > 0.0 && sample_pct <= 100.0) {
+        error!("postgres_redis: sample_pct must be above 0 and at most 100");
+    }
+    let table_name = Spi::get_one_with_args::<String>(
+        "SELECT $1::regclass::text",
+        vec![(PgBuiltInOids::REGCLASSOID.oid(), mapping.into_datum())],
+    )
+    .ok()
+    .flatten()
+    .expect("failed to read the table name");
+    let relid = mapping;
+    let mapping = match Mapping::lookup(relid) {
+        Some(mapping) => mapping,
+        None => error!("postgres_redis: {table_name} is not mapped, track it with track_table"),
+    };
+
+    let mut drifts = vec![];
+    // Both comparisons read the commits that end before this position.
+    let version = repair.then(|| unsafe { utils::fence("verify with repair") });
+    compare_rows(
+        &table_name,
+        &mapping,
+        sample_pct,
+        |key, table_value, redis_value| {
+            let status = match (&table_value, &redis_value) {
+                // Invalidated keys are only populated again by the queries reading them.
+                (Some(_), None) if mapping.write_policy == WritePolicy::Invalidate => return,
+                (Some(_), None) => "missing",
+                (table_value, Some(redis_value)) if table_value.as_ref() != Some(redis_value) => {
+                    "stale"
+                }
+                _ => return,
+            };
+            drifts.push((key, String::from(status), table_value, redis_value));
+        },
+    );
+    match shared_key_prefix(relid, &mapping) {
+        Some(shared) => warning!(
+            "postgres_redis: orphaned keys of {table_name} are not checked, its key prefix '{}' \
+             is shared with {shared}",
+            mapping.key_prefix
+        ),
+        None => find_orphans(
+            relid,
+            &table_name,
+            &mapping,
+            sample_pct,
+            |key, redis_value| {
+                drifts.push((key, String::from("orphaned"), None, Some(redis_value)));
+            },
+        ),
+    }
+
+    if let Some(version) = version {
+        let mut too_long = 0;
+        for (key, _, table_value, _) in drifts.iter() {
+            let info = match table_value {
+                Some(value) if mapping.write_policy == WritePolicy::Set => {
+                    Info::selected(key, value, version)
+                }
+                _ => Info::deleted(key, version),
+            };
+            match info {
+                Some(info) => xact::push(info.with_relation(relid)),
+                None => too_long += 1,
+            }
+        }
+        if too_long > 0 {
+            warning!(
+                "postgres_redis: {too_long} key(s) or value(s) are longer than {MAX_INFO_LENGTH} characters and \
+                 were not repaired, run warm to send them"
+            );
+        }
+    }
+    TableIterator::new(drifts)
+}
+
+/// Call `drift` with the key, the value of the row and the value in redis of every sampled row
+/// of `table_name`.
+fn compare_rows(
+    table_name: &str,
+    mapping: &Mapping,
+    sample_pct: f64,
+    mut drift: impl FnMut(String, Option<String>, Option<String>),
+) {
+    let sample = if sample_pct < 100.0 {
+        format!(" TABLESAMPLE BERNOULLI ({sample_pct})")
+    } else {
+        String::new()
+    };
+    let conditions: Vec<String> = mapping
+        .key_columns
+        .iter()
+        .map(|column| format!("{} IS NOT NULL", quote_identifier(column)))
+        .collect();
+    let query = format!(
+        "SELECT {} FROM {table_name}{sample} WHERE {}",
+        mapping.select_list(),
+        conditions.join(" AND "),
+    );
+    // Not read-only, so the rows are read with a new snapshot.
+    Spi::connect(|mut client| {
+        let mut cursor = client.open_cursor_mut(&query, None);
+        loop {
+            let rows = cursor
+                .fetch(VERIFY_BATCH_SIZE as i64)
+                .expect("failed to read the rows to verify");
+            if rows.is_empty() {
+                break;
+            }
+            let rows: Vec<(String, Option<String>)> = rows
+                .map(|row| {
+                    let (parts, values) = mapping.row_texts(&row);
+                    (
+                        mapping.key_from_texts(&parts),
+                        mapping.value_from_texts(&values),
+                    )
+                })
+                .collect();
+            let keys: Vec<&String> = rows.iter().map(|(key, _)| key).collect();
+            let redis_values: Vec<Option<String>> =
+                run(|connection| redis::cmd("MGET").arg(&keys).query(connection));
+            for ((key, table_value), redis_value) in rows.into_iter().zip(redis_values) {
+                drift(key, table_value, redis_value);
+            }
+        }
+    });
+}
+
+/// Returns the mappings the keys under the key prefix of `mapping`, the one of `relid`, may
+/// belong to as well: every mapping if the prefix is empty, or the mappings whose prefix
+/// starts it or starts with it.
+fn shared_key_prefix(relid: pg_sys::Oid, mapping: &Mapping) -> Option<String> {
+    if mapping.key_prefix.is_empty() {
+        return Some(String::from("every other mapping"));
+    }
+    let table_relid = gucs::table_relid();
+    if relid != table_relid && table_relid != pg_sys::InvalidOid {
+        let table_prefix = gucs::key_prefix();
+        if table_prefix.starts_with(&mapping.key_prefix)
+            || mapping.key_prefix.starts_with(&table_prefix)
+        {
+            return Some(format!("postgres_redis.table ({table_prefix})"));
+        }
+    }
+    trigger::overlapping_mappings(relid, &mapping.key_prefix)
+}
+
+/// Call `orphan` with the key and value of every sampled key under the key prefix of
+/// `mapping` that has no row in `table_name`. Only the keys written by the extension are
+/// considered, the ones with a version key, and keys that were deleted are skipped.
+fn find_orphans(
+    relid: pg_sys::Oid,
+    table_name: &str,
+    mapping: &Mapping,
+    sample_pct: f64,
+    mut orphan: impl FnMut(String, String),
+) {
+    let pattern = format!(
+        "{}*{VERSION_KEY_SUFFIX}",
+        writer::escape_pattern(&mapping.key_prefix)
+    );
+    let query = orphans_query(relid, table_name, mapping);
+    let mut cursor: u64 = 0;
+    loop {
+        check_for_interrupts!();
+        let (next, version_keys): (u64, Vec<String>) = run(|connection| {
+            redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(VERIFY_BATCH_SIZE)
+                .query(connection)
+        });
+        let keys: Vec<String> = version_keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(VERSION_KEY_SUFFIX))
+            .filter(|key| is_sampled(key, sample_pct))
+            .map(String::from)
+            .collect();
+        if !keys.is_empty() {
+            let values: Vec<Option<String>> =
+                run(|connection| redis::cmd("MGET").arg(&keys).query(connection));
+            let present: Vec<(String, String)> = keys
+                .into_iter()
+                .zip(values)
+                .filter_map(|(key, value)| value.map(|value| (key, value)))
+                .collect();
+            let keys: Vec<String> = present.iter().map(|(key, _)| key.clone()).collect();
+            let orphans = orphaned_keys(&query, mapping, &keys);
+            for (key, value) in present {
+                if orphans.contains(&key) {
+                    orphan(key, value);
+                }
+            }
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+}
+
+/// The query returning the keys in the text array `$1` that have no row in `table_name`,
+/// given the texts of their key columns in the text arrays `$2`, `$3`, ... The texts are cast
+/// to the types of the columns, so the rows are found through an index of the key.
+fn orphans_query(relid: pg_sys::Oid, table_name: &str, mapping: &Mapping) -> String {
+    let key_types = mapping.key_types(relid, table_name);
+    let parts: Vec<String> = (1..=key_types.len()).map(|i| format!("p{i}")).collect();
+    let arrays: Vec<String> = (1..=key_types.len() + 1)
+        .map(|i| format!("${i}::text[]"))
+        .collect();
+    let conditions: Vec<String> = mapping
+        .key_columns
+        .iter()
+        .zip(key_types.iter())
+        .zip(parts.iter())
+        .map(|((column, key_type), part)| {
+            format!("t.{} = k.{part}::{key_type}", quote_identifier(column))
+        })
+        .collect();
+    format!(
+        "SELECT k.key FROM unnest({}) k(key, {})
+         WHERE NOT EXISTS (SELECT 1 FROM {table_name} t WHERE {})",
+        arrays.join(", "),
+        parts.join(", "),
+        conditions.join(" AND "),
+    )
+}
+
+/// Returns the `keys` under the key prefix of `mapping` that have no row, running the
+/// `orphans_query`. A key is split back into the texts of its key columns, a key that has
+/// fewer parts than key columns, or whose parts aren't valid values of the columns, wasn't
+/// written for this mapping and isn't an orphan.
+fn orphaned_keys(query: &str, mapping: &Mapping, keys: &[String]) -> Vec<String> {
+    let key_count = mapping.key_columns.len();
+    let split: Vec<(&String, Vec<&str>)> = keys
+        .iter()
+        .filter_map(|key| {
+            let parts: Vec<&str> = key
+                .strip_prefix(&mapping.key_prefix)?
+                .splitn(key_count, ':')
+                .collect();
+            (parts.len() == key_count).then_some((key, parts))
+        })
+        .collect();
+    let lookup = |split: &[(&String, Vec<&str>)]| {
+        let mut args = vec![(
+            PgBuiltInOids::TEXTARRAYOID.oid(),
+            split
+                .iter()
+                .map(|(key, _)| key.to_string())
+                .collect::<Vec<String>>()
+                .into_datum(),
+        )];
+        for i in 0..key_count {
+            args.push((
+                PgBuiltInOids::TEXTARRAYOID.oid(),
+                split
+                    .iter()
+                    .map(|(_, parts)| parts[i].to_string())
+                    .collect::<Vec<String>>()
+                    .into_datum(),
+            ));
+        }
+        Spi::connect(|mut client| {
+            client
+                .update(query, None, Some(args))
+                .expect("failed to look up the redis keys")
+                .filter_map(|row| row.get::<String>(1).unwrap_or(None))
+                .collect::<Vec<String>>()
+        })
+    };
+    // A part that isn't a valid value fails the cast of the whole batch, the keys are then
+    // looked up one at a time.
+    match utils::try_in_subtransaction(|| lookup(&split)) {
+        Some(orphans) => orphans,
+        None => split
+            .chunks(1)
+            .filter_map(|key| utils::try_in_subtransaction(|| lookup(key)))
+            .flatten()
+            .collect(),
+    }
+}
+
+/// Returns true if `key` is part of a `sample_pct` percent sample of the redis keys. The
+/// sample is taken from a hash of the key, so the same keys are picked every time.
+fn is_sampled(key: &str, sample_pct: f64) -> bool {
+    if sample_pct >= 100.0 {
+        return true;
+    }
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    ((hasher.finish() % 10_000) as f64) < sample_pct * 100.0
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::trigger::Mapping;
+
+    #[pg_test]
+    fn test_orphaned_keys_compare_typed_key_columns() {
+        let relid = Spi::get_one::<pg_sys::Oid>("SELECT 'users'::regclass")
+            .unwrap()
+            .unwrap();
+        let mapping = Mapping::lookup(relid).unwrap();
+        let query = super::orphans_query(relid, "users", &mapping);
+        let keys: Vec<String> = ["users:1", "users:999", "users:abc", "users:", "posts:1"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        // The keys that aren't made of a valid id belong to no row of the table.
+        assert_eq!(
+            vec!["users:999"],
+            super::orphaned_keys(&query, &mapping, &keys)
+        );
+    }
+
+    #[pg_test(error = "postgres_redis: sample_pct must be above 0 and at most 100")]
+    fn test_verify_rejects_sample_pct() {
+        Spi::run("SELECT * FROM postgres_redis.verify('pg_class', 0)").unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pgrx::prelude::*;
+
+use crate::client;
+use crate::prshmem::Operation;
+use crate::trigger::Mapping;
+use crate::utils::{self, quote_identifier};
+use crate::writer;
+
+/// The progress of an unfinished warm-up is kept in a redis hash named with this prefix and
+/// the oid of the table. It lives next to the keys it describes: a flush of redis removes it
+/// too, and the next warm-up starts over.
+const PROGRESS_KEY_PREFIX: &str = "postgres_redis:warm:";
+
+fn progress_key(relid: pg_sys::Oid) -> String {
+    format!("{PROGRESS_KEY_PREFIX}{}", relid.as_u32())
+}
+
+/// Run `command` on the redis connection of the backend, raising an error if redis fails.
+fn run<T>(command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>) -> T {
+    match client::with_connection(command) {
+        Ok(result) => result,
+        Err(err) => error!("postgres_redis: redis command failed: {err}"),
+    }
+}
+
+/// Send the rows of the mapped table `mapping` to redis, in batches of `batch_size` rows read
+/// in key order, optionally limited to the rows matching `where_clause`. Returns the number of
+/// keys sent.
+///
+/// The values are versioned with the WAL position taken when the warm-up starts, once the
+/// transactions in progress at that point finished, so they never replace a newer write. The
+/// warm-up therefore needs the READ COMMITTED isolation level. After every batch the
+/// last key is stored in redis, a warm-up that fails or is cancelled resumes after it when run
+/// again with the same `where_clause`, unless `restart` is set.
+///
+/// `where_clause` is trusted SQL, added to the query as is, which is why only superusers can
+/// call the function unless it is granted.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."warm"(
+    "mapping" regclass,
+    "where_clause" text DEFAULT NULL,
+    "batch_size" int DEFAULT 1000,
+    "restart" bool DEFAULT false
+) RETURNS bigint
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'warm_wrapper';
+REVOKE EXECUTE ON FUNCTION postgres_redis."warm"(regclass, text, int, bool) FROM PUBLIC;
+"#)]
+fn warm(
+    mapping: Option<pg_sys::Oid>,
+    where_clause: Option<&str>,
+    batch_size: Option<i32>,
+    restart: Option<bool>,
+) -> i64 {
+    let relid = match mapping {
+        Some(relid) => relid,
+        None => error!("postgres_redis: warm needs a table"),
+    };
+    let batch_size = batch_size.unwrap_or(1000);
+    if batch_size <= 0 {
+        error!("postgres_redis: batch_size must be positive");
+    }
+    let table_name = Spi::get_one_with_args::<String>(
+        "SELECT $1::regclass::text",
+        vec![(PgBuiltInOids::REGCLASSOID.oid(), relid.into_datum())],
+    )
+    .ok()
+    .flatten()
+    .expect("failed to read the table name");
+    let mapping = match Mapping::lookup(relid) {
+        Some(mapping) => mapping,
+        None => error!("postgres_redis: {table_name} is not mapped, track it with track_table"),
+    };
+    let query = batch_query(relid, &table_name, &mapping, where_clause, batch_size);
+
+    let where_clause = where_clause.unwrap_or_default();
+    let progress_key = progress_key(relid);
+    let mut last_key: Option<String> = None;
+    let mut keys_warmed: i64 = 0;
+    if !restart.unwrap_or(false) {
+        let progress: HashMap<String, String> =
+            run(|connection| redis::cmd("HGETALL").arg(&progress_key).query(connection));
+        if progress.get("where_clause").map(String::as_str) == Some(where_clause) {
+            last_key = progress.get("last_key").cloned();
+            keys_warmed = progress
+                .get("keys_warmed")
+                .and_then(|keys| keys.parse().ok())
+                .unwrap_or(0);
+        }
+    }
+
+    let script = writer::script();
+    let mut sent: i64 = 0;
+    // Every batch reads the commits that end before this position. A later commit sends its
+    // own changes with a newer version.
+    let version = unsafe { utils::fence("warm") };
+    loop {
+        check_for_interrupts!();
+        let rows = read_batch(&query, &mapping, last_key.as_deref());
+        let mut pipe = redis::pipe();
+        for (parts, values) in rows.iter() {
+            if let Some(value) = mapping.value_from_texts(values) {
+                let key = mapping.key_from_texts(parts);
+                writer::queue(
+                    &mut pipe,
+                    &script,
+                    &key,
+                    Operation::Set,
+                    &value,
+                    version,
+                    true,
+                );
+                sent += 1;
+            }
+        }
+        let (parts, _) = match rows.last() {
+            Some(row) => row,
+            None => break,
+        };
+        last_key = Some(text_array(parts));
+        keys_warmed += rows.len() as i64;
+        let updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        pipe.cmd("HSET")
+            .arg(&progress_key)
+            .arg("where_clause")
+            .arg(where_clause)
+            .arg("last_key")
+            .arg(last_key.as_deref().unwrap_or_default())
+            .arg("keys_warmed")
+            .arg(keys_warmed)
+            .arg("updated_at")
+            .arg(updated_at)
+            .ignore();
+        if let Err(err) =
+            client::with_connection(|connection| writer::query(connection, &script, &pipe))
+        {
+            error!(
+                "postgres_redis: warm-up of {table_name} failed after {keys_warmed} rows, \
+                 run warm again to resume: {err}"
+            );
+        }
+        if rows.len() < batch_size as usize {
+            break;
+        }
+    }
+    run(|connection| redis::cmd("DEL").arg(&progress_key).query::<()>(connection));
+    sent
+}
+
+/// The query reading a batch of rows of `table_name`: the key and value columns as text, in
+/// key order, after the key in the text array parameter `$1` if it isn't NULL.
+fn batch_query(
+    relid: pg_sys::Oid,
+    table_name: &str,
+    mapping: &Mapping,
+    where_clause: Option<&str>,
+    batch_size: i32,
+) -> String {
+    let key_types = mapping.key_types(relid, table_name);
+    let key_columns: Vec<String> = mapping
+        .key_columns
+        .iter()
+        .map(|column| quote_identifier(column))
+        .collect();
+    let last_key: Vec<String> = key_types
+        .iter()
+        .enumerate()
+        .map(|(i, key_type)| format!("($1::text[])[{}]::{key_type}", i + 1))
+        .collect();
+    let mut conditions: Vec<String> = key_columns
+        .iter()
+        .map(|column| format!("{column} IS NOT NULL"))
+        .collect();
+    conditions.push(format!(
+        "($1 IS NULL OR ({}) > ({}))",
+        key_columns.join(", "),
+        last_key.join(", ")
+    ));
+    if let Some(where_clause) = where_clause {
+        conditions.push(format!("({where_clause})"));
+    }
+    format!(
+        "SELECT {} FROM {table_name} WHERE {} ORDER BY {} LIMIT {batch_size}",
+        mapping.select_list(),
+        conditions.join(" AND "),
+        key_columns.join(", "),
+    )
+}
+
+/// Read the batch of rows after `last_key`, as the texts of their key and value columns.
+fn read_batch(
+    query: &str,
+    mapping: &Mapping,
+    last_key: Option<&str>,
+) -> Vec<(Vec<String>, Vec<Option<String>>)> {
+    // Not read-only, so the batch is read with a new snapshot.
+    Spi::connect(|mut client| {
+        let rows = client
+            .update(
+                query,
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), last_key.into_datum())]),
+            )
+            .expect("failed to read the rows to warm");
+        rows.map(|row| mapping.row_texts(&row)).collect()
+    })
+}
+
+/// Render `parts` as a text array literal, the form the batch query reads the last key in.
+fn text_array(parts: &[String]) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT $1::text",
+        vec![(
+            PgBuiltInOids::TEXTARRAYOID.oid(),
+            parts.to_vec().into_datum(),
+        )],
+    )
+    .ok()
+    .flatten()
+    .expect("failed to render the last key")
+}
+
+/// Returns the warm-ups that haven't finished, e.g. because they are still running or failed.
+#[pg_extern(sql = r#"
+CREATE FUNCTION postgres_redis."warm_progress"()
+    RETURNS TABLE (
+        "mapping" regclass,
+        "where_clause" text,
+        "keys_warmed" bigint,
+        "last_key" text,
+        "updated_at" timestamptz
+    )
+    LANGUAGE c
+    AS 'MODULE_PATHNAME', 'warm_progress_wrapper';
+"#)]
+fn warm_progress() -> TableIterator<
+    'static,
+    (
+        name!(mapping, pg_sys::Oid),
+        name!(where_clause, Option<String>),
+        name!(keys_warmed, i64),
+        name!(last_key, Option<String>),
+        name!(updated_at, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let pattern = format!("{PROGRESS_KEY_PREFIX}*");
+    let mut keys: Vec<String> = vec![];
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, batch): (u64, Vec<String>) = run(|connection| {
+            redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("TYPE")
+                .arg("hash")
+                .query(connection)
+        });
+        keys.extend(batch);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    let mut rows = vec![];
+    for key in keys {
+        let relid = match key
+            .strip_prefix(PROGRESS_KEY_PREFIX)
+            .and_then(|relid| relid.parse::<u32>().ok())
+        {
+            Some(relid) => pg_sys::Oid::from(relid),
+            None => continue,
+        };
+        let progress: HashMap<String, String> =
+            run(|connection| redis::cmd("HGETALL").arg(&key).query(connection));
+        rows.push((
+            relid,
+            progress
+                .get("where_clause")
+                .filter(|where_clause| !where_clause.is_empty())
+                .cloned(),
+            progress
+                .get("keys_warmed")
+                .and_then(|keys| keys.parse().ok())
+                .unwrap_or(0),
+            progress.get("last_key").cloned(),
+            progress
+                .get("updated_at")
+                .and_then(|updated_at| updated_at.parse::<f64>().ok())
+                .map(pgrx::to_timestamp),
+        ));
+    }
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use std::collections::HashMap;
+    use std::io::{BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use crate::tests::read_command;
+
+    /// The keys a fake redis server got through EVALSHA, and the hashes it holds.
+    #[derive(Default)]
+    struct Received {
+        keys: Vec<String>,
+        hashes: HashMap<String, Vec<(String, String)>>,
+    }
+
+    /// Start a redis server that answers the commands of a warm-up, and point
+    /// `postgres_redis.redis_url` at it. It closes the connection once, without answering,
+    /// when it gets the EVALSHA of `drop_at`.
+    fn fake_redis(drop_at: Option<&'static str>) -> Arc<Mutex<Received>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        Spi::run(&format!(
+            "SET postgres_redis.redis_url = 'redis://{}'",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let received = Arc::new(Mutex::new(Received::default()));
+        let server = received.clone();
+        std::thread::spawn(move || {
+            let mut drop_at = drop_at;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while let Some(command) = read_command(&mut reader) {
+                    let mut received = server.lock().unwrap();
+                    let reply = match command[0].to_ascii_uppercase().as_str() {
+                        "EVALSHA" if drop_at == Some(command[3].as_str()) => {
+                            drop_at = None;
+                            break;
+                        }
+                        "EVALSHA" => {
+                            received.keys.push(command[3].clone());
+                            String::from(":1\r\n")
+                        }
+                        "HSET" => {
+                            let fields = command[2..]
+                                .chunks(2)
+                                .map(|field| (field[0].clone(), field[1].clone()))
+                                .collect();
+                            received.hashes.insert(command[1].clone(), fields);
+                            String::from(":1\r\n")
+                        }
+                        "HGETALL" => {
+                            let fields = received.hashes.get(&command[1]).cloned();
+                            let fields = fields.unwrap_or_default();
+                            let mut reply = format!("*{}\r\n", fields.len() * 2);
+                            for (field, value) in fields {
+                                for part in [field, value] {
+                                    reply.push_str(&format!("${}\r\n{part}\r\n", part.len()));
+                                }
+                            }
+                            reply
+                        }
+                        "DEL" => {
+                            received.hashes.remove(&command[1]);
+                            String::from(":1\r\n")
+                        }
+                        _ => String::from("+OK\r\n"),
+                    };
+                    let _ = stream.write_all(reply.as_bytes());
+                }
+            }
+        });
+        received
+    }
+
+    #[pg_test]
+    fn test_warm_sends_every_row() {
+        let received = fake_redis(None);
+        let sent = Spi::get_one::<i64>("SELECT postgres_redis.warm('users', 'id <= 3', 2)");
+        assert_eq!(Ok(Some(3)), sent);
+        let received = received.lock().unwrap();
+        assert_eq!(vec!["users:1", "users:2", "users:3"], received.keys);
+        // The progress of a finished warm-up is removed.
+        assert!(received.hashes.is_empty());
+    }
+
+    #[pg_test]
+    fn test_warm_resumes_after_a_failure() {
+        let received = fake_redis(Some("users:3"));
+        Spi::run(
+            "DO $$ BEGIN
+                 PERFORM postgres_redis.warm('users', 'id <= 3', 2);
+             EXCEPTION WHEN others THEN NULL;
+             END $$",
+        )
+        .unwrap();
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(vec!["users:1", "users:2"], received.keys);
+            let progress = received.hashes.values().next().unwrap();
+            assert!(progress.contains(&(String::from("last_key"), String::from("{2}"))));
+            assert!(progress.contains(&(String::from("keys_warmed"), String::from("2"))));
+        }
+
+        // The second run starts after the last key of the first one.
+        let sent = Spi::get_one::<i64>("SELECT postgres_redis.warm('users', 'id <= 3', 2)");
+        assert_eq!(Ok(Some(1)), sent);
+        let received = received.lock().unwrap();
+        assert_eq!(vec!["users:1", "users:2", "users:3"], received.keys);
+        assert!(received.hashes.is_empty());
+    }
+
+    #[pg_test(error = "postgres_redis: unmapped is not mapped, track it with track_table")]
+    fn test_warm_rejects_unmapped_table() {
+        Spi::run("CREATE TABLE unmapped (id int PRIMARY KEY, name text)").unwrap();
+        Spi::run("SELECT postgres_redis.warm('unmapped')").unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+
+use redis::{Client, Connection, ErrorKind, Pipeline, RedisResult, Script};
+
+use crate::gucs::{self, TruncateStrategy};
+use crate::prshmem::{self, Info, Operation};
+use crate::stats;
+
+/// The version of every key is stored in a companion key made of the key and this suffix.
+pub const VERSION_KEY_SUFFIX: &str = ":__pgr_version";
+
+/// Applies a SET or DEL only if its version is newer than the one stored for the key. A write
+/// also wins against a stored version that is equal, since a SELECT value may carry the same
+/// WAL position as the commit it read. The version is kept after a DEL so that a slow SELECT
+/// can't bring back a deleted row.
+///
+/// KEYS[1] is the key and KEYS[2] its version key. ARGV holds the operation, the value, the
+/// version and "1" if the value comes from a SELECT query.
+const COMPARE_AND_SET: &str = r#"
+local current = tonumber(redis.call('GET', KEYS[2]) or '0')
+local version = tonumber(ARGV[3])
+if version < current or (version == current and ARGV[4] == '1') then
+    return 0
+end
+if ARGV[1] == 'delete' then
+    redis.call('UNLINK', KEYS[1])
+else
+    redis.call('SET', KEYS[1], ARGV[2])
+end
+redis.call('SET', KEYS[2], ARGV[3])
+return 1
+"#;
+
+pub fn version_key(key: &str) -> String {
+    format!("{key}{VERSION_KEY_SUFFIX}")
+}
+
+/// The number of keys asked for by every SCAN call of a truncate.
+const SCAN_COUNT: usize = 1000;
+
+/// A redis connection that writes through the compare-and-set script. The connection is
+/// opened again by the next write after it is lost.
+pub struct Writer {
+    client: Client,
+    connection: Option<Connection>,
+    script: Script,
+    /// The shard of the buffer whose keys a truncate deletes, all of them if `None`.
+    shard: Option<usize>,
+}
+
+impl Writer {
+    pub fn new(client: Client, shard: Option<usize>) -> Writer {
+        Writer {
+            connection: client.get_connection().ok(),
+            client,
+            script: script(),
+            shard,
+        }
+    }
+
+    /// Send all the `items` to redis in order, see `write_round`. If the connection was
+    /// dropped, e.g. since redis restarted after the last round, the items are sent again on a
+    /// new connection. The changes that already reached redis are skipped by the
+    /// compare-and-set script, so sending them twice is harmless.
+    pub fn write(&mut self, items: &[Info]) -> RedisResult<()> {
+        match self.write_round(items) {
+            Err(e) if e.is_io_error() || e.is_connection_dropped() => {
+                self.connection = None;
+                stats::record_retry();
+                let result = self.write_round(items);
+                if matches!(&result, Err(e) if e.is_io_error() || e.is_connection_dropped()) {
+                    self.connection = None;
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    fn connection(&mut self) -> RedisResult<&mut Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(self.client.get_connection()?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// Send all the `items` to redis in order. Consecutive sets and deletes go out in a single
+    /// pipeline of at most `postgres_redis.max_batch_items` changes and about
+    /// `postgres_redis.max_batch_bytes` of keys and values, a truncate runs on its own since it
+    /// has to SCAN for its keys first.
+    fn write_round(&mut self, items: &[Info]) -> RedisResult<()> {
+        self.connection()?;
+        let max_items = gucs::PGD_MAX_BATCH_ITEMS.get() as usize;
+        let max_bytes = gucs::PGD_MAX_BATCH_BYTES.get() as usize;
+        let mut pipe = redis::pipe();
+        let (mut pipe_items, mut pipe_bytes) = (0, 0);
+        for item in items {
+            if item.operation == Operation::Truncate
+                || pipe_items >= max_items
+                || pipe_bytes >= max_bytes
+            {
+                self.query(&pipe)?;
+                pipe.clear();
+                (pipe_items, pipe_bytes) = (0, 0);
+            }
+            if item.operation == Operation::Truncate {
+                self.truncate(item)?;
+                continue;
+            }
+            pipe_items += 1;
+            pipe_bytes += item.key_length as usize + item.value_length as usize;
+            let value = match item.operation {
+                Operation::Set => item.value_string(),
+                _ => String::new(),
+            };
+            self.queue(
+                &mut pipe,
+                &item.key_string(),
+                item.operation,
+                &value,
+                item.version,
+                item.from_select,
+            );
+        }
+        self.query(&pipe)
+    }
+
+    /// Delete the keys under the key prefix of the truncate `item`, see `TruncateStrategy`.
+    /// The keys go through the compare-and-set script as deletes versioned with the truncate,
+    /// so a change older than the truncate can't bring one back. Every worker gets the
+    /// truncates of the buffer and only deletes the keys of its shard, in order with their
+    /// other changes.
+    fn truncate(&mut self, item: &Info) -> RedisResult<()> {
+        let key_prefix = item.key_string();
+        // Without a prefix every key of the database would match.
+        let strategy = match gucs::PGD_TRUNCATE_STRATEGY.get() {
+            TruncateStrategy::Prefix if key_prefix.is_empty() => TruncateStrategy::Scan,
+            strategy => strategy,
+        };
+        let prefix = escape_pattern(&key_prefix);
+        let pattern = match strategy {
+            TruncateStrategy::Ignore => return Ok(()),
+            TruncateStrategy::Scan => format!("{prefix}*{VERSION_KEY_SUFFIX}"),
+            TruncateStrategy::Prefix => format!("{prefix}*"),
+        };
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query(self.connection()?)?;
+            let mut pipe = redis::pipe();
+            for key in keys.iter() {
+                let key = match (strategy, key.strip_suffix(VERSION_KEY_SUFFIX)) {
+                    (TruncateStrategy::Scan, Some(key)) => key,
+                    (TruncateStrategy::Prefix, None) => key.as_str(),
+                    _ => continue,
+                };
+                if self.shard.is_some_and(|shard| prshmem::shard(key) != shard) {
+                    continue;
+                }
+                self.queue(&mut pipe, key, Operation::Delete, "", item.version, false);
+            }
+            self.query(&pipe)?;
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    fn query(&mut self, pipe: &Pipeline) -> RedisResult<()> {
+        self.connection()?;
+        let connection = self.connection.as_mut().unwrap();
+        query(connection, &self.script, pipe)
+    }
+
+    fn queue(
+        &self,
+        pipe: &mut Pipeline,
+        key: &str,
+        operation: Operation,
+        value: &str,
+        version: u64,
+        from_select: bool,
+    ) {
+        queue(
+            pipe,
+            &self.script,
+            key,
+            operation,
+            value,
+            version,
+            from_select,
+        );
+    }
+}
+
+/// The compare-and-set script, for the callers that write to redis without a `Writer`.
+pub fn script() -> Script {
+    Script::new(COMPARE_AND_SET)
+}
+
+/// Run `pipe` on `connection`. The script is loaded again if the redis server lost it, e.g.
+/// after a restart.
+pub fn query(connection: &mut Connection, script: &Script, pipe: &Pipeline) -> RedisResult<()> {
+    if pipe.cmd_iter().next().is_none() {
+        return Ok(());
+    }
+    match pipe.query::<()>(connection) {
+        Err(e) if e.kind() == ErrorKind::NoScriptError => {
+            stats::record_retry();
+            script.prepare_invoke().load(connection)?;
+            pipe.query(connection)
+        }
+        result => result,
+    }
+}
+
+/// Queue `operation` on `key` in `pipe`, through the compare-and-set `script`.
+pub fn queue(
+    pipe: &mut Pipeline,
+    script: &Script,
+    key: &str,
+    operation: Operation,
+    value: &str,
+    version: u64,
+    from_select: bool,
+) {
+    pipe.cmd("EVALSHA")
+        .arg(script.get_hash())
+        .arg(2)
+        .arg(key)
+        .arg(version_key(key))
+        .arg(operation.name())
+        .arg(value)
+        .arg(version)
+        .arg(if from_select { "1" } else { "0" })
+        .ignore();
+}
+
+/// Escape the glob characters of `prefix` for a SCAN MATCH pattern.
+pub fn escape_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Keep only the newest item of every key in `items`, preserving the order of the kept
+/// items. Items with the same version come from the same transaction, so the later one wins
+/// unless it is a SELECT value racing a write. Truncates are always kept.
+pub fn coalesce(items: Vec<Info>) -> Vec<Info> {
+    let mut newest: HashMap<String, usize> = HashMap::new();
+    for (i, item) in items.iter().enumerate() {
+        if item.operation == Operation::Truncate {
+            continue;
+        }
+        let key = item.key_string();
+        match newest.get(&key) {
+            Some(&j)
+                if items[j].version > item.version
+                    || (items[j].version == item.version && item.from_select) => {}
+            _ => {
+                newest.insert(key, i);
+            }
+        }
+    }
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, item)| {
+            item.operation == Operation::Truncate || newest.get(&item.key_string()) == Some(i)
+        })
+        .map(|(_, item)| item)
+        .collect()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use std::io::{BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::Writer;
+    use crate::prshmem::Info;
+    use crate::tests::read_command;
+
+    /// A redis server for two connections that answers every command with 1, and sends the
+    /// keys of the EVALSHA commands of every connection once it is closed. The server closes
+    /// the first connection itself once it got `first` keys.
+    fn fake_redis(first: usize) -> (String, Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (connection, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut keys = vec![];
+                while let Some(command) = read_command(&mut reader) {
+                    let _ = stream.write_all(b":1\r\n");
+                    if command[0].eq_ignore_ascii_case("EVALSHA") {
+                        keys.push(command[3].clone());
+                    }
+                    if connection == 0 && keys.len() == first {
+                        break;
+                    }
+                }
+                sender.send(keys).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    #[pg_test]
+    fn test_write_after_the_connection_dropped() {
+        let (url, keys) = fake_redis(2);
+        let mut writer = Writer::new(redis::Client::open(url).unwrap(), None);
+        let first = [Info::write("1", "Ada").unwrap(), Info::delete("2").unwrap()];
+        assert!(writer.write(&first).is_ok());
+        assert_eq!(vec!["1", "2"], keys.recv().unwrap());
+
+        // The server closed the connection, the round is sent again on a new one.
+        let second = [Info::write("3", "Grace").unwrap()];
+        assert!(writer.write(&second).is_ok());
+        drop(writer);
+        assert_eq!(vec!["3"], keys.recv().unwrap());
+    }
+}
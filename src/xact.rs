@@ -0,0 +1,659 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use pgrx::{error, pg_guard, pg_sys, warning};
+
+use crate::gucs::{self, SyncFailure, SyncMode};
+use crate::prshmem::{self, add_items, Info, Operation, WriteOutcome, MAX_INFO_LENGTH};
+use crate::stats;
+use crate::utils;
+
+/// A transaction keeps up to this many changes in memory, about 10MB, the later ones are
+/// written to a temporary file, see `Spill`. They are added to the buffer in batches of this
+/// size at commit too.
+const MAX_PENDING_CHANGES: usize = 10_000;
+
+/// A change captured during the current transaction. The subtransaction id is kept so that
+/// changes made inside a savepoint can be thrown away if the savepoint is rolled back.
+pub struct PendingChange {
+    subxact_id: pg_sys::SubTransactionId,
+    pub info: Info,
+    /// True if the commit waits for the change to be written to redis.
+    pub synchronous: bool,
+}
+
+/// Every change captured by the statements of the current transaction, in execution order.
+/// Nothing in here reaches the shared memory buffer until the top-level transaction commits.
+static mut PENDING_CHANGES: Vec<PendingChange> = Vec::new();
+
+/// The outermost live subtransaction that wrote to the tracked table, if any. SELECT
+/// queries after such a write may read uncommitted rows of this transaction.
+static mut MAPPED_WRITE_SUBXACT: Option<pg_sys::SubTransactionId> = None;
+
+/// The changes of the current transaction past the first `MAX_PENDING_CHANGES`, in
+/// execution order, so a bulk write doesn't hold all of its rows in memory. The file is
+/// removed as soon as it is created and closed at the end of the transaction.
+struct Spill {
+    file: BufWriter<File>,
+    /// The subtransaction of every change in the file, see `subxact_callback`.
+    subxact_ids: Vec<pg_sys::SubTransactionId>,
+    /// True if the file holds changes to synchronous mappings.
+    synchronous: bool,
+}
+
+/// The size of a change in the spill file: the subtransaction id, the operation, the flags,
+/// the lengths, the version, the relation and the characters of the key and value.
+const RECORD_SIZE: usize = 4 + 1 + 1 + 1 + 1 + 1 + 8 + 4 + 2 * 4 * MAX_INFO_LENGTH;
+
+static mut SPILL: Option<Spill> = None;
+
+/// The changes to synchronous mappings of the committing transaction, from the moment they
+/// are added to the buffer until `wait_for_sync` waited for them.
+struct SyncWait {
+    /// The changes added to the buffer.
+    items: Vec<Info>,
+    /// The keys of the changes that were discarded because the buffer was full.
+    dropped: Vec<String>,
+    /// The shards the changes were added to, see `Enqueued`.
+    seqs: Vec<(usize, u64)>,
+}
+
+static mut SYNC_WAIT: Option<SyncWait> = None;
+
+// A backend is single threaded, so there is never more than one user of these statics.
+fn pending_changes() -> &'static mut Vec<PendingChange> {
+    unsafe { &mut *std::ptr::addr_of_mut!(PENDING_CHANGES) }
+}
+
+fn mapped_write_subxact() -> &'static mut Option<pg_sys::SubTransactionId> {
+    unsafe { &mut *std::ptr::addr_of_mut!(MAPPED_WRITE_SUBXACT) }
+}
+
+fn spill() -> &'static mut Option<Spill> {
+    unsafe { &mut *std::ptr::addr_of_mut!(SPILL) }
+}
+
+fn sync_wait() -> &'static mut Option<SyncWait> {
+    unsafe { &mut *std::ptr::addr_of_mut!(SYNC_WAIT) }
+}
+
+/// Queue a change for the current (sub)transaction.
+pub fn push(info: Info) {
+    push_change(info, false);
+}
+
+/// Queue a change of a synchronous mapping for the current (sub)transaction. The commit waits
+/// for the background worker to write it, see `wait_for_sync`.
+pub fn push_synchronous(info: Info) {
+    push_change(info, true);
+}
+
+/// Queue a change of `postgres_redis.table` for the current (sub)transaction, synchronous if
+/// `postgres_redis.sync_mode` is.
+pub fn push_table_change(info: Info) {
+    push_change(info, gucs::PGD_SYNC_MODE.get() == SyncMode::Synchronous);
+}
+
+fn push_change(info: Info, synchronous: bool) {
+    let change = PendingChange {
+        subxact_id: unsafe { pg_sys::GetCurrentSubTransactionId() },
+        info,
+        synchronous,
+    };
+    if spill().is_none() && pending_changes().len() < MAX_PENDING_CHANGES {
+        pending_changes().push(change);
+        return;
+    }
+    let spill = spill().get_or_insert_with(Spill::create);
+    let mut record = Vec::with_capacity(RECORD_SIZE);
+    encode(&change, &mut record);
+    if let Err(e) = spill.file.write_all(&record) {
+        error!("postgres_redis: could not write the pending changes to a temporary file: {e}");
+    }
+    spill.subxact_ids.push(change.subxact_id);
+    spill.synchronous |= synchronous;
+}
+
+impl Spill {
+    fn create() -> Spill {
+        static mut FILES: u32 = 0;
+        let number = unsafe {
+            FILES += 1;
+            FILES
+        };
+        // The temporary files of the default tablespace, which are removed on a restart.
+        let dir = "base/pgsql_tmp";
+        let path = format!("{dir}/postgres_redis{}.{number}", unsafe {
+            pg_sys::MyProcPid
+        });
+        let file = std::fs::create_dir_all(dir).and_then(|_| {
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create_new(true)
+                .open(&path)?;
+            std::fs::remove_file(&path)?;
+            Ok(file)
+        });
+        match file {
+            Ok(file) => Spill {
+                file: BufWriter::new(file),
+                subxact_ids: vec![],
+                synchronous: false,
+            },
+            Err(e) => error!("postgres_redis: could not create the temporary file {path}: {e}"),
+        }
+    }
+
+    /// Forget the changes from `position` on.
+    fn truncate(&mut self, position: usize) {
+        let result = self
+            .file
+            .flush()
+            .and_then(|_| self.file.get_ref().set_len((position * RECORD_SIZE) as u64));
+        if let Err(e) = result {
+            error!(
+                "postgres_redis: could not truncate the temporary file of the pending changes: {e}"
+            );
+        }
+        self.subxact_ids.truncate(position);
+    }
+
+    /// Read the changes back in batches of `MAX_PENDING_CHANGES` and pass them to `f`.
+    fn read(mut self, mut f: impl FnMut(Vec<PendingChange>)) {
+        let result = self.file.flush().and_then(|_| {
+            let mut file = self.file.get_ref();
+            file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(file);
+            let mut record = vec![0; RECORD_SIZE];
+            let mut remaining = self.subxact_ids.len();
+            while remaining > 0 {
+                let count = remaining.min(MAX_PENDING_CHANGES);
+                let mut batch = Vec::with_capacity(count);
+                for _ in 0..count {
+                    reader.read_exact(&mut record)?;
+                    batch.push(decode(&record));
+                }
+                remaining -= count;
+                f(batch);
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("postgres_redis: could not read the pending changes from a temporary file: {e}");
+        }
+    }
+}
+
+fn encode(change: &PendingChange, record: &mut Vec<u8>) {
+    let info = &change.info;
+    record.extend_from_slice(&change.subxact_id.to_le_bytes());
+    record.push(match info.operation {
+        Operation::Set => 0,
+        Operation::Delete => 1,
+        Operation::Truncate => 2,
+    });
+    record.push(info.from_select as u8);
+    record.push(change.synchronous as u8);
+    record.push(info.key_length as u8);
+    record.push(info.value_length as u8);
+    record.extend_from_slice(&info.version.to_le_bytes());
+    record.extend_from_slice(&info.relid.as_u32().to_le_bytes());
+    for c in info.key.iter().chain(info.value.iter()) {
+        record.extend_from_slice(&(*c as u32).to_le_bytes());
+    }
+}
+
+fn decode(record: &[u8]) -> PendingChange {
+    let u32_at = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+    let mut info = Info::new("", "").unwrap();
+    info.operation = match record[4] {
+        0 => Operation::Set,
+        1 => Operation::Delete,
+        _ => Operation::Truncate,
+    };
+    info.from_select = record[5] != 0;
+    info.key_length = record[7] as i8;
+    info.value_length = record[8] as i8;
+    info.version = u64::from_le_bytes(record[9..17].try_into().unwrap());
+    info.relid = pg_sys::Oid::from(u32_at(17));
+    for i in 0..MAX_INFO_LENGTH {
+        info.key[i] = char::from_u32(u32_at(21 + 4 * i)).unwrap_or(' ');
+        info.value[i] = char::from_u32(u32_at(21 + 4 * (MAX_INFO_LENGTH + i))).unwrap_or(' ');
+    }
+    PendingChange {
+        subxact_id: u32_at(0),
+        info,
+        synchronous: record[6] != 0,
+    }
+}
+
+fn has_synchronous_changes() -> bool {
+    pending_changes().iter().any(|change| change.synchronous)
+        || spill().as_ref().is_some_and(|spill| spill.synchronous)
+}
+
+/// Record that the current (sub)transaction wrote to the tracked table.
+pub fn mark_mapped_write() {
+    let subxact = mapped_write_subxact();
+    if subxact.is_none() {
+        *subxact = Some(unsafe { pg_sys::GetCurrentSubTransactionId() });
+    }
+}
+
+/// Returns true if the tracked table has uncommitted writes by the current transaction.
+pub fn has_mapped_write() -> bool {
+    mapped_write_subxact().is_some()
+}
+
+/// Returns a copy of the pending changes of the current transaction.
+#[cfg(any(test, feature = "pg_test"))]
+pub fn pending_infos() -> Vec<Info> {
+    pending_changes().iter().map(|change| change.info).collect()
+}
+
+/// Remove and return every pending change of the current transaction.
+pub fn take_pending() -> Vec<PendingChange> {
+    let mut changes: Vec<PendingChange> = pending_changes().drain(..).collect();
+    if let Some(spill) = spill().take() {
+        spill.read(|batch| changes.extend(batch));
+    }
+    changes
+}
+
+/// The version of the changes written by the committing transaction: the end of its commit
+/// record. A transaction that never got a transaction id, e.g. a read only one that only
+/// called `redis_enqueue`, writes no commit record and `XactLastCommitEnd` still holds the
+/// one of an earlier transaction, so the current WAL position is used instead. It is past
+/// the commit record of every transaction that committed before.
+fn commit_version() -> u64 {
+    unsafe {
+        if pg_sys::GetTopTransactionIdIfAny() == pg_sys::InvalidTransactionId {
+            utils::current_lsn()
+        } else {
+            pg_sys::XactLastCommitEnd
+        }
+    }
+}
+
+/// Move every pending change to the shared memory buffer. This runs once the commit record
+/// is written, so written values are versioned with the end of that record. SELECT values
+/// keep the version they were read at. The changes are added in batches, those of a bulk
+/// load are read back from the spill file, and overflow is reported once. The changes to
+/// synchronous mappings are waited for once the locks of the transaction are released, see
+/// `resource_release_callback`.
+fn flush() {
+    let commit_lsn = commit_version();
+    let mut wait = SyncWait {
+        items: vec![],
+        dropped: vec![],
+        seqs: vec![],
+    };
+    let mut synchronous = false;
+    let mut dropped = 0;
+    let mut add_batch = |batch: Vec<PendingChange>| {
+        let (items, batch_synchronous): (Vec<Info>, Vec<bool>) = batch
+            .into_iter()
+            .map(|mut change| {
+                if !change.info.from_select {
+                    change.info.version = commit_lsn;
+                }
+                (change.info, change.synchronous)
+            })
+            .unzip();
+        if items.is_empty() {
+            return;
+        }
+        let enqueued = add_items(items.iter().copied());
+        stats::record_commit(&items, &enqueued.dropped);
+        dropped += enqueued.dropped.len();
+        if !batch_synchronous.contains(&true) {
+            return;
+        }
+        synchronous = true;
+        for (shard, seq) in enqueued.seqs {
+            match wait.seqs.iter_mut().find(|(waited, _)| *waited == shard) {
+                Some(waited) => waited.1 = waited.1.max(seq),
+                None => wait.seqs.push((shard, seq)),
+            }
+        }
+        for (position, item) in items.iter().enumerate() {
+            if !batch_synchronous[position] {
+                continue;
+            }
+            match enqueued.dropped.binary_search(&position) {
+                Ok(_) => wait.dropped.push(key_name(item)),
+                Err(_) => wait.items.push(*item),
+            }
+        }
+    };
+    add_batch(pending_changes().drain(..).collect());
+    if let Some(spill) = spill().take() {
+        spill.read(&mut add_batch);
+    }
+    if dropped > 0 {
+        warning!("postgres_redis: the buffer is full, discarding {dropped} change(s)");
+    }
+    if synchronous {
+        *sync_wait() = Some(wait);
+    }
+}
+
+/// With `postgres_redis.sync_failure = error`, fail a transaction with changes to synchronous
+/// mappings before it commits if they can't be written: the background worker isn't running
+/// or has lost its redis connection.
+fn check_sync() {
+    if gucs::PGD_SYNC_FAILURE.get() != SyncFailure::Error || !has_synchronous_changes() {
+        return;
+    }
+    if !prshmem::is_worker_running() || !stats::is_redis_connected() {
+        error!("postgres_redis: redis is unavailable, changes to synchronous mappings can't be committed");
+    }
+}
+
+/// The key of `item` as shown in a warning, a truncate deletes every key of its prefix.
+fn key_name(item: &Info) -> String {
+    match item.operation {
+        Operation::Truncate => format!("{}*", item.key_string()),
+        _ => item.key_string(),
+    }
+}
+
+/// Join the first few `keys` for a warning.
+fn key_list(keys: &[String]) -> String {
+    const SHOWN: usize = 10;
+    let list = keys[..keys.len().min(SHOWN)].join(", ");
+    match keys.len().checked_sub(SHOWN) {
+        Some(more) if more > 0 => format!("{list} and {more} more"),
+        _ => list,
+    }
+}
+
+/// Wait up to `postgres_redis.sync_timeout` for the background workers to write the changes
+/// to synchronous mappings of the committed transaction, and warn the client about the keys
+/// that weren't written. The commit can't be undone anymore. The changes of a failed round
+/// are not written again, they are lost like the ones discarded because the buffer was
+/// full, and the keys may keep an outdated value until they are written again or warmed.
+/// The changes that are late are still written once the background worker gets to them.
+///
+/// This runs once the locks of the transaction are released, so other sessions don't wait
+/// for redis, only the committing one does, until the timeout.
+fn wait_for_sync(wait: SyncWait) {
+    if gucs::PGD_SYNC_FAILURE.get() == SyncFailure::Async {
+        return;
+    }
+    let running = prshmem::is_worker_running();
+    let outcomes = if running {
+        let timeout = Duration::from_millis(gucs::PGD_SYNC_TIMEOUT.get() as u64);
+        prshmem::wait_for_write(&wait.seqs, timeout)
+    } else {
+        vec![]
+    };
+    let outcome = |shard: usize| {
+        outcomes
+            .iter()
+            .find(|(outcome_shard, _)| *outcome_shard == shard)
+            .map_or(WriteOutcome::TimedOut, |(_, outcome)| *outcome)
+    };
+
+    let mut lost = wait.dropped;
+    let mut late = vec![];
+    for item in wait.items {
+        // A truncate is written by every background worker.
+        let outcomes: Vec<WriteOutcome> = match item.operation {
+            Operation::Truncate => wait.seqs.iter().map(|(shard, _)| outcome(*shard)).collect(),
+            _ => vec![outcome(prshmem::shard(&item.key_string()))],
+        };
+        if outcomes.contains(&WriteOutcome::Failed) {
+            lost.push(key_name(&item));
+        } else if outcomes
+            .iter()
+            .any(|outcome| *outcome != WriteOutcome::Written)
+        {
+            late.push(key_name(&item));
+        }
+    }
+    let interrupted = outcomes
+        .iter()
+        .any(|(_, outcome)| *outcome == WriteOutcome::Interrupted);
+    if !lost.is_empty() {
+        warning!(
+            "postgres_redis: the transaction committed, but its changes to synchronous mappings could not be written to redis and are lost, keys: {}",
+            key_list(&lost)
+        );
+    }
+    if !late.is_empty() {
+        let problem = if !running {
+            "wait for the background worker, which isn't running"
+        } else if interrupted {
+            "may not be written to redis yet, the wait was interrupted"
+        } else {
+            "were not written to redis within postgres_redis.sync_timeout"
+        };
+        warning!(
+            "postgres_redis: the transaction committed, but its changes to synchronous mappings {problem}, keys: {}",
+            key_list(&late)
+        );
+    }
+}
+
+/// Drop every pending change without sending it.
+fn discard() {
+    pending_changes().clear();
+    *spill() = None;
+    *sync_wait() = None;
+}
+
+#[pg_guard]
+unsafe extern "C" fn xact_callback(event: pg_sys::XactEvent, _arg: *mut core::ffi::c_void) {
+    match event {
+        pg_sys::XactEvent_XACT_EVENT_PRE_COMMIT => {
+            check_sync();
+            return;
+        }
+        pg_sys::XactEvent_XACT_EVENT_COMMIT => flush(),
+        pg_sys::XactEvent_XACT_EVENT_ABORT => discard(),
+        // The changes of a prepared transaction were persisted with its GID by
+        // `twophase::persist_prepared`, nothing may leak into the next transaction.
+        pg_sys::XactEvent_XACT_EVENT_PREPARE => discard(),
+        _ => return,
+    }
+    *mapped_write_subxact() = None;
+}
+
+/// Wait for the changes to synchronous mappings once the committing transaction released its
+/// locks. The callback runs for every resource owner of the transaction, the changes are
+/// waited for once.
+#[pg_guard]
+unsafe extern "C" fn resource_release_callback(
+    phase: pg_sys::ResourceReleasePhase,
+    is_commit: bool,
+    is_top_level: bool,
+    _arg: *mut core::ffi::c_void,
+) {
+    if phase != pg_sys::ResourceReleasePhase_RESOURCE_RELEASE_AFTER_LOCKS || !is_top_level {
+        return;
+    }
+    if let Some(wait) = sync_wait().take() {
+        if is_commit {
+            wait_for_sync(wait);
+        }
+    }
+}
+
+/// Subtransaction ids only grow, and a subtransaction can only end while it is the innermost
+/// one. Every change with an id at or above `my_subid` was therefore made by the ending
+/// subtransaction or by one of its already committed children.
+#[pg_guard]
+unsafe extern "C" fn subxact_callback(
+    event: pg_sys::SubXactEvent,
+    my_subid: pg_sys::SubTransactionId,
+    parent_subid: pg_sys::SubTransactionId,
+    _arg: *mut core::ffi::c_void,
+) {
+    let subxact = mapped_write_subxact();
+    match event {
+        pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB => {
+            pending_changes().retain(|change| change.subxact_id < my_subid);
+            // The changes of the subtransaction are the last ones of the spill file.
+            if let Some(file) = spill() {
+                if let Some(position) = file.subxact_ids.iter().position(|id| *id >= my_subid) {
+                    file.truncate(position);
+                }
+                if file.subxact_ids.is_empty() {
+                    *spill() = None;
+                }
+            }
+            if subxact.is_some_and(|subxact_id| subxact_id >= my_subid) {
+                *subxact = None;
+            }
+        }
+        pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB => {
+            for change in pending_changes().iter_mut() {
+                if change.subxact_id >= my_subid {
+                    change.subxact_id = parent_subid;
+                }
+            }
+            for subxact_id in spill()
+                .iter_mut()
+                .flat_map(|spill| spill.subxact_ids.iter_mut())
+            {
+                if *subxact_id >= my_subid {
+                    *subxact_id = parent_subid;
+                }
+            }
+            if subxact.is_some_and(|subxact_id| subxact_id >= my_subid) {
+                *subxact = Some(parent_subid);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Register the transaction callbacks that flush or discard the pending changes.
+///
+/// # Safety
+///
+/// Must only be called once, from `_PG_init`.
+pub unsafe fn register_callbacks() {
+    pg_sys::RegisterXactCallback(Some(xact_callback), std::ptr::null_mut());
+    pg_sys::RegisterSubXactCallback(Some(subxact_callback), std::ptr::null_mut());
+    pg_sys::RegisterResourceReleaseCallback(Some(resource_release_callback), std::ptr::null_mut());
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_enqueue_read_only_version() {
+        let before = crate::utils::current_lsn();
+        Spi::run("SELECT postgres_redis.redis_enqueue('user:1', 'Ada')").unwrap();
+        let pending = super::pending_changes();
+        assert_eq!(1, pending.len());
+        assert_eq!("user:1", pending[0].info.key_string());
+        // Nothing was written, so the transaction has no id and no commit record.
+        assert_eq!(pg_sys::InvalidTransactionId, unsafe {
+            pg_sys::GetTopTransactionIdIfAny()
+        });
+        assert!(super::commit_version() >= before);
+        super::discard();
+    }
+
+    /// Run `f` in a subtransaction, like a savepoint, and release or roll it back.
+    fn in_savepoint(release: bool, f: impl FnOnce()) {
+        unsafe {
+            let context = pg_sys::CurrentMemoryContext;
+            let owner = pg_sys::CurrentResourceOwner;
+            pg_sys::BeginInternalSubTransaction(std::ptr::null());
+            f();
+            if release {
+                pg_sys::ReleaseCurrentSubTransaction();
+            } else {
+                pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            }
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+        }
+    }
+
+    fn pending_keys() -> Vec<String> {
+        super::pending_infos()
+            .iter()
+            .map(|info| info.key_string())
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_savepoint_rollback_drops_its_changes() {
+        Spi::run("UPDATE users SET first_name = 'Ada' WHERE id = 1").unwrap();
+        in_savepoint(false, || {
+            Spi::run("UPDATE users SET first_name = 'Grace' WHERE id = 2").unwrap();
+            // A released savepoint belongs to the one it was made in.
+            in_savepoint(true, || {
+                Spi::run("UPDATE users SET first_name = 'Linus' WHERE id = 3").unwrap();
+            });
+            assert_eq!(vec!["users:1", "users:2", "users:3"], pending_keys());
+        });
+        assert_eq!(vec!["users:1"], pending_keys());
+
+        in_savepoint(true, || {
+            Spi::run("UPDATE users SET first_name = 'Grace' WHERE id = 2").unwrap();
+        });
+        assert_eq!(vec!["users:1", "users:2"], pending_keys());
+        assert!(super::has_mapped_write());
+    }
+
+    #[pg_test]
+    fn test_savepoint_rollback_forgets_the_write() {
+        in_savepoint(false, || {
+            Spi::run("UPDATE users SET first_name = 'Ada' WHERE id = 1").unwrap();
+            assert!(super::has_mapped_write());
+        });
+        assert!(pending_keys().is_empty());
+        assert!(!super::has_mapped_write());
+    }
+
+    #[pg_test]
+    fn test_key_list() {
+        let keys: Vec<String> = (1..=12).map(|i| format!("user:{i}")).collect();
+        assert_eq!("user:1, user:2", super::key_list(&keys[..2]));
+        assert!(super::key_list(&keys).ends_with("user:10 and 2 more"));
+    }
+
+    #[pg_test]
+    fn test_pending_changes_spill() {
+        use crate::prshmem::{Info, Operation};
+        let push = |i: usize| super::push(Info::write(&format!("user:{i}"), "Ada").unwrap());
+        (0..super::MAX_PENDING_CHANGES + 2).for_each(push);
+        assert_eq!(super::MAX_PENDING_CHANGES, super::pending_infos().len());
+        in_savepoint(false, || (0..3).for_each(push));
+        in_savepoint(true, || {
+            push(9);
+            super::push_synchronous(Info::delete("user:10").unwrap());
+        });
+
+        let changes = super::take_pending();
+        assert_eq!(super::MAX_PENDING_CHANGES + 4, changes.len());
+        let last: Vec<(String, bool)> = changes[super::MAX_PENDING_CHANGES..]
+            .iter()
+            .map(|change| (change.info.key_string(), change.synchronous))
+            .collect();
+        let expected = [
+            format!("user:{}", super::MAX_PENDING_CHANGES),
+            format!("user:{}", super::MAX_PENDING_CHANGES + 1),
+            String::from("user:9"),
+            String::from("user:10"),
+        ];
+        assert_eq!(
+            expected
+                .into_iter()
+                .zip([false, false, false, true])
+                .collect::<Vec<_>>(),
+            last
+        );
+        assert_eq!(Operation::Delete, changes.last().unwrap().info.operation);
+    }
+}